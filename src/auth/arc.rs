@@ -0,0 +1,722 @@
+//! The Authenticated Received Chain (RFC 8617). Each intermediary that
+//! handles a message, such as a mailing list, can add an ARC set: the
+//! authentication results it saw, a DKIM-like signature of the message as it
+//! passed it on, and a seal over all the sets so far. Receivers can then
+//! trust those results even if the intermediary's changes broke the
+//! original DKIM signatures.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use super::dkim::{
+    self, Algorithm, Canonicalization, DkimError, KeyRecord, Signer, SigningKey, BETWEEN_TAGS,
+};
+use super::Resolver;
+use crate::headers::render::{fold, make_field};
+use crate::{HeaderField, Message};
+
+/// RFC 8617 section 4.2.1
+const MAX_INSTANCE: u32 = 50;
+
+const ARC_SEAL: &str = "ARC-Seal";
+const ARC_MESSAGE_SIGNATURE: &str = "ARC-Message-Signature";
+const ARC_AUTHENTICATION_RESULTS: &str = "ARC-Authentication-Results";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArcError {
+    /// An ARC field is malformed, or lacks a required tag.
+    InvalidField(DkimError),
+    /// The sets aren't numbered from 1 with exactly one of each field per instance.
+    BrokenChain,
+    /// The seal with the given instance has the wrong `cv=`: the first
+    /// must have `none`, and later ones `pass`.
+    InvalidChainValidation(u32),
+    /// The intermediary that added the given instance found the chain had failed.
+    ChainFailed(u32),
+    /// The newest message signature, with the given instance, doesn't verify.
+    MessageSignature(u32, DkimError),
+    /// The seal with the given instance doesn't verify.
+    Seal(u32, DkimError),
+    /// A message already has as many sets as allowed, so no more can be added.
+    TooManyInstances,
+    /// A new set couldn't be signed.
+    Signing(DkimError),
+}
+
+/// The state of the chain, as found by an intermediary (in `cv=`) or a receiver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChainValidation {
+    /// There are no ARC sets.
+    None,
+    Pass,
+    Fail,
+}
+
+impl ChainValidation {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("none") {
+            Some(ChainValidation::None)
+        } else if value.eq_ignore_ascii_case("pass") {
+            Some(ChainValidation::Pass)
+        } else if value.eq_ignore_ascii_case("fail") {
+            Some(ChainValidation::Fail)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ChainValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainValidation::None => write!(f, "none"),
+            ChainValidation::Pass => write!(f, "pass"),
+            ChainValidation::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Parse the `i=` tag of an ARC field.
+fn parse_instance(value: Option<&str>) -> Result<u32, DkimError> {
+    match value.and_then(|value| value.parse().ok()) {
+        Some(instance) if (1..=MAX_INSTANCE).contains(&instance) => Ok(instance),
+        _ => Err(DkimError::InvalidSignature("i=".to_owned())),
+    }
+}
+
+/// An `ARC-Authentication-Results` field: the results the intermediary
+/// found, in the form of an `Authentication-Results` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthenticationResults {
+    pub instance: u32,
+    /// The authserv-id and the results, e.g. `mx.example.org; spf=pass ...`.
+    pub results: String,
+}
+
+impl AuthenticationResults {
+    pub fn parse(value: &str) -> Result<Self, DkimError> {
+        let invalid = || DkimError::InvalidSignature("i=".to_owned());
+        let i = value.find(';').ok_or_else(invalid)?;
+        let tags = dkim::parse_tag_list(&value[..i]).ok_or_else(invalid)?;
+        let instance = parse_instance(dkim::find_tag(&tags, "i"))?;
+        let results: Vec<&str> = value[i + 1..].split_whitespace().collect();
+        Ok(AuthenticationResults {
+            instance,
+            results: results.join(" "),
+        })
+    }
+}
+
+/// The tags of an `ARC-Message-Signature` field, which are those of a DKIM
+/// signature, apart from `i=`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageSignature {
+    pub instance: u32,
+    pub algorithm: Algorithm,
+    /// `b=`
+    pub signature: Vec<u8>,
+    /// `bh=`
+    pub body_hash: Vec<u8>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    /// `d=`
+    pub domain: String,
+    /// `h=`: the names of the signed header fields, in order.
+    pub signed_headers: Vec<String>,
+    /// `l=`
+    pub body_length: Option<u64>,
+    /// `s=`
+    pub selector: String,
+    /// `t=`, in seconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+}
+
+impl MessageSignature {
+    pub fn parse(value: &str) -> Result<Self, DkimError> {
+        let invalid = |what: &str| DkimError::InvalidSignature(what.to_owned());
+        let tags = dkim::parse_tag_list(value).ok_or_else(|| invalid("malformed tag list"))?;
+        let get = |name| dkim::find_tag(&tags, name);
+        let required = |name| {
+            get(name).ok_or_else(|| DkimError::InvalidSignature(format!("missing {}=", name)))
+        };
+
+        let instance = parse_instance(get("i"))?;
+        let algorithm = Algorithm::parse(required("a")?)?;
+        let signature = dkim::decode_base64(required("b")?).ok_or_else(|| invalid("b="))?;
+        let body_hash = dkim::decode_base64(required("bh")?).ok_or_else(|| invalid("bh="))?;
+        let (header_canonicalization, body_canonicalization) =
+            Canonicalization::parse_pair(get("c")).ok_or_else(|| invalid("c="))?;
+        let signed_headers = dkim::parse_signed_headers(required("h")?)?;
+        // RFC 8617 section 4.1.2
+        if signed_headers
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(ARC_SEAL))
+        {
+            return Err(invalid("h= includes ARC-Seal"));
+        }
+        let number = |name| match get(name) {
+            Some(value) => match value.parse() {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => Err(DkimError::InvalidSignature(format!("{}=", name))),
+            },
+            None => Ok(None),
+        };
+
+        Ok(MessageSignature {
+            instance,
+            algorithm,
+            signature,
+            body_hash,
+            header_canonicalization,
+            body_canonicalization,
+            domain: required("d")?.to_owned(),
+            signed_headers,
+            body_length: number("l")?,
+            selector: required("s")?.to_owned(),
+            timestamp: number("t")?,
+        })
+    }
+}
+
+/// The tags of an `ARC-Seal` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Seal {
+    pub instance: u32,
+    pub algorithm: Algorithm,
+    /// `b=`
+    pub signature: Vec<u8>,
+    /// `cv=`: the state of the chain when this seal was added.
+    pub chain_validation: ChainValidation,
+    /// `d=`
+    pub domain: String,
+    /// `s=`
+    pub selector: String,
+    /// `t=`, in seconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+}
+
+impl Seal {
+    pub fn parse(value: &str) -> Result<Self, DkimError> {
+        let invalid = |what: &str| DkimError::InvalidSignature(what.to_owned());
+        let tags = dkim::parse_tag_list(value).ok_or_else(|| invalid("malformed tag list"))?;
+        let get = |name| dkim::find_tag(&tags, name);
+        let required = |name| {
+            get(name).ok_or_else(|| DkimError::InvalidSignature(format!("missing {}=", name)))
+        };
+
+        // Seals always cover the same fields.
+        if get("h").is_some() {
+            return Err(invalid("h="));
+        }
+        let timestamp = match get("t") {
+            Some(t) => Some(t.parse().map_err(|_| invalid("t="))?),
+            None => None,
+        };
+        Ok(Seal {
+            instance: parse_instance(get("i"))?,
+            algorithm: Algorithm::parse(required("a")?)?,
+            signature: dkim::decode_base64(required("b")?).ok_or_else(|| invalid("b="))?,
+            chain_validation: ChainValidation::parse(required("cv")?)
+                .ok_or_else(|| invalid("cv="))?,
+            domain: required("d")?.to_owned(),
+            selector: required("s")?.to_owned(),
+            timestamp,
+        })
+    }
+}
+
+/// The fields that make up one instance of the chain.
+struct ArcSet<'m, 'a> {
+    results: &'m HeaderField<'a>,
+    signature_field: &'m HeaderField<'a>,
+    signature: MessageSignature,
+    seal_field: &'m HeaderField<'a>,
+    seal: Seal,
+}
+
+/// Find and parse the ARC sets in `header`, in order of instance.
+fn collect_sets<'m, 'a>(header: &'m [HeaderField<'a>]) -> Result<Vec<ArcSet<'m, 'a>>, ArcError> {
+    let mut results = vec![];
+    let mut signatures = vec![];
+    let mut seals = vec![];
+    for hf in header {
+        let name = &hf.name().0;
+        let value = || {
+            std::str::from_utf8(hf.raw_value()).map_err(|_| {
+                ArcError::InvalidField(DkimError::InvalidSignature("not UTF-8".to_owned()))
+            })
+        };
+        if name.eq_ignore_ascii_case(ARC_AUTHENTICATION_RESULTS.as_bytes()) {
+            let parsed = AuthenticationResults::parse(value()?).map_err(ArcError::InvalidField)?;
+            results.push((parsed.instance, hf));
+        } else if name.eq_ignore_ascii_case(ARC_MESSAGE_SIGNATURE.as_bytes()) {
+            let parsed = MessageSignature::parse(value()?).map_err(ArcError::InvalidField)?;
+            signatures.push((parsed.instance, (hf, parsed)));
+        } else if name.eq_ignore_ascii_case(ARC_SEAL.as_bytes()) {
+            let parsed = Seal::parse(value()?).map_err(ArcError::InvalidField)?;
+            seals.push((parsed.instance, (hf, parsed)));
+        }
+    }
+
+    let count = seals.len();
+    if results.len() != count || signatures.len() != count {
+        return Err(ArcError::BrokenChain);
+    }
+    // There are as many fields of each kind as instances, so if each
+    // instance is found, each occurs exactly once.
+    fn take<T>(fields: &mut Vec<(u32, T)>, instance: u32) -> Result<T, ArcError> {
+        match fields.iter().position(|(i, _)| *i == instance) {
+            Some(i) => Ok(fields.swap_remove(i).1),
+            None => Err(ArcError::BrokenChain),
+        }
+    }
+    let mut sets = Vec::with_capacity(count);
+    for instance in 1..=count as u32 {
+        let (signature_field, signature) = take(&mut signatures, instance)?;
+        let (seal_field, seal) = take(&mut seals, instance)?;
+        sets.push(ArcSet {
+            results: take(&mut results, instance)?,
+            signature_field,
+            signature,
+            seal_field,
+            seal,
+        });
+    }
+    Ok(sets)
+}
+
+/// Append a field, as seals sign it, to `out`.
+fn push_field(field: &HeaderField, out: &mut Vec<u8>) {
+    dkim::canonicalize_header(
+        Canonicalization::Relaxed,
+        &field.name().0,
+        field.raw_value(),
+        out,
+    );
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Append the fields of `sets`, as seals sign them, to `out`.
+fn push_sets(sets: &[ArcSet], out: &mut Vec<u8>) {
+    for set in sets {
+        push_field(set.results, out);
+        push_field(set.signature_field, out);
+        push_field(set.seal_field, out);
+    }
+}
+
+/// The outcome of checking the ARC sets of a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArcResult {
+    pub result: ChainValidation,
+    /// The number of sets.
+    pub instances: u32,
+    /// Why the chain didn't pass.
+    pub error: Option<ArcError>,
+}
+
+/// Formats the result as a `resinfo` of `Authentication-Results`, e.g. `arc=pass`.
+impl fmt::Display for ArcResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "arc={}", self.result)
+    }
+}
+
+/// Check the ARC sets of `message` (RFC 8617 section 5.2), looking up keys
+/// with `resolver`. Only the newest message signature is checked, since
+/// older ones were expected to be broken by later intermediaries. As with
+/// `dkim::verify`, the body is checked as it was parsed.
+pub fn validate<R: Resolver + ?Sized>(message: &Message, resolver: &R) -> ArcResult {
+    let instances = message
+        .header()
+        .iter()
+        .filter(|hf| hf.name().0.eq_ignore_ascii_case(ARC_SEAL.as_bytes()))
+        .count() as u32;
+    if instances == 0 {
+        return ArcResult {
+            result: ChainValidation::None,
+            instances,
+            error: None,
+        };
+    }
+    let error = collect_sets(message.header())
+        .and_then(|sets| check_chain(message, &sets, resolver))
+        .err();
+    ArcResult {
+        result: match error {
+            Some(_) => ChainValidation::Fail,
+            None => ChainValidation::Pass,
+        },
+        instances,
+        error,
+    }
+}
+
+fn check_chain<R: Resolver + ?Sized>(
+    message: &Message,
+    sets: &[ArcSet],
+    resolver: &R,
+) -> Result<(), ArcError> {
+    let newest = sets.last().expect("there are ARC sets");
+    if newest.seal.chain_validation == ChainValidation::Fail {
+        return Err(ArcError::ChainFailed(newest.seal.instance));
+    }
+    for set in sets {
+        let expected = match set.seal.instance {
+            1 => ChainValidation::None,
+            _ => ChainValidation::Pass,
+        };
+        if set.seal.chain_validation != expected {
+            return Err(ArcError::InvalidChainValidation(set.seal.instance));
+        }
+    }
+    check_message_signature(message, newest, resolver)
+        .map_err(|e| ArcError::MessageSignature(newest.signature.instance, e))?;
+    for n in (1..=sets.len()).rev() {
+        check_seal(&sets[..n], resolver).map_err(|e| ArcError::Seal(n as u32, e))?;
+    }
+    Ok(())
+}
+
+fn check_message_signature<R: Resolver + ?Sized>(
+    message: &Message,
+    set: &ArcSet,
+    resolver: &R,
+) -> Result<(), DkimError> {
+    let signature = &set.signature;
+    let key = KeyRecord::lookup(&signature.selector, &signature.domain, resolver)?;
+    dkim::check_body_hash(
+        message.raw_body(),
+        signature.body_canonicalization,
+        signature.body_length,
+        &signature.body_hash,
+    )?;
+    let mut data = vec![];
+    dkim::select_headers(
+        message.header(),
+        &signature.signed_headers,
+        signature.header_canonicalization,
+        &mut data,
+    );
+    dkim::canonicalize_header(
+        signature.header_canonicalization,
+        &set.signature_field.name().0,
+        &dkim::strip_signature(set.signature_field.raw_value()),
+        &mut data,
+    );
+    key.verify(
+        signature.algorithm,
+        &Sha256::digest(&data),
+        &signature.signature,
+    )
+}
+
+/// Check the seal of the last of `sets`, which covers all of them.
+fn check_seal<R: Resolver + ?Sized>(sets: &[ArcSet], resolver: &R) -> Result<(), DkimError> {
+    let (set, earlier) = sets.split_last().expect("there are ARC sets");
+    let key = KeyRecord::lookup(&set.seal.selector, &set.seal.domain, resolver)?;
+    let mut data = vec![];
+    push_sets(earlier, &mut data);
+    push_field(set.results, &mut data);
+    push_field(set.signature_field, &mut data);
+    dkim::canonicalize_header(
+        Canonicalization::Relaxed,
+        &set.seal_field.name().0,
+        &dkim::strip_signature(set.seal_field.raw_value()),
+        &mut data,
+    );
+    key.verify(
+        set.seal.algorithm,
+        &Sha256::digest(&data),
+        &set.seal.signature,
+    )
+}
+
+/// Adds ARC sets to messages being passed on.
+#[derive(Clone, Debug)]
+pub struct Sealer {
+    authserv_id: String,
+    signer: Signer,
+}
+
+impl Sealer {
+    /// A sealer for `domain`, whose public key is published under
+    /// `selector` as for DKIM. `authserv_id` names the host whose results
+    /// are recorded, as in `Authentication-Results`. By default, the
+    /// message signature covers `dkim::DEFAULT_SIGNED_HEADERS`.
+    pub fn new(authserv_id: &str, domain: &str, selector: &str, key: SigningKey) -> Self {
+        Self {
+            authserv_id: authserv_id.to_owned(),
+            signer: Signer::new(domain, selector, key),
+        }
+    }
+
+    /// The names of the fields for the message signature to cover. From is
+    /// always signed, and ARC-Seal never is.
+    pub fn headers(mut self, names: &[&str]) -> Self {
+        let names: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !name.eq_ignore_ascii_case(ARC_SEAL))
+            .collect();
+        self.signer = self.signer.headers(&names);
+        self
+    }
+
+    /// See `dkim::Signer::oversign`.
+    pub fn oversign(mut self, names: &[&str]) -> Self {
+        self.signer = self.signer.oversign(names);
+        self
+    }
+
+    /// The canonicalization of the message signature; seals are always relaxed.
+    pub fn canonicalization(mut self, header: Canonicalization, body: Canonicalization) -> Self {
+        self.signer = self.signer.canonicalization(header, body);
+        self
+    }
+
+    /// Add a set to `message`, recording `results` (e.g. `spf=pass
+    /// smtp.mailfrom=example.com; dkim=pass header.d=example.com`) and
+    /// `chain`, what `validate` found before the message was changed. The
+    /// first set always has `cv=none`; after that, anything but `Pass` is
+    /// recorded as `fail`.
+    pub fn seal(
+        &self,
+        message: &mut Message,
+        chain: ChainValidation,
+        results: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), ArcError> {
+        let sets = collect_sets(message.header())?;
+        if let Some(newest) = sets.last() {
+            if newest.seal.chain_validation == ChainValidation::Fail {
+                return Err(ArcError::ChainFailed(newest.seal.instance));
+            }
+        }
+        if sets.len() >= MAX_INSTANCE as usize {
+            return Err(ArcError::TooManyInstances);
+        }
+        let instance = sets.len() as u32 + 1;
+        let chain = match (instance, chain) {
+            (1, _) => ChainValidation::None,
+            (_, ChainValidation::Pass) => ChainValidation::Pass,
+            _ => ChainValidation::Fail,
+        };
+
+        let mut tokens = vec![format!("i={};", instance), format!("{};", self.authserv_id)];
+        tokens.extend(results.split_whitespace().map(str::to_owned));
+        let tokens: Vec<_> = tokens
+            .into_iter()
+            .map(|token| (token, BETWEEN_TAGS, true))
+            .collect();
+        let results = make_field(
+            ARC_AUTHENTICATION_RESULTS,
+            &fold(ARC_AUTHENTICATION_RESULTS.as_bytes(), &tokens),
+        )
+        .ok_or_else(|| {
+            ArcError::InvalidField(DkimError::InvalidSignature(
+                ARC_AUTHENTICATION_RESULTS.to_owned(),
+            ))
+        })?;
+
+        let body = dkim::serialized_body(message);
+        let signature = self
+            .signer
+            .make_signature(
+                ARC_MESSAGE_SIGNATURE,
+                format!("i={};", instance),
+                message.header(),
+                &body,
+                now,
+            )
+            .map_err(ArcError::Signing)?;
+
+        let key = &self.signer.key;
+        let tags = vec![
+            format!("i={};", instance),
+            format!("a={};", key.algorithm()),
+            format!("cv={};", chain),
+            format!("d={};", self.signer.domain),
+            format!("s={};", self.signer.selector),
+            format!("t={};", now.timestamp().max(0)),
+        ];
+        let tokens = tags
+            .into_iter()
+            .map(|tag| (tag, BETWEEN_TAGS, true))
+            .collect();
+        let mut data = vec![];
+        push_sets(&sets, &mut data);
+        push_field(&results, &mut data);
+        push_field(&signature, &mut data);
+        let seal = dkim::finish_signature(ARC_SEAL, tokens, key, Canonicalization::Relaxed, data)
+            .map_err(ArcError::Signing)?;
+
+        message.insert_header(0, results);
+        message.insert_header(0, signature);
+        message.insert_header(0, seal);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{validate, ArcError, AuthenticationResults, ChainValidation, Sealer};
+    use crate::auth::dkim::{DkimError, SigningKey};
+    use crate::auth::MemoryResolver;
+    use crate::parse::email::parse_message;
+
+    // Made with an independent implementation: the first set with the RSA
+    // key, then the second with the Ed25519 key, after a list changed the
+    // Subject and added a footer.
+    const SEALED: &str = "\
+        ARC-Seal: i=2; a=ed25519-sha256; cv=pass; d=example.org; s=brisbane;\r\n \
+        t=1528637909; b=9sdrGyqu95ixzKnAkwTdJFiEbLbKqM+FvzM+lGNTzaqMn2n9K/bOr8w3Gebt+ZeI\r\n\t\
+        rvBhUZYT52i1xco8nfpkDg==\r\n\
+        ARC-Message-Signature: i=2; a=ed25519-sha256; c=relaxed/relaxed; d=example.org; s=brisbane;\r\n \
+        t=1528637909; h=from:to:subject:date:message-id;\r\n \
+        bh=ew2ChtpX990TpLE/PRizlnSfSHFp8zrJEj6iJfY397M=;\r\n \
+        b=LBtsxLKqgXzbvMQdi4xGYGxBIQ5p97RJOnnmI5epJi4ytdEteRyKuCZSVhLtamyz\r\n\t\
+        FO2WdqTuIh6sWtcEcaLYBw==\r\n\
+        ARC-Authentication-Results: i=2; lists.example.org;\r\n \
+        arc=pass\r\n\
+        ARC-Seal: i=1; a=rsa-sha256; cv=none; d=example.org; s=test;\r\n \
+        t=1528637909; b=D2/J053fJaTkryP2cjxiAuh0Lgsa/UKkeLSwrJCjRBnuobzVCevXOt5yNUmoU8ju\r\n\t\
+        jkIImMkrgZ4d5Genn0vW2G2mAcKMCn5BRSTTclNjaJ0djrYfLOozT+TyKnMRKRgC\r\n\t\
+        OEwWVg7xW3C7ZyVfU5ffjV81pp0/+V/v6+I+xSX+E5Q=\r\n\
+        ARC-Message-Signature: i=1; a=rsa-sha256; c=relaxed/relaxed; d=example.org; s=test;\r\n \
+        t=1528637909; h=from:to:subject:date:message-id;\r\n \
+        bh=sTT02lKuPAH1nGYBiIjR27DGuCuXdYrdO56uQNzQX+8=;\r\n \
+        b=WbeiiFiWKQM1cJ8O1oWqMtOWDrZYeQZwKBEx/3z8BhqPo2bvY6zTgJO+wxLo0ovj\r\n\t\
+        KHLzLFU0gNklU8s82Rq5X+pjv92v+JJIBuYkNw3OSA8P+xMm+gaatvMnUvMXsUAm\r\n\t\
+        dvvl/JzgnB36YlVCAgFdofJRdiNNlwzv6xzYrIYRVKA=\r\n\
+        ARC-Authentication-Results: i=1; lists.example.org;\r\n \
+        dkim=pass header.d=football.example.com\r\n\
+        From: Joe SixPack <joe@football.example.com>\r\n\
+        To: Suzie Q <suzie@shopping.example.net>\r\n\
+        Subject: [list] Is dinner ready?\r\n\
+        Date: Fri, 11 Jul 2003 21:00:37 -0700\r\n\
+        Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+        \r\n\
+        Hi.\r\n\
+        \r\n\
+        We lost the game.\r\n\
+        -- \r\n\
+        A mailing list\r\n";
+
+    fn resolver() -> MemoryResolver {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_txt(
+            "brisbane._domainkey.example.org",
+            "v=DKIM1; k=ed25519; p=A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=",
+        );
+        resolver.add_txt(
+            "test._domainkey.example.org",
+            "v=DKIM1; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCp6Bt/0nUxq1DG/rqBXpjVOjOcjSfwLyKg\
+             80lap78vaRW8zWdZqFurlTtzD9lEWGr953QzpSkdk6yyzKc4t3eGjpvoGMj23Zq/8n12ZOlgTu//lBCzs0WI\
+             K5hmfk11TIKOAH2wrC3dCMlfwy0+DOYZ2RddxmYbEVxfurl1Xd/B2wIDAQAB",
+        );
+        resolver
+    }
+
+    #[test]
+    fn test_validate() {
+        let resolver = resolver();
+        let message = parse_message(SEALED.as_bytes()).unwrap();
+        let result = validate(&message, &resolver);
+        assert_eq!(result.error, None);
+        assert_eq!(result.to_string(), "arc=pass");
+        assert_eq!(result.instances, 2);
+
+        let results = AuthenticationResults::parse(
+            " i=1; lists.example.org;\r\n dkim=pass header.d=football.example.com",
+        )
+        .unwrap();
+        assert_eq!(results.instance, 1);
+        assert_eq!(
+            results.results,
+            "lists.example.org; dkim=pass header.d=football.example.com"
+        );
+
+        // A change to the body after the last set breaks its message signature.
+        let changed = SEALED.replace("lost", "won");
+        let message = parse_message(changed.as_bytes()).unwrap();
+        let result = validate(&message, &resolver);
+        assert_eq!(result.result, ChainValidation::Fail);
+        assert!(matches!(
+            result.error,
+            Some(ArcError::MessageSignature(2, _))
+        ));
+
+        // So does a change to the results recorded in an earlier set.
+        let changed = SEALED.replace("dkim=pass", "dkim=fail");
+        let message = parse_message(changed.as_bytes()).unwrap();
+        let result = validate(&message, &resolver);
+        assert!(matches!(result.error, Some(ArcError::Seal(2, _))));
+
+        let unsealed = &SEALED[SEALED.find("From:").unwrap()..];
+        let message = parse_message(unsealed.as_bytes()).unwrap();
+        assert_eq!(validate(&message, &resolver).result, ChainValidation::None);
+    }
+
+    #[test]
+    fn test_seal() {
+        let now = Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap();
+        let resolver = resolver();
+        let mut seed = [0; 32];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let key = SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&seed));
+        let sealer = Sealer::new("forwarder.example.org", "example.org", "brisbane", key);
+
+        let unsealed = &SEALED[SEALED.find("From:").unwrap()..];
+        let mut message = parse_message(unsealed.as_bytes()).unwrap();
+        sealer
+            .seal(&mut message, ChainValidation::Pass, "dkim=fail", now)
+            .unwrap();
+        let data = message.to_bytes();
+        let message = parse_message(&data).unwrap();
+        let result = validate(&message, &resolver);
+        assert_eq!(result.error, None);
+        assert_eq!(result.instances, 1);
+        assert!(message.header()[0].raw_value().starts_with(b" i=1;"));
+
+        let mut message = parse_message(SEALED.as_bytes()).unwrap();
+        let chain = validate(&message, &resolver).result;
+        sealer
+            .seal(&mut message, chain, "arc=pass header.oldest-pass=1", now)
+            .unwrap();
+        let data = message.to_bytes();
+        let message = parse_message(&data).unwrap();
+        let result = validate(&message, &resolver);
+        assert_eq!(result.error, None);
+        assert_eq!(result.instances, 3);
+
+        // A chain that an intermediary found to have failed can't be added to.
+        let mut message = parse_message(&data).unwrap();
+        sealer
+            .seal(&mut message, ChainValidation::Fail, "arc=fail", now)
+            .unwrap();
+        assert_eq!(
+            sealer.seal(&mut message, ChainValidation::Pass, "arc=fail", now),
+            Err(ArcError::ChainFailed(4))
+        );
+
+        let mut message = parse_message(unsealed.as_bytes()).unwrap();
+        assert_eq!(
+            sealer.seal(
+                &mut message,
+                ChainValidation::None,
+                "r\u{e9}sultat=pass",
+                now
+            ),
+            Err(ArcError::InvalidField(DkimError::InvalidSignature(
+                "ARC-Authentication-Results".to_owned()
+            )))
+        );
+    }
+}
@@ -0,0 +1,501 @@
+//! Domain-based Message Authentication, Reporting and Conformance (RFC
+//! 7489): whether the domain in From is backed by a passing SPF or DKIM
+//! check for the same (or, with relaxed alignment, a related) domain, and
+//! what the domain asks receivers to do with mail where it isn't.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::OnceLock;
+
+use super::dkim::{self, DkimResult};
+use super::spf::SpfResult;
+use super::{AuthResult, DnsError, Resolver};
+use crate::headers::address::Domain;
+use crate::headers::HeaderFieldInner;
+use crate::Message;
+
+/// The Public Suffix List (https://publicsuffix.org/), used to find
+/// organizational domains.
+const PUBLIC_SUFFIX_LIST: &str = include_str!("public_suffix_list.dat");
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DmarcError {
+    /// The message has no From field, or no domain in it.
+    MissingFrom,
+    /// The message has several From fields or authors, so no single policy applies.
+    MultipleFrom,
+    /// A record is malformed.
+    InvalidRecord(String),
+    /// A DNS lookup failed, e.g. because of a timeout.
+    Dns(String),
+}
+
+impl DmarcError {
+    /// The result reported for a check that ended with this error.
+    pub fn result(&self) -> AuthResult {
+        match self {
+            DmarcError::Dns(_) => AuthResult::TempError,
+            _ => AuthResult::PermError,
+        }
+    }
+}
+
+/// What a domain asks receivers to do with mail that fails DMARC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("none") {
+            Some(Policy::None)
+        } else if value.eq_ignore_ascii_case("quarantine") {
+            Some(Policy::Quarantine)
+        } else if value.eq_ignore_ascii_case("reject") {
+            Some(Policy::Reject)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::None => write!(f, "none"),
+            Policy::Quarantine => write!(f, "quarantine"),
+            Policy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// How closely an authenticated domain must match the From domain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Alignment {
+    /// The domains must have the same organizational domain.
+    Relaxed,
+    /// The domains must be the same.
+    Strict,
+}
+
+impl Alignment {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("r") {
+            Some(Alignment::Relaxed)
+        } else if value.eq_ignore_ascii_case("s") {
+            Some(Alignment::Strict)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `domain` is aligned with `from_domain`.
+    pub fn is_aligned(&self, domain: &str, from_domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.');
+        let from_domain = from_domain.trim_end_matches('.');
+        match self {
+            Alignment::Strict => domain.eq_ignore_ascii_case(from_domain),
+            Alignment::Relaxed => {
+                organizational_domain(domain) == organizational_domain(from_domain)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Alignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alignment::Relaxed => write!(f, "r"),
+            Alignment::Strict => write!(f, "s"),
+        }
+    }
+}
+
+/// A domain's DMARC record, as published at `_dmarc.<domain>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmarcRecord {
+    /// `p=`
+    pub policy: Policy,
+    /// `sp=`: the policy for subdomains, if different.
+    pub subdomain_policy: Option<Policy>,
+    /// `adkim=`
+    pub dkim_alignment: Alignment,
+    /// `aspf=`
+    pub spf_alignment: Alignment,
+    /// `pct=`: the percentage of failing mail to apply the policy to.
+    pub percent: u8,
+    /// `rua=`: where to send aggregate reports.
+    pub aggregate_reports: Vec<String>,
+    /// `ruf=`: where to send failure reports.
+    pub failure_reports: Vec<String>,
+    /// `fo=`: when to send failure reports, e.g. `0:d`.
+    pub failure_options: String,
+    /// `ri=`: how often aggregate reports are wanted, in seconds.
+    pub report_interval: u32,
+}
+
+/// Whether a TXT record is a DMARC record.
+fn is_dmarc_record(record: &str) -> bool {
+    match dkim::parse_tag_list(record) {
+        Some(tags) => matches!(tags.first(), Some(("v", "DMARC1"))),
+        None => false,
+    }
+}
+
+impl DmarcRecord {
+    pub fn parse(record: &str) -> Result<Self, DmarcError> {
+        let invalid = |what: &str| DmarcError::InvalidRecord(what.to_owned());
+        let tags = dkim::parse_tag_list(record).ok_or_else(|| invalid("malformed tag list"))?;
+        let get = |name| dkim::find_tag(&tags, name);
+        if !matches!(tags.first(), Some(("v", "DMARC1"))) {
+            return Err(invalid("v="));
+        }
+        let uris = |name| -> Vec<String> {
+            get(name)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|uri| uri.trim_matches(dkim::is_fws).to_owned())
+                        .filter(|uri| !uri.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let aggregate_reports = uris("rua");
+        // RFC 7489 section 6.6.3: a record without a valid policy, but with
+        // somewhere to send reports, asks for reports only.
+        let policy = match get("p").and_then(Policy::parse) {
+            Some(policy) => policy,
+            None if !aggregate_reports.is_empty() => Policy::None,
+            None => return Err(invalid("p=")),
+        };
+        let subdomain_policy = match get("sp") {
+            Some(sp) => Some(Policy::parse(sp).ok_or_else(|| invalid("sp="))?),
+            None => None,
+        };
+        let alignment = |name| match get(name) {
+            Some(value) => Alignment::parse(value)
+                .ok_or_else(|| DmarcError::InvalidRecord(format!("{}=", name))),
+            None => Ok(Alignment::Relaxed),
+        };
+        let percent = match get("pct") {
+            Some(pct) => pct
+                .parse()
+                .ok()
+                .filter(|pct| *pct <= 100)
+                .ok_or_else(|| invalid("pct="))?,
+            None => 100,
+        };
+        let report_interval = match get("ri") {
+            Some(ri) => ri.parse().map_err(|_| invalid("ri="))?,
+            None => 86400,
+        };
+        Ok(DmarcRecord {
+            policy,
+            subdomain_policy,
+            dkim_alignment: alignment("adkim")?,
+            spf_alignment: alignment("aspf")?,
+            percent,
+            aggregate_reports,
+            failure_reports: uris("ruf"),
+            failure_options: get("fo").unwrap_or("0").to_owned(),
+            report_interval,
+        })
+    }
+
+    /// Look up the record for `domain`. Without exactly one, there is no policy.
+    pub fn lookup<R: Resolver + ?Sized>(
+        domain: &str,
+        resolver: &R,
+    ) -> Result<Option<Self>, DmarcError> {
+        let records = match resolver.txt(&format!("_dmarc.{}", domain)) {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => return Ok(None),
+            Err(DnsError::Temporary(e)) => return Err(DmarcError::Dns(e)),
+        };
+        let mut records = records.iter().filter(|record| is_dmarc_record(record));
+        match (records.next(), records.next()) {
+            // A malformed record is ignored, as if it wasn't there.
+            (Some(record), None) => Ok(DmarcRecord::parse(record).ok()),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn public_suffix_rules() -> &'static HashSet<&'static str> {
+    static RULES: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    RULES.get_or_init(|| {
+        PUBLIC_SUFFIX_LIST
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|rule| !rule.starts_with("//"))
+            .collect()
+    })
+}
+
+/// The organizational domain of `domain`: its public suffix (e.g. `com` or
+/// `co.uk`) and one more label.
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+    let rules = public_suffix_rules();
+    // The number of labels in the public suffix; the longest matching rule
+    // wins, and without one, it is the last label.
+    let mut suffix_len = 1;
+    for i in 0..labels.len() {
+        let candidate = labels[i..].join(".");
+        if rules.contains(format!("!{}", candidate).as_str()) {
+            suffix_len = labels.len() - i - 1;
+            break;
+        }
+        let wildcard = i + 1 < labels.len()
+            && rules.contains(format!("*.{}", labels[i + 1..].join(".")).as_str());
+        if rules.contains(candidate.as_str()) || wildcard {
+            suffix_len = labels.len() - i;
+            break;
+        }
+    }
+    let len = (suffix_len + 1).min(labels.len());
+    labels[labels.len() - len..].join(".")
+}
+
+/// The outcome of a DMARC check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmarcResult {
+    /// `Pass` or `Fail` if the domain has a record, otherwise `None`, or
+    /// `TempError` or `PermError`.
+    pub result: AuthResult,
+    /// The domain of the From address, unless it couldn't be found.
+    pub from_domain: Option<String>,
+    /// The record that applies, which may be the organizational domain's.
+    pub record: Option<DmarcRecord>,
+    /// The policy that applies, taking `sp=` into account.
+    pub policy: Policy,
+    /// Whether a passing DKIM signature is aligned with From.
+    pub dkim_aligned: bool,
+    /// Whether a passing SPF check is aligned with From.
+    pub spf_aligned: bool,
+    pub error: Option<DmarcError>,
+}
+
+impl DmarcResult {
+    /// What to do with the message. `roll` should be a random number below
+    /// 100: a failing message gets the policy if `roll` is below `pct=`,
+    /// and the next less severe one otherwise.
+    pub fn disposition(&self, roll: u8) -> Policy {
+        let percent = self.record.as_ref().map_or(100, |record| record.percent);
+        match (self.result, self.policy) {
+            (AuthResult::Fail, policy) if roll < percent => policy,
+            (AuthResult::Fail, Policy::Reject) => Policy::Quarantine,
+            _ => Policy::None,
+        }
+    }
+}
+
+/// Formats the result as a `resinfo` of `Authentication-Results`, e.g.
+/// `dmarc=pass header.from=example.com`.
+impl fmt::Display for DmarcResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dmarc={}", self.result)?;
+        if let Some(domain) = &self.from_domain {
+            write!(f, " header.from={}", domain)?;
+        }
+        Ok(())
+    }
+}
+
+/// The domain of the message's single author.
+fn from_domain(message: &Message) -> Result<String, DmarcError> {
+    let mut fields = message
+        .header()
+        .iter()
+        .filter(|hf| hf.name().0.eq_ignore_ascii_case(b"From"));
+    let field = fields.next().ok_or(DmarcError::MissingFrom)?;
+    if fields.next().is_some() {
+        return Err(DmarcError::MultipleFrom);
+    }
+    let mailboxes = match field.inner() {
+        HeaderFieldInner::From(mailboxes) => mailboxes,
+        _ => return Err(DmarcError::MissingFrom),
+    };
+    match mailboxes.as_slice() {
+        [mailbox] => match mailbox.addr_spec.as_ref().map(|spec| &spec.domain) {
+            Some(Domain::Name(name)) => {
+                let name = String::from_utf8_lossy(&name.0);
+                Ok(name.trim_end_matches('.').to_lowercase())
+            }
+            _ => Err(DmarcError::MissingFrom),
+        },
+        [] => Err(DmarcError::MissingFrom),
+        _ => Err(DmarcError::MultipleFrom),
+    }
+}
+
+/// Check `message` against the DMARC policy of its From domain, given the
+/// results of checking SPF for its MAIL FROM and its DKIM signatures.
+pub fn evaluate<R: Resolver + ?Sized>(
+    message: &Message,
+    spf: &SpfResult,
+    dkim: &[DkimResult],
+    resolver: &R,
+) -> DmarcResult {
+    let mut result = DmarcResult {
+        result: AuthResult::None,
+        from_domain: None,
+        record: None,
+        policy: Policy::None,
+        dkim_aligned: false,
+        spf_aligned: false,
+        error: None,
+    };
+    if let Err(error) = check(message, spf, dkim, resolver, &mut result) {
+        result.result = error.result();
+        result.error = Some(error);
+    }
+    result
+}
+
+fn check<R: Resolver + ?Sized>(
+    message: &Message,
+    spf: &SpfResult,
+    dkim: &[DkimResult],
+    resolver: &R,
+    result: &mut DmarcResult,
+) -> Result<(), DmarcError> {
+    let domain = from_domain(message)?;
+    result.from_domain = Some(domain.clone());
+    let organizational = organizational_domain(&domain);
+    let (record, subdomain) = match DmarcRecord::lookup(&domain, resolver)? {
+        Some(record) => (record, false),
+        None if organizational != domain => match DmarcRecord::lookup(&organizational, resolver)? {
+            Some(record) => (record, true),
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    result.dkim_aligned = dkim.iter().any(|dkim| match &dkim.signature {
+        Some(signature) if dkim.result == AuthResult::Pass => {
+            record.dkim_alignment.is_aligned(&signature.domain, &domain)
+        }
+        _ => false,
+    });
+    result.spf_aligned =
+        spf.result == AuthResult::Pass && record.spf_alignment.is_aligned(&spf.domain, &domain);
+    result.result = if result.dkim_aligned || result.spf_aligned {
+        AuthResult::Pass
+    } else {
+        AuthResult::Fail
+    };
+    result.policy = match record.subdomain_policy {
+        Some(policy) if subdomain => policy,
+        _ => record.policy,
+    };
+    result.record = Some(record);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{evaluate, organizational_domain, DmarcRecord, Policy};
+    use crate::auth::dkim::{DkimResult, Signature};
+    use crate::auth::spf::check_mail_from;
+    use crate::auth::{AuthResult, MemoryResolver};
+    use crate::parse::email::parse_message;
+    use crate::smtp::envelope::ReversePath;
+
+    #[test]
+    fn test_organizational_domain() {
+        let examples = [
+            ("example.com", "example.com"),
+            ("mail.example.com", "example.com"),
+            ("a.b.example.co.uk", "example.co.uk"),
+            ("co.uk", "co.uk"),
+            ("Foo.Example.COM.", "example.com"),
+            ("a.b.c.www.ck", "www.ck"),
+            ("a.b.example.ck", "b.example.ck"),
+            ("host.unlisted-tld", "host.unlisted-tld"),
+        ];
+        for (domain, organizational) in examples.iter() {
+            assert_eq!(organizational_domain(domain), *organizational, "{}", domain);
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let record = DmarcRecord::parse(
+            "v=DMARC1; p=reject; sp=quarantine; pct=50; rua=mailto:r@example.com",
+        )
+        .unwrap();
+        assert_eq!(record.policy, Policy::Reject);
+        assert_eq!(record.aggregate_reports, ["mailto:r@example.com"]);
+        assert!(DmarcRecord::parse("p=reject; v=DMARC1").is_err());
+
+        let mut resolver = MemoryResolver::new();
+        resolver.add_txt(
+            "_dmarc.example.com",
+            "v=DMARC1; p=reject; sp=quarantine; aspf=s; pct=50",
+        );
+        resolver.add_txt("example.com", "v=spf1 ip4:192.0.2.0/24 -all");
+        resolver.add_txt("bounces.example.com", "v=spf1 ip4:192.0.2.0/24 -all");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let spf = |sender: &str| {
+            let sender = ReversePath::Address(sender.parse().unwrap());
+            check_mail_from(ip, &sender, "mx.example.com", &resolver)
+        };
+        let dkim = |domain: &str| DkimResult {
+            result: AuthResult::Pass,
+            signature: Some(Signature {
+                domain: domain.to_owned(),
+                ..Signature::parse("v=1; a=rsa-sha256; d=x.test; s=s; h=from; bh=AAAA; b=AAAA")
+                    .unwrap()
+            }),
+            error: None,
+        };
+        let message = parse_message(b"From: Joe <joe@news.example.com>\r\n\r\n").unwrap();
+
+        // SPF alignment is strict, but DKIM alignment is relaxed.
+        let result = evaluate(&message, &spf("a@news.example.com"), &[], &resolver);
+        assert_eq!(result.result, AuthResult::Fail);
+        assert_eq!(result.policy, Policy::Quarantine);
+        assert_eq!(result.disposition(0), Policy::Quarantine);
+        assert_eq!(result.disposition(50), Policy::None);
+        let result = evaluate(&message, &spf("a@example.com"), &[], &resolver);
+        assert!(!result.spf_aligned);
+        let result = evaluate(
+            &message,
+            &spf("a@bounces.example.com"),
+            &[dkim("mail.example.com")],
+            &resolver,
+        );
+        assert_eq!(result.result, AuthResult::Pass);
+        assert!(result.dkim_aligned);
+        assert_eq!(
+            result.to_string(),
+            "dmarc=pass header.from=news.example.com"
+        );
+        assert_eq!(result.disposition(0), Policy::None);
+
+        let message = parse_message(b"From: Joe <joe@example.com>\r\n\r\n").unwrap();
+        let result = evaluate(&message, &spf("a@example.com"), &[], &resolver);
+        assert_eq!(result.result, AuthResult::Pass);
+        let result = evaluate(
+            &message,
+            &spf("a@example.net"),
+            &[dkim("example.net")],
+            &resolver,
+        );
+        assert_eq!(result.result, AuthResult::Fail);
+        assert_eq!(result.disposition(49), Policy::Reject);
+        assert_eq!(result.disposition(50), Policy::Quarantine);
+
+        let message = parse_message(b"From: Joe <joe@example.org>\r\n\r\n").unwrap();
+        let result = evaluate(&message, &spf("a@example.org"), &[], &resolver);
+        assert_eq!(result.result, AuthResult::None);
+    }
+}
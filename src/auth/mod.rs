@@ -0,0 +1,173 @@
+//! Checking where a message came from: DKIM signatures (RFC 6376), the
+//! chains of signatures added by intermediaries (ARC, RFC 8617), the hosts
+//! allowed to send for a domain (SPF, RFC 7208), the policies that tie these
+//! to the From domain (DMARC, RFC 7489), and the DNS records they depend
+//! on. Results use the vocabulary of the `Authentication-Results` header
+//! field (RFC 8601).
+
+pub mod arc;
+pub mod dkim;
+pub mod dmarc;
+pub mod spf;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The result of an authentication method, as reported in `Authentication-Results`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AuthResult {
+    /// The method wasn't applicable, e.g. the message isn't signed.
+    None,
+    Pass,
+    Fail,
+    /// SPF only: the host probably isn't allowed to send for the domain.
+    SoftFail,
+    /// The check passed, but local policy rejects the result.
+    Policy,
+    Neutral,
+    /// A transient error, e.g. a DNS timeout; trying later may succeed.
+    TempError,
+    /// An error that won't go away, e.g. a malformed signature or DNS record.
+    PermError,
+}
+
+impl AuthResult {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let results = [
+            AuthResult::None,
+            AuthResult::Pass,
+            AuthResult::Fail,
+            AuthResult::SoftFail,
+            AuthResult::Policy,
+            AuthResult::Neutral,
+            AuthResult::TempError,
+            AuthResult::PermError,
+        ];
+        results
+            .iter()
+            .copied()
+            .find(|result| value.eq_ignore_ascii_case(&result.to_string()))
+    }
+}
+
+impl fmt::Display for AuthResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = match self {
+            AuthResult::None => "none",
+            AuthResult::Pass => "pass",
+            AuthResult::Fail => "fail",
+            AuthResult::SoftFail => "softfail",
+            AuthResult::Policy => "policy",
+            AuthResult::Neutral => "neutral",
+            AuthResult::TempError => "temperror",
+            AuthResult::PermError => "permerror",
+        };
+        write!(f, "{}", result)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name doesn't exist, or has no records of the requested type.
+    NotFound,
+    /// The lookup failed, e.g. because of a timeout or SERVFAIL.
+    Temporary(String),
+}
+
+/// The DNS lookups needed to authenticate messages.
+pub trait Resolver {
+    /// The TXT records at `name`, each with its character strings concatenated.
+    fn txt(&self, name: &str) -> Result<Vec<String>, DnsError>;
+
+    fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError>;
+
+    fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, DnsError>;
+
+    /// The MX records at `name`, as preferences and exchange names.
+    fn mx(&self, name: &str) -> Result<Vec<(u16, String)>, DnsError>;
+
+    /// The names the reverse zone gives for `ip`.
+    fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, DnsError>;
+}
+
+/// A resolver answering from records added to it, e.g. for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryResolver {
+    txt: HashMap<String, Vec<String>>,
+    a: HashMap<String, Vec<Ipv4Addr>>,
+    aaaa: HashMap<String, Vec<Ipv6Addr>>,
+    mx: HashMap<String, Vec<(u16, String)>>,
+    ptr: HashMap<IpAddr, Vec<String>>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_txt(&mut self, name: &str, record: &str) {
+        self.txt
+            .entry(normalize_name(name))
+            .or_default()
+            .push(record.to_owned());
+    }
+
+    pub fn add_a(&mut self, name: &str, address: Ipv4Addr) {
+        self.a
+            .entry(normalize_name(name))
+            .or_default()
+            .push(address);
+    }
+
+    pub fn add_aaaa(&mut self, name: &str, address: Ipv6Addr) {
+        self.aaaa
+            .entry(normalize_name(name))
+            .or_default()
+            .push(address);
+    }
+
+    pub fn add_mx(&mut self, name: &str, preference: u16, exchange: &str) {
+        self.mx
+            .entry(normalize_name(name))
+            .or_default()
+            .push((preference, exchange.to_owned()));
+    }
+
+    pub fn add_ptr(&mut self, ip: IpAddr, name: &str) {
+        self.ptr.entry(ip).or_default().push(name.to_owned());
+    }
+}
+
+fn lookup<T: Clone>(records: &HashMap<String, Vec<T>>, name: &str) -> Result<Vec<T>, DnsError> {
+    records
+        .get(&normalize_name(name))
+        .cloned()
+        .ok_or(DnsError::NotFound)
+}
+
+impl Resolver for MemoryResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        lookup(&self.txt, name)
+    }
+
+    fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+        lookup(&self.a, name)
+    }
+
+    fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, DnsError> {
+        lookup(&self.aaaa, name)
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<(u16, String)>, DnsError> {
+        lookup(&self.mx, name)
+    }
+
+    fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, DnsError> {
+        self.ptr.get(&ip).cloned().ok_or(DnsError::NotFound)
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,838 @@
+//! Sender Policy Framework (RFC 7208): checking whether a host is allowed
+//! to send mail from a domain, according to the domain's `v=spf1` record.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{AuthResult, DnsError, Resolver};
+use crate::smtp::envelope::ReversePath;
+
+/// RFC 7208 section 4.6.4
+const MAX_LOOKUPS: usize = 10;
+const MAX_VOID_LOOKUPS: usize = 2;
+const MAX_NAMES: usize = 10;
+
+const MAX_DOMAIN_LEN: usize = 253;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpfError {
+    /// A DNS lookup failed, e.g. because of a timeout.
+    Dns(String),
+    /// The domain publishes more than one SPF record.
+    MultipleRecords(String),
+    /// A term of a record is malformed, or isn't allowed where it is.
+    InvalidRecord(String),
+    /// An `include` or `redirect` names a domain without an SPF record.
+    MissingRecord(String),
+    /// Evaluating the record needed more than 10 DNS lookups.
+    TooManyLookups,
+    /// More than 2 lookups found nothing.
+    TooManyVoidLookups,
+    /// An `mx` mechanism found more than 10 names.
+    TooManyNames,
+}
+
+impl SpfError {
+    /// The result reported for a check that ended with this error.
+    pub fn result(&self) -> AuthResult {
+        match self {
+            SpfError::Dns(_) => AuthResult::TempError,
+            _ => AuthResult::PermError,
+        }
+    }
+}
+
+/// Which identity of the sender was checked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// The domain of the MAIL FROM address.
+    MailFrom,
+    /// The name given in HELO or EHLO.
+    Helo,
+}
+
+/// The outcome of an SPF check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpfResult {
+    /// One of `None`, `Neutral`, `Pass`, `Fail`, `SoftFail`, `TempError`
+    /// and `PermError`.
+    pub result: AuthResult,
+    pub identity: Identity,
+    /// The sender as checked, e.g. `user@example.com`, or `postmaster@`
+    /// the HELO name.
+    pub sender: String,
+    /// The domain whose record was checked.
+    pub domain: String,
+    /// Why the record couldn't be evaluated.
+    pub error: Option<SpfError>,
+}
+
+/// Formats the result as a `resinfo` of `Authentication-Results`, e.g.
+/// `spf=pass smtp.mailfrom=user@example.com`.
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.identity {
+            Identity::MailFrom => write!(f, "spf={} smtp.mailfrom={}", self.result, self.sender),
+            Identity::Helo => write!(f, "spf={} smtp.helo={}", self.result, self.domain),
+        }
+    }
+}
+
+/// Check whether `ip` may send mail with the given MAIL FROM reverse-path.
+/// The null reverse-path has no domain, so the HELO name is checked instead.
+pub fn check_mail_from<R: Resolver + ?Sized>(
+    ip: IpAddr,
+    reverse_path: &ReversePath,
+    helo: &str,
+    resolver: &R,
+) -> SpfResult {
+    match reverse_path {
+        ReversePath::Null => check_helo(ip, helo, resolver),
+        ReversePath::Address(address) => {
+            let local_part = match address.local_part.as_str() {
+                "" => "postmaster",
+                local_part => local_part,
+            };
+            check(
+                ip,
+                Identity::MailFrom,
+                local_part,
+                &address.domain,
+                helo,
+                resolver,
+            )
+        }
+    }
+}
+
+/// Check whether `ip` may use `helo` as its HELO name.
+pub fn check_helo<R: Resolver + ?Sized>(ip: IpAddr, helo: &str, resolver: &R) -> SpfResult {
+    check(ip, Identity::Helo, "postmaster", helo, helo, resolver)
+}
+
+fn check<R: Resolver + ?Sized>(
+    ip: IpAddr,
+    identity: Identity,
+    local_part: &str,
+    domain: &str,
+    helo: &str,
+    resolver: &R,
+) -> SpfResult {
+    let domain = domain.trim_end_matches('.');
+    let mut checker = Checker {
+        resolver,
+        // RFC 7208 section 5: IPv4-mapped addresses are treated as IPv4.
+        ip: match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        },
+        local_part: local_part.to_owned(),
+        sender_domain: domain.to_owned(),
+        helo: helo.to_owned(),
+        lookups: 0,
+        void_lookups: 0,
+    };
+    let (result, error) = match checker.check_host(domain) {
+        Ok(result) => (result, None),
+        Err(error) => (error.result(), Some(error)),
+    };
+    SpfResult {
+        result,
+        identity,
+        sender: format!("{}@{}", local_part, domain),
+        domain: domain.to_owned(),
+        error,
+    }
+}
+
+/// Whether `domain` can have an SPF record: RFC 7208 section 4.3.
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    domain.len() <= MAX_DOMAIN_LEN
+        && labels.len() > 1
+        && labels
+            .iter()
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// A piece of a macro string (RFC 7208 section 7.1).
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum MacroPiece {
+    Literal(String),
+    Macro {
+        /// The macro letter, in lowercase.
+        letter: char,
+        /// Whether the letter was uppercase, asking for the value to be URL-escaped.
+        escape: bool,
+        /// How many parts to keep, from the right.
+        digits: Option<usize>,
+        reverse: bool,
+        delimiters: Vec<char>,
+    },
+}
+
+fn parse_macro_string(s: &str) -> Option<Vec<MacroPiece>> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            if !('!'..='~').contains(&ch) {
+                return None;
+            }
+            literal.push(ch);
+            continue;
+        }
+        match chars.next()? {
+            '%' => literal.push('%'),
+            '_' => literal.push(' '),
+            '-' => literal.push_str("%20"),
+            '{' => {
+                let mut body = String::new();
+                loop {
+                    match chars.next()? {
+                        '}' => break,
+                        ch => body.push(ch),
+                    }
+                }
+                let mut body = body.chars().peekable();
+                let letter = body.next()?;
+                if !"sloidpvh".contains(letter.to_ascii_lowercase()) {
+                    return None;
+                }
+                let mut digits = String::new();
+                while let Some(digit) = body.next_if(char::is_ascii_digit) {
+                    digits.push(digit);
+                }
+                let digits = match digits.as_str() {
+                    "" => None,
+                    digits => match digits.parse() {
+                        Ok(0) | Err(_) => return None,
+                        Ok(n) => Some(n),
+                    },
+                };
+                let reverse = body.next_if(|ch| *ch == 'r' || *ch == 'R').is_some();
+                let delimiters: Vec<char> = body.collect();
+                if !delimiters.iter().all(|ch| ".-+,/_=".contains(*ch)) {
+                    return None;
+                }
+                if !literal.is_empty() {
+                    pieces.push(MacroPiece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(MacroPiece::Macro {
+                    letter: letter.to_ascii_lowercase(),
+                    escape: letter.is_ascii_uppercase(),
+                    digits,
+                    reverse,
+                    delimiters,
+                });
+            }
+            _ => return None,
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(MacroPiece::Literal(literal));
+    }
+    Some(pieces)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Mechanism {
+    All,
+    Include(Vec<MacroPiece>),
+    A {
+        domain: Option<Vec<MacroPiece>>,
+        ip4_prefix: u8,
+        ip6_prefix: u8,
+    },
+    Mx {
+        domain: Option<Vec<MacroPiece>>,
+        ip4_prefix: u8,
+        ip6_prefix: u8,
+    },
+    Ptr(Option<Vec<MacroPiece>>),
+    Ip(IpAddr, u8),
+    Exists(Vec<MacroPiece>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record {
+    /// Mechanisms, with the result if they match.
+    directives: Vec<(AuthResult, Mechanism)>,
+    redirect: Option<Vec<MacroPiece>>,
+}
+
+/// Whether a TXT record is an SPF record.
+fn is_spf_record(record: &str) -> bool {
+    let version = "v=spf1";
+    record.len() >= version.len()
+        && record.as_bytes()[..version.len()].eq_ignore_ascii_case(version.as_bytes())
+        && matches!(record.as_bytes().get(version.len()), None | Some(b' '))
+}
+
+impl Record {
+    fn parse(record: &str) -> Result<Self, SpfError> {
+        let mut directives = vec![];
+        let mut redirect = None;
+        let mut explanation = false;
+        for term in record.split(' ').skip(1).filter(|term| !term.is_empty()) {
+            let invalid = || SpfError::InvalidRecord(term.to_owned());
+            let name_end = term.find([':', '/']).unwrap_or(term.len());
+            if let Some(eq) = term[..name_end].find('=') {
+                let (name, value) = (&term[..eq], &term[eq + 1..]);
+                let name_ok = name.starts_with(|ch: char| ch.is_ascii_alphabetic())
+                    && name
+                        .chars()
+                        .all(|ch| ch.is_ascii_alphanumeric() || "-_.".contains(ch));
+                if !name_ok {
+                    return Err(invalid());
+                }
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return Err(invalid());
+                    }
+                    redirect = Some(parse_macro_string(value).ok_or_else(invalid)?);
+                } else if name.eq_ignore_ascii_case("exp") {
+                    // Explanations are only of use when rejecting mail in an
+                    // SMTP server, so they aren't expanded.
+                    if explanation {
+                        return Err(invalid());
+                    }
+                    explanation = true;
+                }
+                // Unknown modifiers are ignored.
+                continue;
+            }
+
+            let (qualifier, term) = match term.chars().next() {
+                Some('+') => (AuthResult::Pass, &term[1..]),
+                Some('-') => (AuthResult::Fail, &term[1..]),
+                Some('~') => (AuthResult::SoftFail, &term[1..]),
+                Some('?') => (AuthResult::Neutral, &term[1..]),
+                _ => (AuthResult::Pass, term),
+            };
+            let mechanism = parse_mechanism(term).ok_or_else(invalid)?;
+            directives.push((qualifier, mechanism));
+        }
+        Ok(Record {
+            directives,
+            redirect,
+        })
+    }
+}
+
+fn parse_mechanism(term: &str) -> Option<Mechanism> {
+    let name_end = term.find([':', '/']).unwrap_or(term.len());
+    let name = term[..name_end].to_ascii_lowercase();
+    let rest = &term[name_end..];
+    let domain = |rest: &str| -> Option<Option<Vec<MacroPiece>>> {
+        match rest.strip_prefix(':') {
+            Some(spec) if !spec.is_empty() => Some(Some(parse_macro_string(spec)?)),
+            Some(_) => None,
+            None if rest.is_empty() => Some(None),
+            None => None,
+        }
+    };
+    let prefix = |digits: &str, max: u8| -> Option<u8> {
+        if digits.is_empty() || !digits.bytes().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok().filter(|n| *n <= max)
+    };
+    // Split off `/n` and `//n`, which may follow a domain-spec.
+    let dual_prefix = |rest: &str| -> Option<(Option<Vec<MacroPiece>>, u8, u8)> {
+        let mut rest = rest;
+        let mut ip6_prefix = 128;
+        if let Some(i) = rest.rfind("//") {
+            ip6_prefix = prefix(&rest[i + 2..], 128)?;
+            rest = &rest[..i];
+        }
+        let mut ip4_prefix = 32;
+        if let Some(i) = rest.rfind('/') {
+            if rest[i + 1..].bytes().all(|ch| ch.is_ascii_digit()) {
+                ip4_prefix = prefix(&rest[i + 1..], 32)?;
+                rest = &rest[..i];
+            }
+        }
+        Some((domain(rest)?, ip4_prefix, ip6_prefix))
+    };
+    let required = |rest: &str| match domain(rest) {
+        Some(Some(spec)) => Some(spec),
+        _ => None,
+    };
+    let mechanism = match name.as_str() {
+        "all" if rest.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(required(rest)?),
+        "exists" => Mechanism::Exists(required(rest)?),
+        "ptr" => Mechanism::Ptr(domain(rest)?),
+        "a" => {
+            let (domain, ip4_prefix, ip6_prefix) = dual_prefix(rest)?;
+            Mechanism::A {
+                domain,
+                ip4_prefix,
+                ip6_prefix,
+            }
+        }
+        "mx" => {
+            let (domain, ip4_prefix, ip6_prefix) = dual_prefix(rest)?;
+            Mechanism::Mx {
+                domain,
+                ip4_prefix,
+                ip6_prefix,
+            }
+        }
+        "ip4" | "ip6" => {
+            let value = rest.strip_prefix(':')?;
+            let (address, length) = match value.find('/') {
+                Some(i) => (&value[..i], Some(&value[i + 1..])),
+                None => (value, None),
+            };
+            if name == "ip4" {
+                let address: Ipv4Addr = address.parse().ok()?;
+                let length = length.map_or(Some(32), |length| prefix(length, 32))?;
+                Mechanism::Ip(IpAddr::V4(address), length)
+            } else {
+                let address: Ipv6Addr = address.parse().ok()?;
+                let length = length.map_or(Some(128), |length| prefix(length, 128))?;
+                Mechanism::Ip(IpAddr::V6(address), length)
+            }
+        }
+        _ => return None,
+    };
+    Some(mechanism)
+}
+
+/// Whether `ip` is in the network of `network` with the given prefix length.
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (ip, network, bits) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            (u32::from(ip).into(), u32::from(network).into(), 32)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    let shift = bits - u32::from(prefix);
+    ip.checked_shr(shift).unwrap_or(0) == network.checked_shr(shift).unwrap_or(0)
+}
+
+/// The state of one evaluation of `check_host()`.
+struct Checker<'r, R: ?Sized> {
+    resolver: &'r R,
+    ip: IpAddr,
+    local_part: String,
+    sender_domain: String,
+    helo: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl<'r, R: Resolver + ?Sized> Checker<'r, R> {
+    /// The result for `domain`'s record. Errors are for `temperror` and `permerror`.
+    fn check_host(&mut self, domain: &str) -> Result<AuthResult, SpfError> {
+        if !is_valid_domain(domain) {
+            return Ok(AuthResult::None);
+        }
+        let records = match self.resolver.txt(domain) {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => return Ok(AuthResult::None),
+            Err(DnsError::Temporary(e)) => return Err(SpfError::Dns(e)),
+        };
+        let mut records = records.iter().filter(|record| is_spf_record(record));
+        let record = match (records.next(), records.next()) {
+            (None, _) => return Ok(AuthResult::None),
+            (Some(record), None) => Record::parse(record)?,
+            (Some(_), Some(_)) => return Err(SpfError::MultipleRecords(domain.to_owned())),
+        };
+
+        for (qualifier, mechanism) in record.directives.iter() {
+            if self.matches(mechanism, domain)? {
+                return Ok(*qualifier);
+            }
+        }
+        match &record.redirect {
+            Some(target) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(target, domain);
+                match self.check_host(&target)? {
+                    AuthResult::None => Err(SpfError::MissingRecord(target)),
+                    result => Ok(result),
+                }
+            }
+            None => Ok(AuthResult::Neutral),
+        }
+    }
+
+    fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, SpfError> {
+        let target = |checker: &Self, spec: &Option<Vec<MacroPiece>>| match spec {
+            Some(spec) => checker.expand_domain(spec, domain),
+            None => domain.to_owned(),
+        };
+        let matched = match mechanism {
+            Mechanism::All => true,
+            Mechanism::Ip(network, prefix) => in_network(self.ip, *network, *prefix),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain);
+                match self.check_host(&target)? {
+                    AuthResult::Pass => true,
+                    AuthResult::None => return Err(SpfError::MissingRecord(target)),
+                    _ => false,
+                }
+            }
+            Mechanism::A {
+                domain: spec,
+                ip4_prefix,
+                ip6_prefix,
+            } => {
+                self.count_lookup()?;
+                let target = target(self, spec);
+                let addresses = self.addresses(&target, true)?;
+                self.any_in_network(&addresses, *ip4_prefix, *ip6_prefix)
+            }
+            Mechanism::Mx {
+                domain: spec,
+                ip4_prefix,
+                ip6_prefix,
+            } => {
+                self.count_lookup()?;
+                let target = target(self, spec);
+                let exchanges = self.void_lookup(self.resolver.mx(&target))?;
+                if exchanges.len() > MAX_NAMES {
+                    return Err(SpfError::TooManyNames);
+                }
+                let mut matched = false;
+                for (_, exchange) in exchanges.iter() {
+                    let addresses = self.addresses(exchange, false)?;
+                    if self.any_in_network(&addresses, *ip4_prefix, *ip6_prefix) {
+                        matched = true;
+                        break;
+                    }
+                }
+                matched
+            }
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = target(self, spec);
+                // If the lookup fails, the host just has no validated name.
+                let names = self.resolver.ptr(self.ip).unwrap_or_default();
+                let mut matched = false;
+                for name in names.iter().take(MAX_NAMES) {
+                    let name = name.trim_end_matches('.');
+                    if !super::dkim::is_subdomain(name, &target) {
+                        continue;
+                    }
+                    // Only names that lead back to the address count.
+                    if let Ok(addresses) = self.addresses(name, false) {
+                        if addresses.contains(&self.ip) {
+                            matched = true;
+                            break;
+                        }
+                    }
+                }
+                matched
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain);
+                // Always an A lookup, whatever the client's address.
+                !self.void_lookup(self.resolver.a(&target))?.is_empty()
+            }
+        };
+        Ok(matched)
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfError> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(SpfError::TooManyLookups);
+        }
+        Ok(())
+    }
+
+    /// The records from a lookup, counting it if it found nothing.
+    fn void_lookup<T>(&mut self, records: Result<Vec<T>, DnsError>) -> Result<Vec<T>, SpfError> {
+        let records = match records {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => vec![],
+            Err(DnsError::Temporary(e)) => return Err(SpfError::Dns(e)),
+        };
+        if records.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(SpfError::TooManyVoidLookups);
+            }
+        }
+        Ok(records)
+    }
+
+    /// The addresses of `name` in the client's address family.
+    fn addresses(&mut self, name: &str, count_void: bool) -> Result<Vec<IpAddr>, SpfError> {
+        let addresses: Result<Vec<IpAddr>, DnsError> = match self.ip {
+            IpAddr::V4(_) => self
+                .resolver
+                .a(name)
+                .map(|addresses| addresses.into_iter().map(IpAddr::V4).collect()),
+            IpAddr::V6(_) => self
+                .resolver
+                .aaaa(name)
+                .map(|addresses| addresses.into_iter().map(IpAddr::V6).collect()),
+        };
+        if count_void {
+            self.void_lookup(addresses)
+        } else {
+            match addresses {
+                Ok(addresses) => Ok(addresses),
+                Err(DnsError::NotFound) => Ok(vec![]),
+                Err(DnsError::Temporary(e)) => Err(SpfError::Dns(e)),
+            }
+        }
+    }
+
+    fn any_in_network(&self, addresses: &[IpAddr], ip4_prefix: u8, ip6_prefix: u8) -> bool {
+        addresses.iter().any(|address| {
+            let prefix = match address {
+                IpAddr::V4(_) => ip4_prefix,
+                IpAddr::V6(_) => ip6_prefix,
+            };
+            in_network(self.ip, *address, prefix)
+        })
+    }
+
+    /// Expand a domain-spec, shortening the result to fit in a domain name.
+    fn expand_domain(&self, spec: &[MacroPiece], domain: &str) -> String {
+        let mut expanded = self.expand(spec, domain);
+        while expanded.len() > MAX_DOMAIN_LEN {
+            match expanded.find('.') {
+                Some(i) => {
+                    expanded.drain(..=i);
+                }
+                None => break,
+            }
+        }
+        expanded
+    }
+
+    /// Expand a macro string (RFC 7208 section 7.3). `%{p}` is always
+    /// `unknown`: finding a validated name takes many lookups, and RFC 7208
+    /// discourages its use.
+    fn expand(&self, spec: &[MacroPiece], domain: &str) -> String {
+        let mut out = String::new();
+        for piece in spec {
+            let (letter, escape, digits, reverse, delimiters) = match piece {
+                MacroPiece::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                MacroPiece::Macro {
+                    letter,
+                    escape,
+                    digits,
+                    reverse,
+                    delimiters,
+                } => (letter, escape, digits, reverse, delimiters),
+            };
+            let value = match letter {
+                's' => format!("{}@{}", self.local_part, self.sender_domain),
+                'l' => self.local_part.clone(),
+                'o' => self.sender_domain.clone(),
+                'd' => domain.to_owned(),
+                'i' => match self.ip {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => {
+                        let nibbles: Vec<String> = ip
+                            .octets()
+                            .iter()
+                            .flat_map(|byte| vec![byte >> 4, byte & 0xf])
+                            .map(|nibble| format!("{:x}", nibble))
+                            .collect();
+                        nibbles.join(".")
+                    }
+                },
+                'p' => "unknown".to_owned(),
+                'v' => match self.ip {
+                    IpAddr::V4(_) => "in-addr".to_owned(),
+                    IpAddr::V6(_) => "ip6".to_owned(),
+                },
+                'h' => self.helo.clone(),
+                _ => unreachable!("macro letters are checked when parsing"),
+            };
+            let mut parts: Vec<&str> = if delimiters.is_empty() {
+                value.split('.').collect()
+            } else {
+                value.split(|ch| delimiters.contains(&ch)).collect()
+            };
+            if *reverse {
+                parts.reverse();
+            }
+            if let Some(digits) = digits {
+                let skip = parts.len().saturating_sub(*digits);
+                parts.drain(..skip);
+            }
+            let value = parts.join(".");
+            if *escape {
+                for ch in value.chars() {
+                    if ch.is_ascii_alphanumeric() || "-._~".contains(ch) {
+                        out.push(ch);
+                    } else {
+                        let mut buf = [0; 4];
+                        for byte in ch.encode_utf8(&mut buf).bytes() {
+                            out.push_str(&format!("%{:02X}", byte));
+                        }
+                    }
+                }
+            } else {
+                out.push_str(&value);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{check_helo, check_mail_from, parse_macro_string, Checker, SpfError};
+    use crate::auth::{AuthResult, MemoryResolver};
+    use crate::smtp::envelope::ReversePath;
+
+    #[test]
+    fn test_macros() {
+        let expand = |ip: &str, spec: &str| {
+            let checker = Checker {
+                resolver: &MemoryResolver::new(),
+                ip: ip.parse().unwrap(),
+                local_part: "strong-bad".to_owned(),
+                sender_domain: "email.example.com".to_owned(),
+                helo: "mx.example.org".to_owned(),
+                lookups: 0,
+                void_lookups: 0,
+            };
+            let spec = parse_macro_string(spec).unwrap();
+            checker.expand(&spec, "email.example.com")
+        };
+        // RFC 7208 section 7.4
+        let examples = [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+            ("%{S}%%%_%-", "strong-bad%40email.example.com% %20"),
+        ];
+        for (spec, expanded) in examples.iter() {
+            assert_eq!(expand("192.0.2.3", spec), *expanded, "{}", spec);
+        }
+        assert_eq!(
+            expand("2001:db8::cb01", "%{ir}.%{v}._spf.%{d2}"),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+        for spec in ["%{d0}", "%{x}", "%{d", "%z", "a b"].iter() {
+            assert_eq!(parse_macro_string(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_check_host() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_txt(
+            "example.com",
+            "v=spf1 ip4:192.0.2.0/24 a:mail.example.com mx include:_spf.example.net \
+             exists:%{ir}.allow.example.com -all",
+        );
+        resolver.add_txt("_spf.example.net", "v=spf1 ip6:2001:db8::/32 ~all");
+        resolver.add_a("mail.example.com", "198.51.100.1".parse().unwrap());
+        resolver.add_mx("example.com", 10, "mx.example.com");
+        resolver.add_a("mx.example.com", "198.51.100.2".parse().unwrap());
+        resolver.add_a(
+            "3.113.0.203.allow.example.com",
+            "127.0.0.2".parse().unwrap(),
+        );
+        resolver.add_txt("sub.example.com", "v=spf1 redirect=example.com");
+        resolver.add_txt("helo.example.org", "v=spf1 a -all");
+        resolver.add_a("helo.example.org", "198.51.100.9".parse().unwrap());
+
+        let sender = ReversePath::Address("user@example.com".parse().unwrap());
+        let check = |ip: &str, sender: &ReversePath| {
+            let ip: IpAddr = ip.parse().unwrap();
+            check_mail_from(ip, sender, "helo.example.org", &resolver)
+        };
+        let result = check("192.0.2.55", &sender);
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(
+            result.to_string(),
+            "spf=pass smtp.mailfrom=user@example.com"
+        );
+        for ip in ["198.51.100.1", "198.51.100.2", "2001:db8::1", "203.0.113.3"].iter() {
+            assert_eq!(check(ip, &sender).result, AuthResult::Pass, "{}", ip);
+        }
+        // The included record's ~all doesn't count as a match.
+        assert_eq!(check("203.0.113.4", &sender).result, AuthResult::Fail);
+        let sub = ReversePath::Address("user@sub.example.com".parse().unwrap());
+        assert_eq!(check("192.0.2.1", &sub).result, AuthResult::Pass);
+        assert_eq!(check("203.0.113.4", &sub).result, AuthResult::Fail);
+        let other = ReversePath::Address("user@example.org".parse().unwrap());
+        assert_eq!(check("192.0.2.1", &other).result, AuthResult::None);
+
+        // Bounces are checked against the HELO name.
+        let result = check("198.51.100.9", &ReversePath::Null);
+        assert_eq!(result.result, AuthResult::Pass);
+        assert_eq!(result.to_string(), "spf=pass smtp.helo=helo.example.org");
+        let ip = "198.51.100.10".parse().unwrap();
+        assert_eq!(
+            check_helo(ip, "helo.example.org", &resolver).result,
+            AuthResult::Fail
+        );
+
+        let mut resolver = MemoryResolver::new();
+        let records = [
+            ("loop.example.com", "v=spf1 include:loop.example.com"),
+            (
+                "void.example.com",
+                "v=spf1 a:a.test mx:b.test exists:c.test -all",
+            ),
+            ("bad.example.com", "v=spf1 ip4:192.0.2.300 -all"),
+            ("two.example.com", "v=spf1 -all"),
+            ("two.example.com", "v=spf1 +all"),
+        ];
+        for (name, record) in records.iter() {
+            resolver.add_txt(name, record);
+        }
+        let check = |domain: &str| {
+            let sender = ReversePath::Address(format!("user@{}", domain).parse().unwrap());
+            let ip = "192.0.2.1".parse().unwrap();
+            let result = check_mail_from(ip, &sender, "helo.example.org", &resolver);
+            assert_eq!(result.result, AuthResult::PermError);
+            result.error.unwrap()
+        };
+        assert_eq!(check("loop.example.com"), SpfError::TooManyLookups);
+        assert_eq!(check("void.example.com"), SpfError::TooManyVoidLookups);
+        assert_eq!(
+            check("bad.example.com"),
+            SpfError::InvalidRecord("ip4:192.0.2.300".to_owned())
+        );
+        assert_eq!(
+            check("two.example.com"),
+            SpfError::MultipleRecords("two.example.com".to_owned())
+        );
+    }
+}
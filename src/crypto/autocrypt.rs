@@ -0,0 +1,300 @@
+//! Autocrypt (Level 1): OpenPGP keys announced in the `Autocrypt` header
+//! field, and passed on to the other recipients of an encrypted message in
+//! `Autocrypt-Gossip` fields.
+
+use crate::headers::address::{Address, Mailbox};
+use crate::headers::render::{addr_spec, fold, make_field};
+use crate::headers::HeaderFieldInner;
+use crate::{HeaderField, Message};
+
+pub const AUTOCRYPT: &str = "Autocrypt";
+pub const AUTOCRYPT_GOSSIP: &str = "Autocrypt-Gossip";
+
+// Break priorities for folding; breaks between attributes are preferred.
+const INSIDE_KEYDATA: usize = 0;
+const BETWEEN_ATTRIBUTES: usize = 1;
+
+// Base64 can be broken anywhere, but breaking between quanta keeps it tidy.
+const KEYDATA_CHUNK: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AutocryptError {
+    /// An attribute isn't of the form `name=value`.
+    Malformed,
+    MissingAttribute(&'static str),
+    /// An attribute that receivers must understand, i.e. one whose name
+    /// doesn't start with `_`.
+    UnknownAttribute(String),
+    InvalidKeydata,
+    /// The From field doesn't have exactly one address.
+    InvalidFrom,
+    /// No valid field has an `addr` matching the From address.
+    NotFound,
+    /// More than one valid field has an `addr` matching the From address.
+    Ambiguous,
+    /// A field can't be made because the attribute with this name (e.g.
+    /// an internationalized `addr`) isn't printable ASCII, or has a `;`.
+    InvalidAttribute(String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PreferEncrypt {
+    NoPreference,
+    /// The sender wants to encrypt whenever the recipient does too.
+    Mutual,
+}
+
+/// The value of an `Autocrypt` or `Autocrypt-Gossip` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Autocrypt {
+    pub addr: String,
+    /// Always `NoPreference` in gossip.
+    pub prefer_encrypt: PreferEncrypt,
+    /// The OpenPGP transferable public key, not armored.
+    pub keydata: Vec<u8>,
+    /// Attributes starting with `_`, which may be ignored.
+    pub non_critical: Vec<(String, String)>,
+}
+
+/// Whether `value` can be written as the value of an attribute.
+fn is_attribute_value(value: &str) -> bool {
+    let value = value.trim();
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|ch| (b' '..=b'~').contains(&ch) && ch != b';')
+}
+
+/// The address of `mailbox`, lowercased for comparison.
+fn address(mailbox: &Mailbox) -> Option<String> {
+    let spec = mailbox.addr_spec.as_ref()?;
+    Some(String::from_utf8_lossy(&addr_spec(spec)).to_lowercase())
+}
+
+/// The values of the fields of `message` called `name`.
+fn fields<'m>(message: &'m Message, name: &'m str) -> impl Iterator<Item = Autocrypt> + 'm {
+    message
+        .header()
+        .iter()
+        .filter(move |hf| hf.name().0.eq_ignore_ascii_case(name.as_bytes()))
+        .filter_map(|hf| Autocrypt::parse(&String::from_utf8_lossy(&hf.unfolded_value().0)).ok())
+}
+
+impl Autocrypt {
+    pub fn new(addr: &str, keydata: Vec<u8>) -> Self {
+        Autocrypt {
+            addr: addr.to_owned(),
+            prefer_encrypt: PreferEncrypt::NoPreference,
+            keydata,
+            non_critical: vec![],
+        }
+    }
+
+    /// Parse an unfolded field value, e.g. `addr=alice@example.com; keydata=...`.
+    pub fn parse(value: &str) -> Result<Self, AutocryptError> {
+        let mut addr = None;
+        let mut prefer_encrypt = PreferEncrypt::NoPreference;
+        let mut keydata = None;
+        let mut non_critical = vec![];
+        for attribute in value.split(';').map(str::trim).filter(|a| !a.is_empty()) {
+            let (name, value) = attribute.split_once('=').ok_or(AutocryptError::Malformed)?;
+            let (name, value) = (name.trim(), value.trim());
+            match name.to_ascii_lowercase().as_str() {
+                "addr" => addr = Some(value.to_owned()),
+                "prefer-encrypt" if value.eq_ignore_ascii_case("mutual") => {
+                    prefer_encrypt = PreferEncrypt::Mutual
+                }
+                "prefer-encrypt" => prefer_encrypt = PreferEncrypt::NoPreference,
+                "keydata" => {
+                    let encoded: String = value.split_ascii_whitespace().collect();
+                    let decoded =
+                        base64::decode(encoded).map_err(|_| AutocryptError::InvalidKeydata)?;
+                    keydata = Some(decoded);
+                }
+                _ if name.starts_with('_') => {
+                    non_critical.push((name.to_owned(), value.to_owned()))
+                }
+                _ => return Err(AutocryptError::UnknownAttribute(name.to_owned())),
+            }
+        }
+        Ok(Autocrypt {
+            addr: addr.ok_or(AutocryptError::MissingAttribute("addr"))?,
+            prefer_encrypt,
+            keydata: keydata
+                .filter(|keydata| !keydata.is_empty())
+                .ok_or(AutocryptError::MissingAttribute("keydata"))?,
+            non_critical,
+        })
+    }
+
+    /// The `Autocrypt` field of `message`: the only valid one whose `addr`
+    /// matches the single From address. Others are ignored.
+    pub fn find(message: &Message) -> Result<Self, AutocryptError> {
+        let from = match message.header_field("From").map(|hf| hf.inner()) {
+            Some(HeaderFieldInner::From(mailboxes)) if mailboxes.len() == 1 => {
+                address(&mailboxes[0]).ok_or(AutocryptError::InvalidFrom)?
+            }
+            _ => return Err(AutocryptError::InvalidFrom),
+        };
+        let mut matching = fields(message, AUTOCRYPT).filter(|ac| ac.addr.to_lowercase() == from);
+        let found = matching.next().ok_or(AutocryptError::NotFound)?;
+        if matching.next().is_some() {
+            return Err(AutocryptError::Ambiguous);
+        }
+        Ok(found)
+    }
+
+    /// The valid `Autocrypt-Gossip` fields of `part`, which is normally the
+    /// decrypted content of `message`, whose `addr` is a To or Cc
+    /// recipient of `message`.
+    pub fn gossip(part: &Message, message: &Message) -> Vec<Self> {
+        let mut recipients = vec![];
+        for hf in message.header() {
+            let addresses = match hf.inner() {
+                HeaderFieldInner::To(addresses) | HeaderFieldInner::Cc(addresses) => addresses,
+                _ => continue,
+            };
+            for recipient in addresses {
+                match recipient {
+                    Address::Mailbox(mailbox) => recipients.extend(address(mailbox)),
+                    Address::Group(group) => {
+                        recipients.extend(group.mailboxes.iter().filter_map(address))
+                    }
+                }
+            }
+        }
+        fields(part, AUTOCRYPT_GOSSIP)
+            .filter(|ac| recipients.contains(&ac.addr.to_lowercase()))
+            .map(|ac| Autocrypt {
+                prefer_encrypt: PreferEncrypt::NoPreference,
+                ..ac
+            })
+            .collect()
+    }
+
+    /// Make an `Autocrypt` field, e.g. for an outgoing message.
+    pub fn field(&self) -> Result<HeaderField<'static>, AutocryptError> {
+        self.make_field(AUTOCRYPT, self.prefer_encrypt)
+    }
+
+    /// Make an `Autocrypt-Gossip` field, which never has `prefer-encrypt`.
+    pub fn gossip_field(&self) -> Result<HeaderField<'static>, AutocryptError> {
+        self.make_field(AUTOCRYPT_GOSSIP, PreferEncrypt::NoPreference)
+    }
+
+    fn make_field(
+        &self,
+        name: &str,
+        prefer_encrypt: PreferEncrypt,
+    ) -> Result<HeaderField<'static>, AutocryptError> {
+        if !is_attribute_value(&self.addr) || self.addr.contains(' ') {
+            return Err(AutocryptError::InvalidAttribute("addr".to_owned()));
+        }
+        for (name, value) in self.non_critical.iter() {
+            if !is_attribute_value(name) || name.contains(' ') || !is_attribute_value(value) {
+                return Err(AutocryptError::InvalidAttribute(name.clone()));
+            }
+        }
+        let mut tokens = vec![(format!("addr={};", self.addr), BETWEEN_ATTRIBUTES, true)];
+        if prefer_encrypt == PreferEncrypt::Mutual {
+            tokens.push((
+                "prefer-encrypt=mutual;".to_owned(),
+                BETWEEN_ATTRIBUTES,
+                true,
+            ));
+        }
+        for (name, value) in self.non_critical.iter() {
+            tokens.push((format!("{}={};", name, value), BETWEEN_ATTRIBUTES, true));
+        }
+        tokens.push(("keydata=".to_owned(), INSIDE_KEYDATA, false));
+        let keydata = base64::encode(&self.keydata);
+        for chunk in keydata.as_bytes().chunks(KEYDATA_CHUNK) {
+            let chunk = String::from_utf8_lossy(chunk).into_owned();
+            tokens.push((chunk, INSIDE_KEYDATA, false));
+        }
+
+        make_field(name, &fold(name.as_bytes(), &tokens)).ok_or(AutocryptError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Autocrypt, AutocryptError, PreferEncrypt};
+    use crate::parse::email::parse_message;
+
+    #[test]
+    fn test_find() {
+        let input = "From: Alice <Alice@Example.com>\r\n\
+                     To: bob@example.net\r\n\
+                     Autocrypt: addr=mallory@example.org; keydata=AAAA\r\n\
+                     Autocrypt: addr=alice@example.com; prefer-encrypt=mutual;\r\n \
+                     _comment=hi; keydata=mQGNBF\r\n \
+                     5tYWls\r\n\
+                     Autocrypt: addr=alice@example.com; color=blue; keydata=AAAA\r\n\
+                     \r\n\
+                     Hello.\r\n";
+        let message = parse_message(input.as_bytes()).unwrap();
+        let autocrypt = Autocrypt::find(&message).unwrap();
+        assert_eq!(autocrypt.addr, "alice@example.com");
+        assert_eq!(autocrypt.prefer_encrypt, PreferEncrypt::Mutual);
+        assert_eq!(autocrypt.keydata, base64::decode("mQGNBF5tYWls").unwrap());
+        assert_eq!(
+            autocrypt.non_critical,
+            vec![("_comment".to_owned(), "hi".to_owned())]
+        );
+        assert_eq!(
+            Autocrypt::parse("addr=alice@example.com; color=blue; keydata=AAAA"),
+            Err(AutocryptError::UnknownAttribute("color".to_owned()))
+        );
+
+        let inner = parse_message(
+            b"Autocrypt-Gossip: addr=bob@example.net; keydata=AAAA\r\n\
+              Autocrypt-Gossip: addr=eve@example.org; keydata=AAAA\r\n\
+              Content-Type: text/plain\r\n\
+              \r\n\
+              Hi.\r\n",
+        )
+        .unwrap();
+        let gossip = Autocrypt::gossip(&inner, &message);
+        assert_eq!(gossip.len(), 1);
+        assert_eq!(gossip[0].addr, "bob@example.net");
+    }
+
+    #[test]
+    fn test_field() {
+        let mut autocrypt = Autocrypt::new("alice@example.com", (0..=255).collect());
+        autocrypt.prefer_encrypt = PreferEncrypt::Mutual;
+        let field = autocrypt.field().unwrap();
+        let value = field.raw_value();
+        assert!(value.starts_with(b" addr=alice@example.com; prefer-encrypt=mutual;\r\n keydata="));
+        let input = [
+            &b"Autocrypt:"[..],
+            value,
+            b"\r\nFrom: alice@example.com\r\n\r\n",
+        ]
+        .concat();
+        for line in String::from_utf8_lossy(&input).split("\r\n") {
+            assert!(line.len() <= 78, "{:?} is too long", line);
+        }
+        let message = parse_message(&input).unwrap();
+        assert_eq!(Autocrypt::find(&message).unwrap(), autocrypt);
+
+        let gossip = autocrypt.gossip_field().unwrap();
+        assert_eq!(gossip.name().0, *b"Autocrypt-Gossip");
+        assert!(!String::from_utf8_lossy(gossip.raw_value()).contains("prefer-encrypt"));
+
+        let autocrypt = Autocrypt::new("j\u{f6}rg@example.com", vec![1, 2, 3]);
+        assert_eq!(
+            autocrypt.field().err(),
+            Some(AutocryptError::InvalidAttribute("addr".to_owned()))
+        );
+        let mut autocrypt = Autocrypt::new("alice@example.com", vec![1, 2, 3]);
+        autocrypt
+            .non_critical
+            .push(("_note".to_owned(), "a; b".to_owned()));
+        assert_eq!(
+            autocrypt.gossip_field().err(),
+            Some(AutocryptError::InvalidAttribute("_note".to_owned()))
+        );
+    }
+}
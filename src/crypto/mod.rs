@@ -0,0 +1,131 @@
+//! Signed and encrypted messages. bmail doesn't do any cryptography itself;
+//! it finds the parts that a verifier or decryptor needs, with the bytes
+//! exactly as they were sent.
+//!
+//! PGP/MIME and S/MIME both use the security multiparts of RFC 1847:
+//! `multipart/signed`, whose first part is the signed content and second
+//! the detached signature, and `multipart/encrypted`, whose first part is
+//! control information and second the encrypted data. The `protocol`
+//! parameter gives the content type of the signature or control part.
+//! Exchanging OpenPGP keys in header fields is covered by `autocrypt`.
+
+pub mod autocrypt;
+pub mod pgp;
+pub mod smime;
+
+use crate::headers::mime::ContentType;
+use crate::report::body_bytes;
+use crate::section::SectionPath;
+use crate::{Body, Message};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    /// There is no part of the expected type and protocol.
+    NotFound,
+    /// A security multipart doesn't have exactly two parts.
+    WrongPartCount(usize),
+    /// A part of a security multipart doesn't have the content type given
+    /// by the `protocol` parameter.
+    ProtocolMismatch,
+    /// The control part of a `multipart/encrypted` has an unsupported version.
+    UnsupportedVersion,
+}
+
+/// Whether `ct` is `type/subtype` (compared case-insensitively).
+pub(crate) fn is_type(ct: Option<&ContentType>, r#type: &str, subtype: &str) -> bool {
+    match ct {
+        Some(ct) => {
+            ct.r#type.0.eq_ignore_ascii_case(r#type.as_bytes())
+                && ct.subtype.0.eq_ignore_ascii_case(subtype.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// Find the first `multipart/<subtype>` part with the given protocol, and
+/// return it with its two parts.
+pub(crate) fn find_security_multipart<'m, 'a>(
+    message: &'m Message<'a>,
+    subtype: &str,
+    protocol: &str,
+) -> Result<(SectionPath, &'m Message<'a>, [&'m Message<'a>; 2]), CryptoError> {
+    let matches = |m: &Message| {
+        is_type(m.content_type(), "multipart", subtype)
+            && matches!(m.content_type().and_then(|ct| ct.parameters.get("protocol")),
+                        Some(p) if p.eq_ignore_ascii_case(protocol))
+    };
+    let (section, multipart) = if matches(message) {
+        (SectionPath::default(), message)
+    } else {
+        message
+            .parts()
+            .find(|part| matches(part.message))
+            .map(|part| (part.section, part.message))
+            .ok_or(CryptoError::NotFound)?
+    };
+    match multipart.body() {
+        Body::Multipart { parts, .. } if parts.len() == 2 => {
+            Ok((section, multipart, [&parts[0], &parts[1]]))
+        }
+        Body::Multipart { parts, .. } => Err(CryptoError::WrongPartCount(parts.len())),
+        _ => Err(CryptoError::NotFound),
+    }
+}
+
+/// Check that `part` has the content type given by a `protocol` parameter.
+pub(crate) fn check_protocol(part: &Message, protocol: &str) -> Result<(), CryptoError> {
+    let (r#type, subtype) = protocol
+        .split_once('/')
+        .ok_or(CryptoError::ProtocolMismatch)?;
+    if is_type(part.content_type(), r#type, subtype) {
+        Ok(())
+    } else {
+        Err(CryptoError::ProtocolMismatch)
+    }
+}
+
+/// A `multipart/signed` part (RFC 1847 section 2.1).
+#[derive(Clone, Debug)]
+pub struct Signed<'m, 'a> {
+    pub section: SectionPath,
+    pub multipart: &'m Message<'a>,
+    /// The signed content, parsed.
+    pub content: &'m Message<'a>,
+    pub signature_part: &'m Message<'a>,
+    /// The hash algorithm used, e.g. `pgp-sha256` or `sha-256`, lowercased.
+    pub micalg: Option<String>,
+}
+
+impl<'m, 'a> Signed<'m, 'a> {
+    /// Find the first `multipart/signed` part with the given protocol.
+    pub(crate) fn find(message: &'m Message<'a>, protocol: &str) -> Result<Self, CryptoError> {
+        let (section, multipart, [content, signature_part]) =
+            find_security_multipart(message, "signed", protocol)?;
+        check_protocol(signature_part, protocol)?;
+        let micalg = multipart
+            .content_type()
+            .and_then(|ct| ct.parameters.get("micalg"))
+            .map(|micalg| micalg.to_ascii_lowercase());
+        Ok(Signed {
+            section,
+            multipart,
+            content,
+            signature_part,
+            micalg,
+        })
+    }
+
+    /// The bytes the signature covers: the whole first part, header
+    /// included, exactly as it was sent. Since the parser only accepts CRLF
+    /// line endings, this is already in canonical form. It comes from
+    /// `Message::raw`, so it is empty if the message wasn't parsed, and
+    /// doesn't reflect any edits to the part.
+    pub fn signed_data(&self) -> &'m [u8] {
+        self.content.raw()
+    }
+
+    /// The detached signature, with any Content-Transfer-Encoding decoded.
+    pub fn signature(&self) -> &'m [u8] {
+        body_bytes(self.signature_part)
+    }
+}
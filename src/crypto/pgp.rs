@@ -0,0 +1,149 @@
+//! PGP/MIME (RFC 3156): OpenPGP signatures and encryption in security
+//! multiparts.
+
+use super::{check_protocol, find_security_multipart, CryptoError, Signed};
+use crate::report::body_bytes;
+use crate::section::SectionPath;
+use crate::Message;
+
+pub const SIGNATURE_PROTOCOL: &str = "application/pgp-signature";
+pub const ENCRYPTED_PROTOCOL: &str = "application/pgp-encrypted";
+
+/// Find the first `multipart/signed` part with an OpenPGP signature. The
+/// signature is ASCII-armored, i.e. starts with `-----BEGIN PGP SIGNATURE-----`.
+pub fn find_signed<'m, 'a>(message: &'m Message<'a>) -> Result<Signed<'m, 'a>, CryptoError> {
+    Signed::find(message, SIGNATURE_PROTOCOL)
+}
+
+/// A `multipart/encrypted` part holding an OpenPGP message.
+#[derive(Clone, Debug)]
+pub struct Encrypted<'m, 'a> {
+    pub section: SectionPath,
+    pub multipart: &'m Message<'a>,
+    /// The `application/pgp-encrypted` part, which only holds `Version: 1`.
+    pub control_part: &'m Message<'a>,
+    pub payload_part: &'m Message<'a>,
+}
+
+impl<'m, 'a> Encrypted<'m, 'a> {
+    /// Find the first `multipart/encrypted` part with an OpenPGP message.
+    pub fn find(message: &'m Message<'a>) -> Result<Self, CryptoError> {
+        let (section, multipart, [control_part, payload_part]) =
+            find_security_multipart(message, "encrypted", ENCRYPTED_PROTOCOL)?;
+        check_protocol(control_part, ENCRYPTED_PROTOCOL)?;
+        let control = String::from_utf8_lossy(body_bytes(control_part));
+        let version = control.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("Version") {
+                Some(value.trim())
+            } else {
+                None
+            }
+        });
+        if version != Some("1") {
+            return Err(CryptoError::UnsupportedVersion);
+        }
+        Ok(Encrypted {
+            section,
+            multipart,
+            control_part,
+            payload_part,
+        })
+    }
+
+    /// The encrypted OpenPGP message, normally ASCII-armored, with any
+    /// Content-Transfer-Encoding decoded. Decrypting it gives a MIME entity
+    /// (which may itself be signed) that can be parsed with `parse_message`.
+    pub fn payload(&self) -> &'m [u8] {
+        body_bytes(self.payload_part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_signed, Encrypted};
+    use crate::crypto::CryptoError;
+    use crate::parse::email::parse_message;
+    use crate::section::SectionPath;
+
+    const SIGNED_CONTENT: &str = "Content-Type: multipart/mixed; boundary=inner\r\n\
+                                  \r\n\
+                                  --inner\r\n\
+                                  Content-Type: text/plain; charset=utf-8\r\n\
+                                  Content-Transfer-Encoding: quoted-printable\r\n\
+                                  \r\n\
+                                  Caf=C3=A9 at 10? \r\n\
+                                  --inner--\r\n";
+
+    #[test]
+    fn test_signed() {
+        let input = format!(
+            "From: Alice <alice@example.com>\r\n\
+             Content-Type: multipart/mixed; boundary=outer\r\n\
+             \r\n\
+             --outer\r\n\
+             Content-Type: multipart/signed; micalg=PGP-SHA256;\r\n \
+             protocol=\"application/pgp-signature\"; boundary=signed\r\n\
+             \r\n\
+             --signed\r\n\
+             {}\r\n\
+             --signed\r\n\
+             Content-Type: application/pgp-signature; name=signature.asc\r\n\
+             \r\n\
+             -----BEGIN PGP SIGNATURE-----\r\n\
+             \r\n\
+             iQEzBAEBCAAdFiEE\r\n\
+             -----END PGP SIGNATURE-----\r\n\
+             \r\n\
+             --signed--\r\n\
+             --outer--\r\n",
+            SIGNED_CONTENT
+        );
+        let message = parse_message(input.as_bytes()).unwrap();
+        let signed = find_signed(&message).unwrap();
+        assert_eq!(signed.section, SectionPath(vec![1]));
+        assert_eq!(signed.micalg.as_deref(), Some("pgp-sha256"));
+        // The trailing space and the quoted-printable encoding must survive.
+        assert_eq!(signed.signed_data(), SIGNED_CONTENT.as_bytes());
+        assert!(signed
+            .signature()
+            .starts_with(b"-----BEGIN PGP SIGNATURE-----\r\n"));
+
+        let message = parse_message(b"Subject: Hi\r\n\r\nHello.\r\n").unwrap();
+        assert_eq!(find_signed(&message).unwrap_err(), CryptoError::NotFound);
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let input = "From: Alice <alice@example.com>\r\n\
+                     Content-Type: multipart/encrypted; boundary=b;\r\n \
+                     protocol=\"application/pgp-encrypted\"\r\n\
+                     \r\n\
+                     --b\r\n\
+                     Content-Type: application/pgp-encrypted\r\n\
+                     \r\n\
+                     Version: 1\r\n\
+                     --b\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     \r\n\
+                     -----BEGIN PGP MESSAGE-----\r\n\
+                     \r\n\
+                     hQEMA1m8\r\n\
+                     -----END PGP MESSAGE-----\r\n\
+                     --b--\r\n";
+        let message = parse_message(input.as_bytes()).unwrap();
+        let encrypted = Encrypted::find(&message).unwrap();
+        assert_eq!(encrypted.section, SectionPath::default());
+        assert_eq!(
+            encrypted.payload(),
+            b"-----BEGIN PGP MESSAGE-----\r\n\r\nhQEMA1m8\r\n-----END PGP MESSAGE-----"
+        );
+
+        let input = input.replace("Version: 1", "Version: 2");
+        let message = parse_message(input.as_bytes()).unwrap();
+        assert_eq!(
+            Encrypted::find(&message).unwrap_err(),
+            CryptoError::UnsupportedVersion
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! S/MIME (RFC 8551): CMS objects in `application/pkcs7-mime` parts, and
+//! detached signatures in security multiparts.
+//!
+//! The cryptography is left to a `Handler`, which `open` uses to unwrap the
+//! layers of signing, encryption and compression around the innermost entity.
+
+use std::fmt;
+
+use super::{is_type, CryptoError, Signed};
+use crate::parse::email::parse_message;
+use crate::report::body_bytes;
+use crate::section::SectionPath;
+use crate::Message;
+
+pub const SIGNATURE_PROTOCOL: &str = "application/pkcs7-signature";
+/// The protocol used by older clients, which receivers should also accept.
+pub const LEGACY_SIGNATURE_PROTOCOL: &str = "application/x-pkcs7-signature";
+
+/// Find the first `multipart/signed` part with a CMS signature. The
+/// signature is a DER-encoded `SignedData` without the content.
+pub fn find_signed<'m, 'a>(message: &'m Message<'a>) -> Result<Signed<'m, 'a>, CryptoError> {
+    match Signed::find(message, SIGNATURE_PROTOCOL) {
+        Err(CryptoError::NotFound) => Signed::find(message, LEGACY_SIGNATURE_PROTOCOL),
+        result => result,
+    }
+}
+
+/// The `smime-type` parameter of an `application/pkcs7-mime` part.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SmimeType {
+    EnvelopedData,
+    AuthEnvelopedData,
+    SignedData,
+    /// Only certificates, e.g. to send them to a correspondent.
+    CertsOnly,
+    CompressedData,
+    Other(String),
+}
+
+impl SmimeType {
+    pub fn parse(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "enveloped-data" => SmimeType::EnvelopedData,
+            "authenveloped-data" => SmimeType::AuthEnvelopedData,
+            "signed-data" => SmimeType::SignedData,
+            "certs-only" => SmimeType::CertsOnly,
+            "compressed-data" => SmimeType::CompressedData,
+            other => SmimeType::Other(other.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for SmimeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            SmimeType::EnvelopedData => "enveloped-data",
+            SmimeType::AuthEnvelopedData => "authEnveloped-data",
+            SmimeType::SignedData => "signed-data",
+            SmimeType::CertsOnly => "certs-only",
+            SmimeType::CompressedData => "compressed-data",
+            SmimeType::Other(s) => s,
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn is_pkcs7_mime(message: &Message) -> bool {
+    is_type(message.content_type(), "application", "pkcs7-mime")
+        || is_type(message.content_type(), "application", "x-pkcs7-mime")
+}
+
+/// An `application/pkcs7-mime` part.
+#[derive(Clone, Debug)]
+pub struct Pkcs7Mime<'m, 'a> {
+    pub section: SectionPath,
+    pub part: &'m Message<'a>,
+    /// `None` if the part has no `smime-type` parameter, as with some
+    /// older clients.
+    pub smime_type: Option<SmimeType>,
+}
+
+impl<'m, 'a> Pkcs7Mime<'m, 'a> {
+    /// Find the first `application/pkcs7-mime` part.
+    pub fn find(message: &'m Message<'a>) -> Result<Self, CryptoError> {
+        let (section, part) = if is_pkcs7_mime(message) {
+            (SectionPath::default(), message)
+        } else {
+            message
+                .parts()
+                .find(|part| is_pkcs7_mime(part.message))
+                .map(|part| (part.section, part.message))
+                .ok_or(CryptoError::NotFound)?
+        };
+        let smime_type = part
+            .content_type()
+            .and_then(|ct| ct.parameters.get("smime-type"))
+            .map(|smime_type| SmimeType::parse(smime_type));
+        Ok(Pkcs7Mime {
+            section,
+            part,
+            smime_type,
+        })
+    }
+
+    /// The DER-encoded CMS object, with the Content-Transfer-Encoding decoded.
+    pub fn data(&self) -> &'m [u8] {
+        body_bytes(self.part)
+    }
+}
+
+/// The cryptography needed to open S/MIME messages, e.g. a binding to
+/// OpenSSL holding the user's private key and trusted certificates.
+pub trait Handler {
+    type Error;
+
+    /// Check a detached signature over `signed_data`.
+    fn verify_detached(&self, signed_data: &[u8], signature: &[u8]) -> Result<(), Self::Error>;
+
+    /// Check a `SignedData` object that contains its content, and
+    /// return the content.
+    fn verify(&self, signed_data: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decrypt an `EnvelopedData` or `AuthEnvelopedData` object.
+    fn decrypt(&self, enveloped_data: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decompress a `CompressedData` object (RFC 3274).
+    fn decompress(&self, compressed_data: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpenError<E> {
+    Handler(E),
+    /// The multipart/signed structure is broken.
+    Structure(CryptoError),
+    /// A verified, decrypted or decompressed entity isn't a MIME entity with
+    /// CRLF line endings.
+    MalformedContent,
+}
+
+/// A layer of protection that `open` removed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Signed,
+    Encrypted,
+    Compressed,
+}
+
+/// The result of `open`.
+#[derive(Clone, Debug)]
+pub struct Opened {
+    /// The layers that were removed, outermost first.
+    pub layers: Vec<Layer>,
+    /// The innermost entity, which is the original message if it wasn't
+    /// signed, encrypted or compressed.
+    pub message: Message<'static>,
+}
+
+/// The layer that was removed and the content inside it, or `None` if there
+/// was no layer to remove.
+type Unwrapped<E> = Result<Option<(Layer, Vec<u8>)>, OpenError<E>>;
+
+/// Remove one layer from `entity`.
+fn unwrap_layer<H: Handler>(entity: &Message, handler: &H) -> Unwrapped<H::Error> {
+    if is_pkcs7_mime(entity) {
+        let pkcs7 = Pkcs7Mime::find(entity).map_err(OpenError::Structure)?;
+        return match pkcs7.smime_type {
+            Some(SmimeType::EnvelopedData) | Some(SmimeType::AuthEnvelopedData) | None => {
+                let content = handler.decrypt(pkcs7.data()).map_err(OpenError::Handler)?;
+                Ok(Some((Layer::Encrypted, content)))
+            }
+            Some(SmimeType::SignedData) => {
+                let content = handler.verify(pkcs7.data()).map_err(OpenError::Handler)?;
+                Ok(Some((Layer::Signed, content)))
+            }
+            Some(SmimeType::CompressedData) => {
+                let content = handler
+                    .decompress(pkcs7.data())
+                    .map_err(OpenError::Handler)?;
+                Ok(Some((Layer::Compressed, content)))
+            }
+            // Certificates only, or a type we don't know: there's no entity
+            // to unwrap.
+            Some(_) => Ok(None),
+        };
+    }
+    if !is_type(entity.content_type(), "multipart", "signed") {
+        return Ok(None);
+    }
+    let signed = match find_signed(entity) {
+        Ok(signed) if signed.section.depth() == 0 => signed,
+        // A multipart/signed of some other protocol, e.g. PGP/MIME.
+        Ok(_) | Err(CryptoError::NotFound) => return Ok(None),
+        Err(e) => return Err(OpenError::Structure(e)),
+    };
+    handler
+        .verify_detached(signed.signed_data(), signed.signature())
+        .map_err(OpenError::Handler)?;
+    Ok(Some((Layer::Signed, signed.signed_data().to_vec())))
+}
+
+/// Verify, decrypt and decompress `message` with `handler` until the innermost
+/// entity is reached, e.g. for a message that was signed and then encrypted.
+pub fn open<H: Handler>(message: &Message, handler: &H) -> Result<Opened, OpenError<H::Error>> {
+    let mut layers = vec![];
+    let mut inner: Option<Message<'static>> = None;
+    while let Some((layer, content)) = unwrap_layer(inner.as_ref().unwrap_or(message), handler)? {
+        let parsed = parse_message(&content).map_err(|_| OpenError::MalformedContent)?;
+        layers.push(layer);
+        inner = Some(parsed.into_owned());
+    }
+    Ok(Opened {
+        layers,
+        message: inner.unwrap_or_else(|| message.clone().into_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_signed, open, Handler, Layer, OpenError, Pkcs7Mime, SmimeType};
+    use crate::parse::email::parse_message;
+    use crate::Body;
+
+    const INNER: &str = "Content-Type: text/plain\r\n\
+                         \r\n\
+                         The launch code is 1234.\r\n";
+    const SIGNATURE: &[u8] = b"\x30\x82\x01\x00signature";
+    const ENVELOPED: &[u8] = b"\x30\x80enveloped";
+    const COMPRESSED: &[u8] = b"\x30\x80compressed";
+
+    fn signed_entity() -> String {
+        format!(
+            "Content-Type: multipart/signed; protocol=\"application/x-pkcs7-signature\";\r\n \
+             micalg=sha-256; boundary=s\r\n\
+             \r\n\
+             --s\r\n\
+             {}\r\n\
+             --s\r\n\
+             Content-Type: application/x-pkcs7-signature; name=smime.p7s\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {}\r\n\
+             --s--\r\n",
+            INNER,
+            base64::encode(SIGNATURE)
+        )
+    }
+
+    /// Pretends that `ENVELOPED` decrypts to a signed entity, that
+    /// `SIGNATURE` is a valid signature of `INNER`, and that `COMPRESSED`
+    /// decompresses to `INNER`.
+    struct FakeHandler;
+
+    impl Handler for FakeHandler {
+        type Error = &'static str;
+
+        fn verify_detached(&self, signed_data: &[u8], signature: &[u8]) -> Result<(), Self::Error> {
+            if signed_data == INNER.as_bytes() && signature == SIGNATURE {
+                Ok(())
+            } else {
+                Err("bad signature")
+            }
+        }
+
+        fn verify(&self, _: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Err("unexpected signed-data")
+        }
+
+        fn decrypt(&self, enveloped_data: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            if enveloped_data == ENVELOPED {
+                Ok(signed_entity().into_bytes())
+            } else {
+                Err("can't decrypt")
+            }
+        }
+
+        fn decompress(&self, compressed_data: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            if compressed_data == COMPRESSED {
+                Ok(INNER.as_bytes().to_vec())
+            } else {
+                Err("can't decompress")
+            }
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let input = format!("From: alice@example.com\r\n{}", signed_entity());
+        let message = parse_message(input.as_bytes()).unwrap();
+        let signed = find_signed(&message).unwrap();
+        assert_eq!(signed.signed_data(), INNER.as_bytes());
+        assert_eq!(signed.signature(), SIGNATURE);
+        assert_eq!(signed.micalg.as_deref(), Some("sha-256"));
+
+        let input = "Content-Type: application/pkcs7-mime; smime-type=certs-only\r\n\
+                     Content-Transfer-Encoding: base64\r\n\
+                     \r\n\
+                     MIIB\r\n";
+        let message = parse_message(input.as_bytes()).unwrap();
+        let pkcs7 = Pkcs7Mime::find(&message).unwrap();
+        assert_eq!(pkcs7.smime_type, Some(SmimeType::CertsOnly));
+        assert_eq!(pkcs7.data(), b"\x30\x82\x01");
+    }
+
+    #[test]
+    fn test_open() {
+        let input = format!(
+            "From: alice@example.com\r\n\
+             Content-Type: application/pkcs7-mime; smime-type=enveloped-data;\r\n \
+             name=smime.p7m\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {}\r\n",
+            base64::encode(ENVELOPED)
+        );
+        let message = parse_message(input.as_bytes()).unwrap();
+        let opened = open(&message, &FakeHandler).unwrap();
+        assert_eq!(opened.layers, vec![Layer::Encrypted, Layer::Signed]);
+        match opened.message.body() {
+            Body::SimpleText(text) => assert_eq!(text, "The launch code is 1234.\r\n"),
+            _ => panic!("expected text body"),
+        }
+
+        let tampered = input.replace(&base64::encode(ENVELOPED), &base64::encode(b"other"));
+        let message = parse_message(tampered.as_bytes()).unwrap();
+        assert_eq!(
+            open(&message, &FakeHandler).unwrap_err(),
+            OpenError::Handler("can't decrypt")
+        );
+
+        let input = format!(
+            "Content-Type: application/pkcs7-mime; smime-type=compressed-data\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {}\r\n",
+            base64::encode(COMPRESSED)
+        );
+        let message = parse_message(input.as_bytes()).unwrap();
+        let opened = open(&message, &FakeHandler).unwrap();
+        assert_eq!(opened.layers, vec![Layer::Compressed]);
+        match opened.message.body() {
+            Body::SimpleText(text) => assert_eq!(text, "The launch code is 1234.\r\n"),
+            _ => panic!("expected text body"),
+        }
+    }
+}
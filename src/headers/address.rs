@@ -3,30 +3,84 @@ use std::borrow::Cow;
 use crate::{ByteStr, ByteString};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Domain<'a> {
-    Name(&'a ByteStr),
+    Name(Cow<'a, ByteStr>),
     Literal(ByteString),
 }
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddrSpec<'a> {
     pub local_part: Cow<'a, ByteStr>,
     pub domain: Domain<'a>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mailbox<'a> {
     pub display_name: Vec<ByteString>,
     pub addr_spec: Option<AddrSpec<'a>>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Group<'a> {
     pub display_name: Vec<ByteString>,
     pub mailboxes: Vec<Mailbox<'a>>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Address<'a> {
     Mailbox(Mailbox<'a>),
     Group(Group<'a>),
 }
+
+impl<'a> Domain<'a> {
+    pub fn into_owned(self) -> Domain<'static> {
+        match self {
+            Domain::Name(name) => Domain::Name(Cow::Owned(name.into_owned())),
+            Domain::Literal(literal) => Domain::Literal(literal),
+        }
+    }
+}
+
+impl<'a> AddrSpec<'a> {
+    pub fn into_owned(self) -> AddrSpec<'static> {
+        AddrSpec {
+            local_part: Cow::Owned(self.local_part.into_owned()),
+            domain: self.domain.into_owned(),
+        }
+    }
+}
+
+impl<'a> Mailbox<'a> {
+    pub fn into_owned(self) -> Mailbox<'static> {
+        Mailbox {
+            display_name: self.display_name,
+            addr_spec: self.addr_spec.map(AddrSpec::into_owned),
+        }
+    }
+}
+
+impl<'a> Group<'a> {
+    pub fn into_owned(self) -> Group<'static> {
+        Group {
+            display_name: self.display_name,
+            mailboxes: self
+                .mailboxes
+                .into_iter()
+                .map(Mailbox::into_owned)
+                .collect(),
+        }
+    }
+}
+
+impl<'a> Address<'a> {
+    pub fn into_owned(self) -> Address<'static> {
+        match self {
+            Address::Mailbox(mbox) => Address::Mailbox(mbox.into_owned()),
+            Address::Group(group) => Address::Group(group.into_owned()),
+        }
+    }
+}
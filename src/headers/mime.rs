@@ -6,13 +6,41 @@ use crate::ByteStr;
 use quoted_printable::ParseMode;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ContentType<'a> {
-    pub r#type: &'a ByteStr,
-    pub subtype: &'a ByteStr,
+    pub r#type: Cow<'a, ByteStr>,
+    pub subtype: Cow<'a, ByteStr>,
     pub parameters: HashMap<String, String>, // TODO [perf] - could avoid copies for the (typical) lowercase-only case.
 }
 
+impl<'a> ContentType<'a> {
+    pub fn into_owned(self) -> ContentType<'static> {
+        ContentType {
+            r#type: Cow::Owned(self.r#type.into_owned()),
+            subtype: Cow::Owned(self.subtype.into_owned()),
+            parameters: self.parameters,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContentDisposition<'a> {
+    pub disposition: Cow<'a, ByteStr>, // e.g. "inline" or "attachment"
+    pub parameters: HashMap<String, String>,
+}
+
+impl<'a> ContentDisposition<'a> {
+    pub fn into_owned(self) -> ContentDisposition<'static> {
+        ContentDisposition {
+            disposition: Cow::Owned(self.disposition.into_owned()),
+            parameters: self.parameters,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ContentTransferEncoding {
     SevenBit,
     EightBit,
@@ -37,6 +65,26 @@ impl ContentTransferEncoding {
                 .map_err(ContentDecodeError::QuotedPrintable),
         }
     }
+    /// The inverse of `decode`. Lines are wrapped to 76 characters where
+    /// the encoding requires it.
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        use ContentTransferEncoding::*;
+        match self {
+            SevenBit | EightBit | Binary => input.to_vec(),
+            Base64 => {
+                let encoded = base64::encode(input);
+                let mut out = Vec::with_capacity(encoded.len() + encoded.len() / 76 * 2);
+                for (i, line) in encoded.as_bytes().chunks(76).enumerate() {
+                    if i != 0 {
+                        out.extend_from_slice(b"\r\n");
+                    }
+                    out.extend_from_slice(line);
+                }
+                out
+            }
+            QuotedPrintable => quoted_printable::encode(input),
+        }
+    }
     pub fn is_trivial(&self) -> bool {
         use ContentTransferEncoding::*;
         match self {
@@ -4,15 +4,18 @@ use std::borrow::Cow;
 pub mod address;
 pub mod layout;
 pub mod mime;
+pub(crate) mod render;
 
 use crate::headers::mime::ContentTransferEncoding;
+use crate::parse::header::is_ftext;
 use crate::parse::is_wsp;
 use crate::{ByteStr, ByteString};
 use address::{Address, Mailbox};
-use mime::ContentType;
+use mime::{ContentDisposition, ContentType};
 
 #[derive(Debug, Clone, EnumKind)]
 #[enum_kind(HeaderFieldKind)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HeaderFieldInner<'a> {
     Unstructured(ByteString),
     // "Date:"
@@ -25,12 +28,48 @@ pub enum HeaderFieldInner<'a> {
     Bcc(Vec<Address<'a>>),
     ContentType(ContentType<'a>),
     ContentTransferEncoding(ContentTransferEncoding),
+    ContentDisposition(ContentDisposition<'a>),
+}
+
+impl<'a> HeaderFieldInner<'a> {
+    pub fn into_owned(self) -> HeaderFieldInner<'static> {
+        use HeaderFieldInner::*;
+        fn addresses(list: Vec<Address>) -> Vec<Address<'static>> {
+            list.into_iter().map(Address::into_owned).collect()
+        }
+        match self {
+            Unstructured(s) => Unstructured(s),
+            OrigDate(dt) => OrigDate(dt),
+            From(list) => From(list.into_iter().map(Mailbox::into_owned).collect()),
+            Sender(mbox) => Sender(mbox.into_owned()),
+            ReplyTo(list) => ReplyTo(addresses(list)),
+            To(list) => To(addresses(list)),
+            Cc(list) => Cc(addresses(list)),
+            Bcc(list) => Bcc(addresses(list)),
+            ContentType(ct) => ContentType(ct.into_owned()),
+            ContentTransferEncoding(cte) => ContentTransferEncoding(cte),
+            ContentDisposition(cd) => ContentDisposition(cd.into_owned()),
+        }
+    }
+}
+
+/// Why a field couldn't be built from a typed value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldError {
+    /// The name is empty, or has a character that isn't allowed in one,
+    /// such as a colon, a space or a control character.
+    InvalidName,
+    /// The value can't be written as a valid field, e.g. because it
+    /// has a CR or LF in it.
+    InvalidValue,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HeaderField<'a> {
-    name: &'a ByteStr,
-    raw_value: &'a [u8],
+    name: Cow<'a, ByteStr>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::bytes"))]
+    raw_value: Cow<'a, [u8]>,
     inner: HeaderFieldInner<'a>,
     unfolded_value: Cow<'a, ByteStr>,
 }
@@ -39,22 +78,69 @@ impl<'a> HeaderField<'a> {
     pub fn new(name: &'a ByteStr, raw_value: &'a [u8], inner: HeaderFieldInner<'a>) -> Self {
         let unfolded_value = Self::compute_unfolded_value(raw_value);
         Self {
-            name,
-            raw_value,
+            name: Cow::Borrowed(name),
+            raw_value: Cow::Borrowed(raw_value),
             inner,
             unfolded_value,
         }
     }
     pub fn name(&self) -> &ByteStr {
-        self.name
+        &self.name
     }
     pub fn raw_value(&self) -> &[u8] {
-        self.raw_value
+        &self.raw_value
     }
     pub fn inner(&self) -> &HeaderFieldInner<'a> {
         &self.inner
     }
-    fn compute_unfolded_value(rv: &'a [u8]) -> Cow<'a, ByteStr> {
+    /// Append the field, including its terminating CRLF, to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.name.0);
+        out.push(b':');
+        out.extend_from_slice(&self.raw_value);
+        out.extend_from_slice(b"\r\n");
+    }
+    /// Build a field from a typed value, rendering the raw value from it.
+    /// Fails if `name` isn't a valid field name, or if the rendered field
+    /// wouldn't parse back, e.g. because an unstructured value has a line
+    /// break in it.
+    pub fn from_inner(name: &str, inner: HeaderFieldInner<'a>) -> Result<Self, FieldError> {
+        if name.is_empty() || !name.bytes().all(is_ftext) {
+            return Err(FieldError::InvalidName);
+        }
+        let raw_value = Self::render(name.as_bytes(), &inner)?;
+        let unfolded_value = Cow::Owned(Self::compute_unfolded_value(&raw_value).into_owned());
+        Ok(Self {
+            name: Cow::Owned(ByteString(name.as_bytes().to_vec())),
+            raw_value: Cow::Owned(raw_value),
+            inner,
+            unfolded_value,
+        })
+    }
+    /// Replace the value of this field, re-rendering its raw value.
+    /// On failure, the field is left unchanged.
+    pub fn set_inner(&mut self, inner: HeaderFieldInner<'a>) -> Result<(), FieldError> {
+        let raw_value = Self::render(&self.name.0, &inner)?;
+        self.unfolded_value = Cow::Owned(Self::compute_unfolded_value(&raw_value).into_owned());
+        self.raw_value = Cow::Owned(raw_value);
+        self.inner = inner;
+        Ok(())
+    }
+    fn render(name: &[u8], inner: &HeaderFieldInner) -> Result<Vec<u8>, FieldError> {
+        // Line breaks are the renderer's to add; any others would end the field.
+        if let HeaderFieldInner::Unstructured(text) = inner {
+            if text.0.iter().any(|ch| *ch == b'\r' || *ch == b'\n') {
+                return Err(FieldError::InvalidValue);
+            }
+        }
+        let raw_value = render::render_value(name, inner);
+        let name = std::str::from_utf8(name).map_err(|_| FieldError::InvalidName)?;
+        match render::make_field(name, &raw_value) {
+            Some(_) => Ok(raw_value),
+            None => Err(FieldError::InvalidValue),
+        }
+    }
+    fn compute_unfolded_value(rv: &[u8]) -> Cow<'_, ByteStr> {
         // unfolding - remove any \r\n that is immediately
         // followed by WSP
         let mut breaks = rv.windows(3).filter_map(|win| {
@@ -81,4 +167,14 @@ impl<'a> HeaderField<'a> {
     pub fn unfolded_value(&self) -> &ByteStr {
         &*self.unfolded_value
     }
+    /// Copy any data borrowed from the input buffer, so that
+    /// the field can outlive it.
+    pub fn into_owned(self) -> HeaderField<'static> {
+        HeaderField {
+            name: Cow::Owned(self.name.into_owned()),
+            raw_value: Cow::Owned(self.raw_value.into_owned()),
+            inner: self.inner.into_owned(),
+            unfolded_value: Cow::Owned(self.unfolded_value.into_owned()),
+        }
+    }
 }
@@ -0,0 +1,515 @@
+//! Rendering of typed header field values back to text,
+//! folded with `HeaderFieldFormatter`.
+
+use std::collections::HashMap;
+
+use nom::combinator::all_consuming;
+
+use super::address::{AddrSpec, Address, Domain, Mailbox};
+use super::layout::HeaderFieldFormatter;
+use super::HeaderFieldInner;
+use crate::parse::header::header_field;
+use crate::parse::mime::is_token_ch;
+use crate::parse::{is_atext, is_wsp};
+use crate::{ByteString, HeaderField};
+
+pub(crate) const MAX_WIDTH: usize = 78;
+
+// The most text in one encoded word: 60 characters of base64, which with
+// the `=?utf-8?B?` and `?=` around them is within the limit of 75.
+const MAX_ENCODED_CHUNK: usize = 45;
+
+// Break priorities; folding prefers the highest-numbered ones.
+const INSIDE_ITEM: usize = 0;
+const BETWEEN_WORDS: usize = 1;
+const BETWEEN_ITEMS: usize = 2;
+
+struct Token {
+    text: Vec<u8>,
+    priority: usize,
+    space: bool,
+}
+
+#[derive(Default)]
+struct Tokens(Vec<Token>);
+
+impl Tokens {
+    fn push(&mut self, text: Vec<u8>) {
+        self.0.push(Token {
+            text,
+            priority: BETWEEN_WORDS,
+            space: true,
+        });
+    }
+
+    // Glue `text` (e.g. a separator) onto the end of the last token,
+    // and set the properties of the break that follows it.
+    fn end_item(&mut self, text: &[u8], priority: usize, space: bool) {
+        if let Some(last) = self.0.last_mut() {
+            last.text.extend_from_slice(text);
+            last.priority = priority;
+            last.space = space;
+        }
+    }
+}
+
+/// Render `inner` as the value of a field called `name`: everything after the colon,
+/// not including the final CRLF. Lines are folded to fit in `MAX_WIDTH` columns
+/// when possible.
+pub(crate) fn render_value(name: &[u8], inner: &HeaderFieldInner) -> Vec<u8> {
+    let mut tokens = tokens(inner);
+    tokens.end_item(b"", INSIDE_ITEM, false);
+    let tokens: Vec<_> = tokens
+        .0
+        .into_iter()
+        .map(|t| (t.text, t.priority, t.space))
+        .collect();
+    fold(name, &tokens)
+}
+
+/// Fold `tokens` into the value of a field called `name`, not including the
+/// final CRLF. Each token has the priority of the break after it (folding
+/// prefers higher ones), and whether that break is a space.
+pub(crate) fn fold<T: AsRef<[u8]>>(name: &[u8], tokens: &[(T, usize, bool)]) -> Vec<u8> {
+    let distinct_priorities = tokens.iter().map(|(_, priority, _)| priority + 1).max();
+    let distinct_priorities = distinct_priorities.unwrap_or(1);
+    let prefix = [name, b":"].concat();
+    let mut hff = HeaderFieldFormatter::new(
+        MAX_WIDTH,
+        distinct_priorities,
+        &prefix,
+        distinct_priorities - 1,
+        true,
+    );
+    let fits = tokens
+        .iter()
+        .all(|(text, priority, space)| hff.push(text.as_ref(), *priority, *space).is_ok());
+    let mut folded = vec![];
+    if fits {
+        hff.done(&mut folded);
+    } else {
+        // Some token (e.g., a very long domain) is too long for a line.
+        folded.extend_from_slice(&prefix);
+        folded.push(b' ');
+        for (i, (text, _, space)) in tokens.iter().enumerate() {
+            folded.extend_from_slice(text.as_ref());
+            if *space && i + 1 < tokens.len() {
+                folded.push(b' ');
+            }
+        }
+        folded.extend_from_slice(b"\r\n");
+    }
+    folded[prefix.len()..folded.len() - 2].to_vec()
+}
+
+/// Parse a field made by folding, so that it keeps its exact raw value.
+/// Returns `None` if the value isn't valid in a field, e.g. because it
+/// isn't ASCII.
+pub(crate) fn make_field(name: &str, raw_value: &[u8]) -> Option<HeaderField<'static>> {
+    let mut field = name.as_bytes().to_vec();
+    field.push(b':');
+    field.extend_from_slice(raw_value);
+    field.extend_from_slice(b"\r\n");
+    let (_, field) = all_consuming(header_field)(&field).ok()?;
+    Some(field.into_owned())
+}
+
+fn tokens(inner: &HeaderFieldInner) -> Tokens {
+    use HeaderFieldInner::*;
+    let mut tokens = Tokens::default();
+    match inner {
+        Unstructured(ByteString(text)) => unstructured(text, &mut tokens),
+        OrigDate(date_time) => {
+            for word in date_time.to_rfc2822().split(' ') {
+                tokens.push(word.as_bytes().to_vec());
+            }
+        }
+        From(mailboxes) => {
+            for (i, mb) in mailboxes.iter().enumerate() {
+                if i != 0 {
+                    tokens.end_item(b",", BETWEEN_ITEMS, true);
+                }
+                mailbox(mb, &mut tokens);
+            }
+        }
+        Sender(mb) => mailbox(mb, &mut tokens),
+        ReplyTo(addresses) | To(addresses) | Cc(addresses) | Bcc(addresses) => {
+            for (i, address) in addresses.iter().enumerate() {
+                if i != 0 {
+                    tokens.end_item(b",", BETWEEN_ITEMS, true);
+                }
+                match address {
+                    Address::Mailbox(mb) => mailbox(mb, &mut tokens),
+                    Address::Group(group) => {
+                        phrase(&group.display_name, &mut tokens);
+                        tokens.end_item(b":", BETWEEN_ITEMS, true);
+                        for (i, mb) in group.mailboxes.iter().enumerate() {
+                            if i != 0 {
+                                tokens.end_item(b",", BETWEEN_ITEMS, true);
+                            }
+                            mailbox(mb, &mut tokens);
+                        }
+                        tokens.end_item(b";", BETWEEN_ITEMS, true);
+                    }
+                }
+            }
+        }
+        ContentType(ct) => {
+            tokens.push([&ct.r#type.0, &b"/"[..], &ct.subtype.0].concat());
+            parameters(&ct.parameters, &mut tokens);
+        }
+        ContentTransferEncoding(cte) => {
+            use super::mime::ContentTransferEncoding::*;
+            let cte: &[u8] = match cte {
+                SevenBit => b"7bit",
+                EightBit => b"8bit",
+                Binary => b"binary",
+                Base64 => b"base64",
+                QuotedPrintable => b"quoted-printable",
+            };
+            tokens.push(cte.to_vec());
+        }
+        ContentDisposition(cd) => {
+            tokens.push(cd.disposition.0.to_vec());
+            parameters(&cd.parameters, &mut tokens);
+        }
+    }
+    tokens
+}
+
+fn quote_if_needed(s: &[u8], is_plain: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    if is_plain(s) {
+        return s.to_vec();
+    }
+    let mut quoted = Vec::with_capacity(s.len() + 2);
+    quoted.push(b'"');
+    for ch in s.iter().copied() {
+        if ch == b'"' || ch == b'\\' {
+            quoted.push(b'\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+fn is_atom(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().copied().all(is_atext)
+}
+
+fn is_dot_atom(s: &[u8]) -> bool {
+    s.split(|ch| *ch == b'.').all(is_atom)
+}
+
+/// The encoded words (RFC 2047) for `text`, each short enough to fit on a
+/// line. Decoders ignore the whitespace between adjacent encoded words, so
+/// any whitespace in `text` is encoded along with the rest.
+fn encoded_words(text: &[u8]) -> Vec<Vec<u8>> {
+    let text = String::from_utf8_lossy(text);
+    let encode = |chunk: &str| format!("=?utf-8?B?{}?=", base64::encode(chunk)).into_bytes();
+    let mut words = vec![];
+    let mut start = 0;
+    for (i, ch) in text.char_indices() {
+        if i + ch.len_utf8() - start > MAX_ENCODED_CHUNK {
+            words.push(encode(&text[start..i]));
+            start = i;
+        }
+    }
+    words.push(encode(&text[start..]));
+    words
+}
+
+/// Split `text` into tokens at its spaces, keeping any other whitespace,
+/// and encoding runs of words that aren't ASCII.
+fn unstructured(text: &[u8], tokens: &mut Tokens) {
+    // Each word with the whitespace that follows it.
+    let mut pieces = vec![];
+    let mut rest = trim_wsp_start(text);
+    while !rest.is_empty() {
+        let word_end = rest.iter().position(|ch| is_wsp(*ch)).unwrap_or(rest.len());
+        let wsp_end = rest[word_end..]
+            .iter()
+            .position(|ch| !is_wsp(*ch))
+            .map_or(rest.len(), |n| word_end + n);
+        pieces.push((&rest[..word_end], &rest[word_end..wsp_end]));
+        rest = &rest[wsp_end..];
+    }
+
+    // The token being built, which only ends at a space.
+    let mut pending = vec![];
+    let mut i = 0;
+    while i < pieces.len() {
+        let (word, mut wsp) = pieces[i];
+        if word.is_ascii() {
+            pending.extend_from_slice(word);
+            i += 1;
+        } else {
+            let end = pieces[i..]
+                .iter()
+                .position(|(word, _)| word.is_ascii())
+                .map_or(pieces.len(), |n| i + n);
+            let mut run = vec![];
+            for (j, (word, wsp)) in pieces[i..end].iter().enumerate() {
+                run.extend_from_slice(word);
+                if i + j + 1 < end {
+                    run.extend_from_slice(wsp);
+                }
+            }
+            let mut words = encoded_words(&run);
+            let last = words.pop().unwrap_or_default();
+            for word in words {
+                pending.extend_from_slice(&word);
+                tokens.push(std::mem::take(&mut pending));
+            }
+            pending.extend_from_slice(&last);
+            wsp = pieces[end - 1].1;
+            i = end;
+        }
+        match wsp.split_last() {
+            Some((b' ', before)) => {
+                pending.extend_from_slice(before);
+                tokens.push(std::mem::take(&mut pending));
+            }
+            // Folding would turn other whitespace into a space, so there's
+            // no break here.
+            Some(_) => pending.extend_from_slice(wsp),
+            None => {}
+        }
+    }
+    if !pending.is_empty() {
+        tokens.push(pending);
+    }
+}
+
+fn trim_wsp_start(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !is_wsp(*first) {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+fn phrase(words: &[ByteString], tokens: &mut Tokens) {
+    let mut i = 0;
+    while i < words.len() {
+        if words[i].0.is_ascii() {
+            tokens.push(quote_if_needed(&words[i].0, is_atom));
+            i += 1;
+            continue;
+        }
+        let end = words[i..]
+            .iter()
+            .position(|word| word.0.is_ascii())
+            .map_or(words.len(), |n| i + n);
+        let run: Vec<&[u8]> = words[i..end].iter().map(|word| &word.0[..]).collect();
+        for word in encoded_words(&run.join(&b' ')) {
+            tokens.push(word);
+        }
+        i = end;
+    }
+}
+
+pub(crate) fn addr_spec(spec: &AddrSpec) -> Vec<u8> {
+    let mut out = quote_if_needed(&spec.local_part.0, is_dot_atom);
+    out.push(b'@');
+    match &spec.domain {
+        Domain::Name(name) => out.extend_from_slice(&name.0),
+        Domain::Literal(dtext) => {
+            out.push(b'[');
+            out.extend_from_slice(&dtext.0);
+            out.push(b']');
+        }
+    }
+    out
+}
+
+fn mailbox(mb: &Mailbox, tokens: &mut Tokens) {
+    phrase(&mb.display_name, tokens);
+    let spec = mb.addr_spec.as_ref().map(addr_spec).unwrap_or_default();
+    if mb.display_name.is_empty() && !spec.is_empty() {
+        tokens.push(spec);
+    } else {
+        tokens.push([&b"<"[..], &spec, b">"].concat());
+    }
+}
+
+fn parameters(params: &HashMap<String, String>, tokens: &mut Tokens) {
+    // Sort for deterministic output.
+    let mut params: Vec<_> = params.iter().collect();
+    params.sort();
+    for (attr, val) in params {
+        tokens.end_item(b";", BETWEEN_ITEMS, true);
+        if val.bytes().all(|ch| (b' '..=b'~').contains(&ch)) {
+            let val = quote_if_needed(val.as_bytes(), |s| {
+                !s.is_empty() && s.iter().copied().all(is_token_ch)
+            });
+            tokens.push([attr.as_bytes(), b"=", &val].concat());
+        } else {
+            // A quoted string can't hold this, so use RFC 2231 encoding.
+            let mut text = format!("{}*=utf-8''", attr);
+            for ch in val.bytes() {
+                if is_token_ch(ch) && !b"*'%".contains(&ch) {
+                    text.push(ch as char);
+                } else {
+                    text.push_str(&format!("%{:02X}", ch));
+                }
+            }
+            tokens.push(text.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
+    use nom::combinator::all_consuming;
+
+    use crate::headers::address::{Address, Mailbox};
+    use crate::headers::mime::ContentDisposition;
+    use crate::headers::{FieldError, HeaderField, HeaderFieldInner};
+    use crate::parse::header::header_field;
+    use crate::ByteString;
+
+    #[test]
+    fn test_render_round_trip() {
+        let input = "To: Brennan Vincent <brennan@umanwizard.com>, \"Poppins, Mary\" \
+                     <mary-poppins-supercalifragilisticexpialidocious@example.com>, \
+                     Friends: a@example.com, b@example.com;, \"odd local\"@example.com\r\n";
+        let (_, hf) = all_consuming(header_field)(input.as_bytes()).unwrap();
+        let rendered = super::render_value(b"To", hf.inner());
+        let line = [&b"To:"[..], &rendered, b"\r\n"].concat();
+        for l in line.split(|ch| *ch == b'\n') {
+            assert!(
+                l.len() <= super::MAX_WIDTH + 1,
+                "{:?}",
+                String::from_utf8_lossy(l)
+            );
+        }
+        let (_, reparsed) = all_consuming(header_field)(&line).unwrap();
+        let (orig, new) = match (hf.inner(), reparsed.inner()) {
+            (HeaderFieldInner::To(orig), HeaderFieldInner::To(new)) => (orig, new),
+            _ => panic!("expected To fields"),
+        };
+        assert_eq!(format!("{:?}", orig), format!("{:?}", new));
+        assert!(matches!(new[2], Address::Group(_)));
+        assert_eq!(
+            String::from_utf8(rendered).unwrap(),
+            " Brennan Vincent <brennan@umanwizard.com>, \"Poppins, Mary\"\r\n \
+             <mary-poppins-supercalifragilisticexpialidocious@example.com>, Friends:\r\n \
+             a@example.com, b@example.com;, \"odd local\"@example.com"
+        );
+    }
+
+    #[test]
+    fn test_render_whitespace_and_non_ascii() {
+        let subject = HeaderFieldInner::Unstructured(ByteString(b"a  b\tc  d".to_vec()));
+        assert_eq!(super::render_value(b"Subject", &subject), b" a  b\tc  d");
+
+        let subject = HeaderFieldInner::Unstructured(ByteString(
+            "caf\u{e9} cr\u{e8}me and tea".as_bytes().to_vec(),
+        ));
+        let rendered = super::render_value(b"Subject", &subject);
+        assert_eq!(
+            String::from_utf8(rendered.clone()).unwrap(),
+            " =?utf-8?B?Y2Fmw6kgY3LDqG1l?= and tea"
+        );
+        let line = [&b"Subject:"[..], &rendered, b"\r\n"].concat();
+        all_consuming(header_field)(&line).unwrap();
+
+        let to = HeaderFieldInner::To(vec![Address::Mailbox(Mailbox {
+            display_name: vec![
+                ByteString(b"J".to_vec()),
+                ByteString("\u{f6}rg".as_bytes().to_vec()),
+            ],
+            addr_spec: None,
+        })]);
+        assert_eq!(
+            super::render_value(b"To", &to),
+            b" J =?utf-8?B?w7ZyZw==?= <>".to_vec()
+        );
+
+        let long = "\u{e9}".repeat(60);
+        let subject = HeaderFieldInner::Unstructured(ByteString(long.as_bytes().to_vec()));
+        let rendered = super::render_value(b"Subject", &subject);
+        let line = [&b"Subject:"[..], &rendered, b"\r\n"].concat();
+        for l in line.split(|ch| *ch == b'\n') {
+            assert!(l.len() <= super::MAX_WIDTH + 1);
+        }
+        all_consuming(header_field)(&line).unwrap();
+    }
+
+    #[test]
+    fn test_render_unfoldable() {
+        let long = "x".repeat(100);
+        let input = format!("Content-Type: text/plain; name={}; charset=utf-8\r\n", long);
+        let (_, hf) = all_consuming(header_field)(input.as_bytes()).unwrap();
+        let rendered = super::render_value(b"Content-Type", hf.inner());
+        assert_eq!(
+            String::from_utf8(rendered).unwrap(),
+            format!(" text/plain; charset=utf-8; name={}", long)
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_invalid_fields() {
+        let injected = HeaderFieldInner::Unstructured(ByteString(b"hi\r\nBcc: x@evil".to_vec()));
+        assert_eq!(
+            HeaderField::from_inner("Subject", injected).err(),
+            Some(FieldError::InvalidValue)
+        );
+        for name in ["", "X Spam", "X-Spam:", "X-Spam\r\nBcc"] {
+            let value = HeaderFieldInner::Unstructured(ByteString(b"yes".to_vec()));
+            assert_eq!(
+                HeaderField::from_inner(name, value).err(),
+                Some(FieldError::InvalidName)
+            );
+        }
+
+        let mut field = HeaderField::from_inner(
+            "Subject",
+            HeaderFieldInner::Unstructured(ByteString(b"hi".to_vec())),
+        )
+        .unwrap();
+        let injected = HeaderFieldInner::Unstructured(ByteString(b"hi\nBcc: x@evil".to_vec()));
+        assert_eq!(
+            field.set_inner(injected).err(),
+            Some(FieldError::InvalidValue)
+        );
+        assert_eq!(field.raw_value(), b" hi");
+    }
+
+    #[test]
+    fn test_render_extended_parameters() {
+        let mut parameters = HashMap::new();
+        parameters.insert(
+            "filename".to_string(),
+            "r\u{e9}sum\u{e9} 100%.pdf".to_string(),
+        );
+        parameters.insert("note".to_string(), "a\r\nBcc: x@evil".to_string());
+        let cd = HeaderFieldInner::ContentDisposition(ContentDisposition {
+            disposition: Cow::Owned(ByteString(b"attachment".to_vec())),
+            parameters,
+        });
+        let field = HeaderField::from_inner("Content-Disposition", cd).unwrap();
+        assert_eq!(
+            field.raw_value(),
+            &b" attachment;\r\n filename*=utf-8''r%C3%A9sum%C3%A9%20100%25.pdf;\r\n \
+               note*=utf-8''a%0D%0ABcc%3A%20x%40evil"[..]
+        );
+
+        let mut line = b"Content-Disposition:".to_vec();
+        line.extend_from_slice(field.raw_value());
+        line.extend_from_slice(b"\r\n");
+        let (_, reparsed) = all_consuming(header_field)(&line).unwrap();
+        let cd = match reparsed.inner() {
+            HeaderFieldInner::ContentDisposition(cd) => cd,
+            _ => panic!("expected Content-Disposition"),
+        };
+        assert_eq!(cd.parameters["filename"], "r\u{e9}sum\u{e9} 100%.pdf");
+        assert_eq!(cd.parameters["note"], "a\r\nBcc: x@evil");
+    }
+}
@@ -0,0 +1,524 @@
+//! Rendering of the IMAP `BODYSTRUCTURE`, `BODY` and `ENVELOPE`
+//! FETCH data items, as specified in RFC 3501 section 7.4.2, and of
+//! `THREAD` responses (RFC 5256).
+//!
+//! Each function appends the parenthesized structure (without the
+//! data item name) to `out`.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use nom::combinator::all_consuming;
+use nom::Parser;
+
+use crate::headers::address::{Address, Domain, Mailbox};
+use crate::headers::mime::ContentTransferEncoding;
+use crate::headers::HeaderFieldInner;
+use crate::parse::is_wsp;
+use crate::thread::Thread;
+use crate::{Body, Message};
+
+/// The extensible form of the body structure (`BODYSTRUCTURE`), which
+/// includes the MD5, disposition, language and location of each part.
+pub fn body_structure(message: &Message, out: &mut Vec<u8>) {
+    body_inner(message, true, out)
+}
+
+/// The non-extensible form of the body structure (`BODY`).
+pub fn body(message: &Message, out: &mut Vec<u8>) {
+    body_inner(message, false, out)
+}
+
+pub fn envelope(message: &Message, out: &mut Vec<u8>) {
+    use HeaderFieldInner::*;
+
+    let from = find_inner(message, |inner| match inner {
+        From(mailboxes) => Some(mailboxes),
+        _ => None,
+    });
+    let sender = find_inner(message, |inner| match inner {
+        Sender(mailbox) => Some(mailbox),
+        _ => None,
+    });
+    let reply_to = find_inner(message, |inner| match inner {
+        ReplyTo(addresses) => Some(addresses),
+        _ => None,
+    });
+
+    out.push(b'(');
+    nstring(unstructured_value(message, "Date"), out);
+    out.push(b' ');
+    nstring(unstructured_value(message, "Subject"), out);
+    out.push(b' ');
+    mailbox_list(from.into_iter().flatten(), out);
+    out.push(b' ');
+    // [RFC] Sender and Reply-To default to From.
+    match sender {
+        Some(sender) => mailbox_list(std::iter::once(sender), out),
+        None => mailbox_list(from.into_iter().flatten(), out),
+    }
+    out.push(b' ');
+    match reply_to {
+        Some(reply_to) => address_list(reply_to, out),
+        None => mailbox_list(from.into_iter().flatten(), out),
+    }
+    let to = find_inner(message, |inner| match inner {
+        To(addresses) => Some(addresses),
+        _ => None,
+    });
+    let cc = find_inner(message, |inner| match inner {
+        Cc(addresses) => Some(addresses),
+        _ => None,
+    });
+    let bcc = find_inner(message, |inner| match inner {
+        Bcc(addresses) => Some(addresses),
+        _ => None,
+    });
+    for addresses in [to, cc, bcc].iter() {
+        out.push(b' ');
+        address_list(addresses.map(Vec::as_slice).unwrap_or(&[]), out);
+    }
+    out.push(b' ');
+    nstring(unstructured_value(message, "In-Reply-To"), out);
+    out.push(b' ');
+    nstring(unstructured_value(message, "Message-ID"), out);
+    out.push(b')');
+}
+
+/// The threads of a `THREAD` response, e.g. `(2)(3 6 (4 23)(44 7 96))`.
+/// `id` gives the sequence number or UID of the message at an index.
+pub fn thread(threads: &[Thread], id: impl Fn(usize) -> u32, out: &mut Vec<u8>) {
+    for thread in threads {
+        out.push(b'(');
+        thread_node(thread, &id, out);
+        out.push(b')');
+    }
+}
+
+fn thread_node(thread: &Thread, id: &impl Fn(usize) -> u32, out: &mut Vec<u8>) {
+    if let Some(message) = thread.message {
+        write!(out, "{}", id(message)).unwrap();
+        if !thread.children.is_empty() {
+            out.push(b' ');
+        }
+    }
+    // A chain of single replies is written as a flat list.
+    match thread.children.as_slice() {
+        [child] if thread.message.is_some() => thread_node(child, id, out),
+        children => {
+            for child in children {
+                out.push(b'(');
+                thread_node(child, id, out);
+                out.push(b')');
+            }
+        }
+    }
+}
+
+fn body_inner(message: &Message, extensible: bool, out: &mut Vec<u8>) {
+    out.push(b'(');
+    match message.body() {
+        Body::Multipart { parts, .. } => {
+            for part in parts {
+                body_inner(part, extensible, out);
+            }
+            out.push(b' ');
+            // A message built without a Content-Type has nothing else to go
+            // by; it's written as multipart/mixed.
+            let ct = message.content_type();
+            string(ct.map_or(&b"MIXED"[..], |ct| &ct.subtype.0), out);
+            if extensible {
+                out.push(b' ');
+                match ct {
+                    Some(ct) => parameters(&ct.parameters, out),
+                    None => nil(out),
+                }
+                out.push(b' ');
+                extension_data(message, out);
+            }
+        }
+        Body::SimpleText(_) | Body::SimpleBinary(_) => {
+            let ct = message.content_type();
+            let (is_text, is_rfc822) = match ct {
+                Some(ct) => (
+                    ct.r#type.0.eq_ignore_ascii_case(b"text"),
+                    ct.r#type.0.eq_ignore_ascii_case(b"message")
+                        && ct.subtype.0.eq_ignore_ascii_case(b"rfc822"),
+                ),
+                None => (true, false),
+            };
+            let encapsulated = match message.body() {
+                Body::SimpleBinary(data) if is_rfc822 => {
+                    all_consuming(crate::parse::email::message())
+                        .parse(data)
+                        .ok()
+                        .map(|(_, encapsulated)| encapsulated)
+                }
+                _ => None,
+            };
+            match ct {
+                // Without its envelope and body structure, a message/rfc822
+                // part that doesn't parse can only be described as data.
+                Some(ct) if is_rfc822 && encapsulated.is_none() => {
+                    out.extend_from_slice(br#""APPLICATION" "OCTET-STREAM" "#);
+                    parameters(&ct.parameters, out);
+                }
+                Some(ct) => {
+                    string(&ct.r#type.0, out);
+                    out.push(b' ');
+                    string(&ct.subtype.0, out);
+                    out.push(b' ');
+                    parameters(&ct.parameters, out);
+                }
+                None => out.extend_from_slice(br#""text" "plain" ("charset" "us-ascii")"#),
+            }
+            out.push(b' ');
+            nstring(unstructured_value(message, "Content-ID"), out);
+            out.push(b' ');
+            nstring(unstructured_value(message, "Content-Description"), out);
+            out.push(b' ');
+            let cte = find_inner(message, |inner| match inner {
+                HeaderFieldInner::ContentTransferEncoding(cte) => Some(*cte),
+                _ => None,
+            });
+            let cte: &[u8] = match cte {
+                None | Some(ContentTransferEncoding::SevenBit) => b"7BIT",
+                Some(ContentTransferEncoding::EightBit) => b"8BIT",
+                Some(ContentTransferEncoding::Binary) => b"BINARY",
+                Some(ContentTransferEncoding::Base64) => b"BASE64",
+                Some(ContentTransferEncoding::QuotedPrintable) => b"QUOTED-PRINTABLE",
+            };
+            string(cte, out);
+            write!(out, " {}", message.body_size()).unwrap();
+
+            if let Some(encapsulated) = encapsulated {
+                out.push(b' ');
+                envelope(&encapsulated, out);
+                out.push(b' ');
+                body_inner(&encapsulated, extensible, out);
+                write!(out, " {}", message.body_lines()).unwrap();
+            } else if is_text {
+                write!(out, " {}", message.body_lines()).unwrap();
+            }
+            if extensible {
+                out.push(b' ');
+                nstring(unstructured_value(message, "Content-MD5"), out);
+                out.push(b' ');
+                extension_data(message, out);
+            }
+        }
+    }
+    out.push(b')');
+}
+
+// body-fld-dsp SP body-fld-lang SP body-fld-loc
+fn extension_data(message: &Message, out: &mut Vec<u8>) {
+    let disposition = find_inner(message, |inner| match inner {
+        HeaderFieldInner::ContentDisposition(cd) => Some(cd),
+        _ => None,
+    });
+    match disposition {
+        Some(cd) => {
+            out.push(b'(');
+            string(&cd.disposition.0, out);
+            out.push(b' ');
+            parameters(&cd.parameters, out);
+            out.push(b')');
+        }
+        None => nil(out),
+    }
+    out.push(b' ');
+    let languages: Vec<&[u8]> = unstructured_value(message, "Content-Language")
+        .map(|langs| {
+            langs
+                .split(|ch| *ch == b',')
+                .map(trim_wsp)
+                .filter(|lang| !lang.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    match languages.as_slice() {
+        [] => nil(out),
+        [lang] => string(lang, out),
+        langs => {
+            out.push(b'(');
+            for (i, lang) in langs.iter().enumerate() {
+                if i != 0 {
+                    out.push(b' ');
+                }
+                string(lang, out);
+            }
+            out.push(b')');
+        }
+    }
+    out.push(b' ');
+    nstring(unstructured_value(message, "Content-Location"), out);
+}
+
+fn find_inner<'m, 'a, T>(
+    message: &'m Message<'a>,
+    f: impl Fn(&'m HeaderFieldInner<'a>) -> Option<T>,
+) -> Option<T> {
+    message.header().iter().find_map(|hf| f(hf.inner()))
+}
+
+fn trim_wsp(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !is_wsp(*first) {
+            break;
+        }
+        s = rest;
+    }
+    while let [rest @ .., last] = s {
+        if !is_wsp(*last) {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+fn unstructured_value<'m>(message: &'m Message, name: &str) -> Option<&'m [u8]> {
+    message
+        .header_field(name)
+        .map(|hf| trim_wsp(&hf.unfolded_value().0))
+}
+
+fn nil(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"NIL");
+}
+
+/// Append `s` as a quoted string if possible, or as a literal otherwise.
+fn string(s: &[u8], out: &mut Vec<u8>) {
+    if s.iter().all(|ch| ch.is_ascii() && !b"\0\r\n".contains(ch)) {
+        out.push(b'"');
+        for ch in s.iter().copied() {
+            if ch == b'"' || ch == b'\\' {
+                out.push(b'\\');
+            }
+            out.push(ch);
+        }
+        out.push(b'"');
+    } else {
+        write!(out, "{{{}}}\r\n", s.len()).unwrap();
+        out.extend_from_slice(s);
+    }
+}
+
+fn nstring(s: Option<&[u8]>, out: &mut Vec<u8>) {
+    match s {
+        Some(s) => string(s, out),
+        None => nil(out),
+    }
+}
+
+fn parameters(params: &HashMap<String, String>, out: &mut Vec<u8>) {
+    if params.is_empty() {
+        return nil(out);
+    }
+    // Sort for deterministic output.
+    let mut params: Vec<_> = params.iter().collect();
+    params.sort();
+    out.push(b'(');
+    for (i, (attr, val)) in params.into_iter().enumerate() {
+        if i != 0 {
+            out.push(b' ');
+        }
+        string(attr.as_bytes(), out);
+        out.push(b' ');
+        string(val.as_bytes(), out);
+    }
+    out.push(b')');
+}
+
+// addr-name SP addr-adl SP addr-mailbox SP addr-host
+fn address_fields(
+    name: Option<&[u8]>,
+    mailbox: Option<&[u8]>,
+    host: Option<&[u8]>,
+    out: &mut Vec<u8>,
+) {
+    out.push(b'(');
+    nstring(name, out);
+    out.extend_from_slice(b" NIL ");
+    nstring(mailbox, out);
+    out.push(b' ');
+    nstring(host, out);
+    out.push(b')');
+}
+
+fn mailbox(mailbox: &Mailbox, out: &mut Vec<u8>) {
+    let name = mailbox
+        .display_name
+        .iter()
+        .map(|word| word.0.as_slice())
+        .collect::<Vec<_>>()
+        .join(&b' ');
+    let name = if name.is_empty() {
+        None
+    } else {
+        Some(name.as_slice())
+    };
+    match &mailbox.addr_spec {
+        Some(spec) => {
+            let literal;
+            let host = match &spec.domain {
+                Domain::Name(name) => &name.0,
+                Domain::Literal(dtext) => {
+                    literal = [&b"["[..], &dtext.0, b"]"].concat();
+                    literal.as_slice()
+                }
+            };
+            address_fields(name, Some(&spec.local_part.0), Some(host), out)
+        }
+        // [RFC] Seen in the wild: `Foo <>`. Use empty strings rather than NIL,
+        // since NIL would be mistaken for group syntax.
+        None => address_fields(name, Some(b""), Some(b""), out),
+    }
+}
+
+fn mailbox_list<'m, 'a: 'm>(mailboxes: impl Iterator<Item = &'m Mailbox<'a>>, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.push(b'(');
+    for mb in mailboxes {
+        mailbox(mb, out);
+    }
+    if out.len() == start + 1 {
+        out.truncate(start);
+        nil(out);
+    } else {
+        out.push(b')');
+    }
+}
+
+fn address_list(addresses: &[Address], out: &mut Vec<u8>) {
+    if addresses.is_empty() {
+        return nil(out);
+    }
+    out.push(b'(');
+    for address in addresses {
+        match address {
+            Address::Mailbox(mb) => mailbox(mb, out),
+            Address::Group(group) => {
+                // A group is delimited by a start marker holding its name
+                // in the mailbox field, and an end marker with NIL everywhere.
+                let name = group
+                    .display_name
+                    .iter()
+                    .map(|word| word.0.as_slice())
+                    .collect::<Vec<_>>()
+                    .join(&b' ');
+                address_fields(None, Some(&name), None, out);
+                for mb in group.mailboxes.iter() {
+                    mailbox(mb, out);
+                }
+                address_fields(None, None, None, out);
+            }
+        }
+    }
+    out.push(b')');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use nom::combinator::all_consuming;
+    use nom::Parser;
+
+    use crate::parse::email::message;
+    use crate::{Body, Message};
+
+    #[test]
+    fn test_body_structure_and_envelope() {
+        let input = "From: Brennan Vincent <brennan@umanwizard.com>\r\n\
+                     To: a@example.com, Friends: b@example.com;\r\n\
+                     Subject: Hello\r\n\
+                     Date: Mon, 1 Mar 2021 12:00:00 +0000\r\n\
+                     Message-ID: <1@example.com>\r\n\
+                     Content-Type: multipart/mixed; boundary=xyz\r\n\
+                     \r\n\
+                     --xyz\r\n\
+                     Content-Type: text/plain; charset=utf-8\r\n\
+                     \r\n\
+                     Hello!\r\n\
+                     World!\r\n\
+                     --xyz\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     Content-Disposition: attachment; filename=\"a.bin\"\r\n\
+                     Content-Transfer-Encoding: base64\r\n\
+                     \r\n\
+                     AAEC\r\n\
+                     --xyz--\r\n";
+        let (_, m) = all_consuming(message()).parse(input.as_bytes()).unwrap();
+
+        let mut out = vec![];
+        super::body_structure(&m, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "((\"text\" \"plain\" (\"charset\" \"utf-8\") NIL NIL \"7BIT\" 14 2 NIL NIL NIL NIL)\
+             (\"application\" \"octet-stream\" NIL NIL NIL \"BASE64\" 4 NIL \
+             (\"attachment\" (\"filename\" \"a.bin\")) NIL NIL) \
+             \"mixed\" (\"boundary\" \"xyz\") NIL NIL NIL)"
+        );
+
+        let mut out = vec![];
+        super::body(&m, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "((\"text\" \"plain\" (\"charset\" \"utf-8\") NIL NIL \"7BIT\" 14 2)\
+             (\"application\" \"octet-stream\" NIL NIL NIL \"BASE64\" 4) \"mixed\")"
+        );
+
+        let mut out = vec![];
+        super::envelope(&m, &mut out);
+        let from = "((\"Brennan Vincent\" NIL \"brennan\" \"umanwizard.com\"))";
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "(\"Mon, 1 Mar 2021 12:00:00 +0000\" \"Hello\" {0} {0} {0} \
+                 ((NIL NIL \"a\" \"example.com\")(NIL NIL \"Friends\" NIL)\
+                 (NIL NIL \"b\" \"example.com\")(NIL NIL NIL NIL)) \
+                 NIL NIL NIL \"<1@example.com>\")",
+                from
+            )
+        );
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let input = "Content-Type: message/rfc822\r\n\
+                     \r\n\
+                     \x00not a message";
+        let (_, m) = all_consuming(message()).parse(input.as_bytes()).unwrap();
+        let mut out = vec![];
+        super::body(&m, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "(\"APPLICATION\" \"OCTET-STREAM\" NIL NIL NIL \"7BIT\" 14)"
+        );
+
+        let body = Body::Multipart {
+            preamble: Cow::Borrowed(b""),
+            parts: vec![Message::from_parts(
+                vec![],
+                Body::SimpleText("Hi".to_owned()),
+            )],
+            epilogue: Cow::Borrowed(b""),
+        };
+        let mut out = vec![];
+        super::body_structure(&Message::from_parts(vec![], body), &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "((\"text\" \"plain\" (\"charset\" \"us-ascii\") NIL NIL \"7BIT\" 0 0 NIL NIL NIL NIL) \
+             \"MIXED\" NIL NIL NIL NIL)"
+        );
+    }
+
+    #[test]
+    fn test_literal() {
+        let mut out = vec![];
+        super::string("caf\u{e9}".as_bytes(), &mut out);
+        assert_eq!(out, b"{5}\r\ncaf\xc3\xa9");
+    }
+}
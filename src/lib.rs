@@ -1,14 +1,44 @@
+pub mod auth;
+pub mod crypto;
 pub mod error;
 pub mod headers;
+pub mod imap;
+pub mod mailbox;
 pub mod parse;
+pub mod report;
+pub mod section;
+#[cfg(feature = "serde")]
+pub mod serialize;
+pub mod smtp;
+pub mod subject;
+pub mod thread;
+mod write;
 
+/// The sender and recipients of a message as given to SMTP, which may differ
+/// from those in its header (e.g. for Bcc recipients or bounces).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct SmtpEnvelope {
-    pub from: Option<String>,
-    pub to: Option<String>,
+    pub from: ReversePath,
+    pub params: MailParameters,
+    pub to: Vec<Recipient>,
 }
 
+impl SmtpEnvelope {
+    /// An envelope with no MAIL or RCPT parameters.
+    pub fn new(from: ReversePath, to: impl IntoIterator<Item = SmtpAddress>) -> Self {
+        Self {
+            from,
+            params: MailParameters::default(),
+            to: to.into_iter().map(Recipient::new).collect(),
+        }
+    }
+}
+
+use std::borrow::Cow;
 use std::ops::Deref;
 
+use smtp::envelope::{MailParameters, Recipient, ReversePath, SmtpAddress};
+
 #[derive(Clone)]
 pub struct ByteString(pub Vec<u8>);
 
@@ -55,24 +85,61 @@ impl ToOwned for ByteStr {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Body<'a> {
     SimpleText(String),
-    SimpleBinary(Vec<u8>),
+    SimpleBinary(#[cfg_attr(feature = "serde", serde(with = "serialize::bytes"))] Vec<u8>),
     Multipart {
-        preamble: &'a [u8],
+        #[cfg_attr(feature = "serde", serde(with = "serialize::bytes"))]
+        preamble: Cow<'a, [u8]>,
         parts: Vec<Message<'a>>,
-        epilogue: &'a [u8],
+        #[cfg_attr(feature = "serde", serde(with = "serialize::bytes"))]
+        epilogue: Cow<'a, [u8]>,
         // content_subtype: &'a [u8],
     },
 }
 
+impl<'a> Body<'a> {
+    pub fn into_owned(self) -> Body<'static> {
+        match self {
+            Body::SimpleText(text) => Body::SimpleText(text),
+            Body::SimpleBinary(data) => Body::SimpleBinary(data),
+            Body::Multipart {
+                preamble,
+                parts,
+                epilogue,
+            } => Body::Multipart {
+                preamble: Cow::Owned(preamble.into_owned()),
+                parts: parts.into_iter().map(Message::into_owned).collect(),
+                epilogue: Cow::Owned(epilogue.into_owned()),
+            },
+        }
+    }
+}
+
+use headers::mime::ContentType;
 pub use headers::HeaderField;
+use headers::{FieldError, HeaderFieldInner};
 #[derive(Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct Message<'a> {
     header: Vec<HeaderField<'a>>,
-    content_type: Option<usize>, // index of Content-Type field in header
+    // index of Content-Type field in header, recomputed after deserializing
+    #[cfg_attr(feature = "serde", serde(skip))]
+    content_type: Option<usize>,
     body: Body<'a>,
     size: usize,
+    // size and line count of the body in its transfer encoding
+    body_size: usize,
+    body_lines: usize,
+    // the whole message, header included, as it appeared in the input, e.g.
+    // for computing signatures; not serialized (see `serialize`)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    raw: Cow<'a, [u8]>,
 }
 
 impl<'a> Message<'a> {
@@ -81,12 +148,18 @@ impl<'a> Message<'a> {
         content_type: Option<usize>,
         body: Body<'a>,
         size: usize,
+        body_size: usize,
+        body_lines: usize,
+        raw: &'a [u8],
     ) -> Self {
         Self {
             header,
             content_type,
             body,
             size,
+            body_size,
+            body_lines,
+            raw: Cow::Borrowed(raw),
         }
     }
 
@@ -94,12 +167,143 @@ impl<'a> Message<'a> {
         &self.header
     }
 
+    /// The first header field with the given name (compared case-insensitively).
+    pub fn header_field(&self, name: &str) -> Option<&HeaderField<'a>> {
+        self.header
+            .iter()
+            .find(|hf| hf.name().0.eq_ignore_ascii_case(name.as_bytes()))
+    }
+
+    /// Build a message from its parts, e.g. for sending.
+    ///
+    /// The body must agree with the Content-Type field (if any) in `header`.
+    /// A multipart body may lack a Content-Type field or boundary, here or
+    /// after editing with `remove_header` or `body_mut`; it's then treated as
+    /// `multipart/mixed`, and `write` generates a boundary.
+    pub fn from_parts(header: Vec<HeaderField<'a>>, body: Body<'a>) -> Self {
+        let mut message = Self::new(header, None, body, 0, 0, 0, b"");
+        message.reindex();
+        message
+    }
+
+    /// Insert `field` before the field at `index` (e.g., 0 to prepend a `Received` field).
+    pub fn insert_header(&mut self, index: usize, field: HeaderField<'a>) {
+        self.header.insert(index, field);
+        self.reindex();
+    }
+
+    pub fn push_header(&mut self, field: HeaderField<'a>) {
+        self.header.push(field);
+        self.reindex();
+    }
+
+    pub fn remove_header(&mut self, index: usize) -> HeaderField<'a> {
+        let removed = self.header.remove(index);
+        self.reindex();
+        removed
+    }
+
+    /// Remove all fields with the given name (compared case-insensitively),
+    /// returning how many were removed.
+    pub fn remove_headers(&mut self, name: &str) -> usize {
+        let before = self.header.len();
+        self.header
+            .retain(|hf| !hf.name().0.eq_ignore_ascii_case(name.as_bytes()));
+        self.reindex();
+        before - self.header.len()
+    }
+
+    /// Replace the value of the field at `index`, keeping its name. On
+    /// failure (see `HeaderField::set_inner`), the field is left unchanged.
+    pub fn replace_header(
+        &mut self,
+        index: usize,
+        inner: HeaderFieldInner<'a>,
+    ) -> Result<(), FieldError> {
+        self.header[index].set_inner(inner)?;
+        self.reindex();
+        Ok(())
+    }
+
+    fn reindex(&mut self) {
+        self.content_type = self
+            .header
+            .iter()
+            .position(|hf| matches!(hf.inner(), HeaderFieldInner::ContentType(_)));
+    }
+
     pub fn body(&self) -> &Body<'a> {
         &self.body
     }
+
+    pub fn body_mut(&mut self) -> &mut Body<'a> {
+        &mut self.body
+    }
+
+    pub fn content_type(&self) -> Option<&ContentType<'a>> {
+        self.content_type.map(|idx| match self.header[idx].inner() {
+            HeaderFieldInner::ContentType(ct) => ct,
+            _ => unreachable!(),
+        })
+    }
+
+    /// Size in bytes of the message as it appeared in the input.
+    ///
+    /// This and the other size accessors describe the parsed input; they are
+    /// zero for messages built with `from_parts`, and are not updated by editing.
     pub fn size(&self) -> usize {
         self.size
     }
+
+    /// Size in bytes of the body as it appeared in the input,
+    /// i.e., before any Content-Transfer-Encoding was decoded.
+    pub fn body_size(&self) -> usize {
+        self.body_size
+    }
+
+    /// Number of lines in the body as it appeared in the input.
+    pub fn body_lines(&self) -> usize {
+        self.body_lines
+    }
+
+    /// The body exactly as it appeared in the input, before any
+    /// Content-Transfer-Encoding was decoded: the end of `raw`.
+    pub fn raw_body(&self) -> &[u8] {
+        &self.raw[self.raw.len().saturating_sub(self.body_size)..]
+    }
+
+    /// The whole message exactly as it appeared in the input, header
+    /// included. For a part of a multipart body this is everything between
+    /// the boundary delimiters, e.g. the signed bytes of a `multipart/signed`
+    /// part.
+    ///
+    /// Like the sizes, this describes the parsed input: it is empty for
+    /// messages built with `from_parts` or deserialized, and isn't updated
+    /// by editing, so after e.g. `insert_header` or `body_mut` it no longer
+    /// matches the message.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Copy any data borrowed from the input buffer, so that
+    /// the message can outlive it (e.g., to be sent to another thread).
+    /// Every part gets its own copy of its `raw` bytes, so this takes more
+    /// memory the more deeply the message's parts are nested.
+    pub fn into_owned(self) -> Message<'static> {
+        Message {
+            header: self
+                .header
+                .into_iter()
+                .map(HeaderField::into_owned)
+                .collect(),
+            content_type: self.content_type,
+            body: self.body.into_owned(),
+            size: self.size,
+            body_size: self.body_size,
+            body_lines: self.body_lines,
+            raw: Cow::Owned(self.raw.into_owned()),
+        }
+    }
 }
 
 impl<'a> std::fmt::Debug for Body<'a> {
@@ -145,3 +349,48 @@ impl<'a> std::fmt::Debug for Message<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+    use nom::Parser;
+
+    use crate::headers::{HeaderField, HeaderFieldInner};
+    use crate::parse::email::message;
+    use crate::ByteString;
+
+    #[test]
+    fn test_edit_header() {
+        let input = "X-Spam: yes\r\n\
+                     Subject: hi\r\n\
+                     Content-Type: text/plain\r\n\
+                     x-spam: very\r\n\
+                     \r\n\
+                     body\r\n";
+        let (_, mut m) = all_consuming(message()).parse(input.as_bytes()).unwrap();
+
+        let received = HeaderFieldInner::Unstructured(ByteString(b"from a by b".to_vec()));
+        m.insert_header(0, HeaderField::from_inner("Received", received).unwrap());
+        assert_eq!(m.remove_headers("X-Spam"), 2);
+        let names: Vec<_> = m.header().iter().map(|hf| hf.name().0.to_vec()).collect();
+        assert_eq!(
+            names,
+            vec![
+                b"Received".to_vec(),
+                b"Subject".to_vec(),
+                b"Content-Type".to_vec()
+            ]
+        );
+        assert_eq!(m.header()[0].raw_value(), b" from a by b");
+        assert_eq!(m.content_type().unwrap().subtype.0, *b"plain");
+
+        let (_, html) = crate::parse::mime::content_type(b" text/html; charset=utf-8").unwrap();
+        m.replace_header(2, HeaderFieldInner::ContentType(html))
+            .unwrap();
+        assert_eq!(m.content_type().unwrap().subtype.0, *b"html");
+        assert_eq!(m.header()[2].raw_value(), b" text/html; charset=utf-8");
+
+        m.remove_header(2);
+        assert!(m.content_type().is_none());
+    }
+}
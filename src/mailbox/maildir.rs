@@ -0,0 +1,365 @@
+//! Maildir mailboxes.
+//!
+//! A Maildir is a directory with three subdirectories: `tmp`, `new` and `cur`.
+//! Each message is stored in its own file. Messages are delivered by writing
+//! them into `tmp` under a unique name and then renaming them into `new`; a
+//! mail reader moves them to `cur` once it has seen them, appending an info
+//! suffix such as `:2,RS` that records the message's flags.
+//!
+//! See <https://cr.yp.to/proto/maildir.html>.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{to_crlf, to_lf};
+use crate::Message;
+
+/// Separates the unique name of a message from its info.
+const INFO_SEPARATOR: char = ':';
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Subdir {
+    New,
+    Cur,
+}
+
+impl Subdir {
+    fn name(self) -> &'static str {
+        match self {
+            Subdir::New => "new",
+            Subdir::Cur => "cur",
+        }
+    }
+}
+
+/// The flags of a message, as stored in a `2,` info suffix.
+///
+/// Flags are single ASCII letters. The uppercase ones are defined by the
+/// Maildir specification (see the associated constants); lowercase ones are
+/// commonly used for keywords.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flags(BTreeSet<char>);
+
+impl Flags {
+    pub const DRAFT: char = 'D';
+    pub const FLAGGED: char = 'F';
+    pub const PASSED: char = 'P';
+    pub const REPLIED: char = 'R';
+    pub const SEEN: char = 'S';
+    pub const TRASHED: char = 'T';
+
+    /// Parse the flags from an info suffix (the part after the `:`).
+    /// Returns `None` if the info is not of the `2,` kind.
+    pub fn from_info(info: &str) -> Option<Self> {
+        let flags = info.strip_prefix("2,")?;
+        Some(Flags(
+            flags.chars().filter(char::is_ascii_alphabetic).collect(),
+        ))
+    }
+
+    pub fn contains(&self, flag: char) -> bool {
+        self.0.contains(&flag)
+    }
+
+    /// Add `flag`. Returns false, leaving the flags unchanged, if it isn't
+    /// an ASCII letter.
+    pub fn insert(&mut self, flag: char) -> bool {
+        if !flag.is_ascii_alphabetic() {
+            return false;
+        }
+        self.0.insert(flag);
+        true
+    }
+
+    pub fn remove(&mut self, flag: char) {
+        self.0.remove(&flag);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = char> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+/// Formats the flags as an info suffix, e.g. `2,FS`.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The specification requires the flags to be in ASCII order,
+        // which `BTreeSet` already gives us.
+        write!(f, "2,")?;
+        for flag in self.iter() {
+            write!(f, "{}", flag)?;
+        }
+        Ok(())
+    }
+}
+
+/// A message file in a Maildir.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaildirEntry {
+    id: String,
+    info: Option<String>,
+    subdir: Subdir,
+    path: PathBuf,
+}
+
+impl MaildirEntry {
+    /// The unique name of the message, which doesn't change when it is
+    /// moved or its flags change.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The info suffix of the file name, if any, not including the separator.
+    pub fn info(&self) -> Option<&str> {
+        self.info.as_deref()
+    }
+
+    /// The flags of the message. Messages without a `2,` info have none.
+    pub fn flags(&self) -> Flags {
+        self.info
+            .as_deref()
+            .and_then(Flags::from_info)
+            .unwrap_or_default()
+    }
+
+    pub fn subdir(&self) -> Subdir {
+        self.subdir
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the contents of the message file, with line endings converted
+    /// to CRLF. The result can be parsed with `parse_message`.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        Ok(to_crlf(&fs::read(&self.path)?))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Maildir {
+    path: PathBuf,
+}
+
+impl Maildir {
+    /// Open the Maildir at `path`, which is not checked for existence.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Create the Maildir at `path` (and its parents) if it doesn't exist yet.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let maildir = Self::new(path);
+        for subdir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(maildir.path.join(subdir))?;
+        }
+        Ok(maildir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The messages in `subdir`, sorted by file name. Files whose names
+    /// start with a dot are ignored, as the specification requires.
+    pub fn list(&self, subdir: Subdir) -> io::Result<Vec<MaildirEntry>> {
+        let mut entries = vec![];
+        for dirent in fs::read_dir(self.path.join(subdir.name()))? {
+            let dirent = dirent?;
+            let name = match dirent.file_name().into_string() {
+                Ok(name) => name,
+                // Not something we could have delivered; skip it.
+                Err(_) => continue,
+            };
+            if name.starts_with('.') || !dirent.file_type()?.is_file() {
+                continue;
+            }
+            entries.push(self.entry(subdir, &name));
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// The messages in `new`, followed by those in `cur`.
+    pub fn list_all(&self) -> io::Result<Vec<MaildirEntry>> {
+        let mut entries = self.list(Subdir::New)?;
+        entries.extend(self.list(Subdir::Cur)?);
+        Ok(entries)
+    }
+
+    /// Look up a message by its unique name, in either `new` or `cur`.
+    pub fn find(&self, id: &str) -> io::Result<Option<MaildirEntry>> {
+        Ok(self.list_all()?.into_iter().find(|entry| entry.id == id))
+    }
+
+    /// Deliver `data` as a new message: write it to `tmp` under a fresh
+    /// unique name, flush it to disk, and rename it into `new`. CRLF line
+    /// endings are stored as LF.
+    pub fn deliver(&self, data: &[u8]) -> io::Result<MaildirEntry> {
+        let data = to_lf(data);
+        loop {
+            let id = unique_name();
+            let tmp_path = self.path.join("tmp").join(&id);
+            let mut file = match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)
+            {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            let written = file.write_all(&data).and_then(|()| file.sync_all());
+            drop(file);
+            let entry = self.entry(Subdir::New, &id);
+            if let Err(e) = written.and_then(|()| fs::rename(&tmp_path, &entry.path)) {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+            return Ok(entry);
+        }
+    }
+
+    /// Serialize `message` and deliver it like `deliver`.
+    pub fn deliver_message(&self, message: &Message) -> io::Result<MaildirEntry> {
+        self.deliver(&message.to_bytes())
+    }
+
+    /// Move `entry` to `cur` (if it isn't there already) and set its flags.
+    pub fn set_flags(&self, entry: &MaildirEntry, flags: &Flags) -> io::Result<MaildirEntry> {
+        let name = format!("{}{}{}", entry.id, INFO_SEPARATOR, flags);
+        let new_entry = self.entry(Subdir::Cur, &name);
+        if new_entry.path != entry.path {
+            fs::rename(&entry.path, &new_entry.path)?;
+        }
+        Ok(new_entry)
+    }
+
+    /// Move `entry` back to `new`, dropping its info.
+    pub fn mark_new(&self, entry: &MaildirEntry) -> io::Result<MaildirEntry> {
+        let new_entry = self.entry(Subdir::New, &entry.id);
+        if new_entry.path != entry.path {
+            fs::rename(&entry.path, &new_entry.path)?;
+        }
+        Ok(new_entry)
+    }
+
+    pub fn remove(&self, entry: &MaildirEntry) -> io::Result<()> {
+        fs::remove_file(&entry.path)
+    }
+
+    fn entry(&self, subdir: Subdir, name: &str) -> MaildirEntry {
+        let (id, info) = match name.find(INFO_SEPARATOR) {
+            Some(i) => (&name[..i], Some(name[i + 1..].to_owned())),
+            None => (name, None),
+        };
+        MaildirEntry {
+            id: id.to_owned(),
+            info,
+            subdir,
+            path: self.path.join(subdir.name()).join(name),
+        }
+    }
+}
+
+/// A name of the form `seconds.MmicrosecondsPpidQcount.hostname`, as
+/// recommended by the Maildir specification. `count` distinguishes
+/// deliveries by this process within the same microsecond.
+fn unique_name() -> String {
+    static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        hostname()
+    )
+}
+
+fn hostname() -> String {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return "localhost".to_owned();
+    }
+    // The specification requires these to be escaped, since they would
+    // otherwise break up the file name.
+    name.replace('/', "\\057").replace(':', "\\072")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flags, Maildir, Subdir};
+    use crate::parse::email::parse_message;
+
+    #[test]
+    fn test_flags() {
+        let flags = Flags::from_info("2,SRa").unwrap();
+        assert!(flags.contains(Flags::SEEN) && flags.contains(Flags::REPLIED));
+        assert!(!flags.contains(Flags::TRASHED));
+        assert_eq!(flags.to_string(), "2,RSa");
+        assert!(Flags::from_info("1,experimental").is_none());
+
+        let mut flags = Flags::default();
+        assert!(flags.insert(Flags::DRAFT));
+        assert!(!flags.insert(','));
+        assert_eq!(flags.to_string(), "2,D");
+    }
+
+    #[test]
+    fn test_deliver_and_flag() {
+        let dir = std::env::temp_dir().join(format!("bmail-maildir-{}", std::process::id()));
+        let maildir = Maildir::create(&dir).unwrap();
+        let first = maildir.deliver(b"Subject: one\r\n\r\nFirst\r\n").unwrap();
+        let second = maildir.deliver(b"Subject: two\r\n\r\nSecond\r\n").unwrap();
+        assert_ne!(first.id(), second.id());
+        assert_eq!(
+            std::fs::read(first.path()).unwrap(),
+            b"Subject: one\n\nFirst\n"
+        );
+        assert!(maildir
+            .path()
+            .join("tmp")
+            .read_dir()
+            .unwrap()
+            .next()
+            .is_none());
+        assert_eq!(maildir.list(Subdir::New).unwrap().len(), 2);
+
+        let mut flags = first.flags();
+        flags.insert(Flags::SEEN);
+        flags.insert(Flags::FLAGGED);
+        let seen = maildir.set_flags(&first, &flags).unwrap();
+        assert_eq!(seen.subdir(), Subdir::Cur);
+        assert_eq!(seen.id(), first.id());
+        assert_eq!(seen.info(), Some("2,FS"));
+        assert!(!first.path().exists());
+
+        let cur = maildir.list(Subdir::Cur).unwrap();
+        assert_eq!(cur, vec![seen.clone()]);
+        assert_eq!(cur[0].flags(), flags);
+        let data = maildir.find(first.id()).unwrap().unwrap().read().unwrap();
+        let message = parse_message(&data).unwrap();
+        assert!(message.header_field("Subject").is_some());
+
+        let unseen = maildir.mark_new(&seen).unwrap();
+        assert_eq!(unseen.info(), None);
+        assert_eq!(maildir.list(Subdir::New).unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
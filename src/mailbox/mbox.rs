@@ -0,0 +1,559 @@
+//! Reading and writing mbox files.
+//!
+//! Every message in an mbox file is preceded by a separator line of the form
+//! `From sender date`, and followed by an empty line. The variants differ in
+//! how they prevent lines of the body that look like separators from being
+//! mistaken for them:
+//!
+//! * mboxo quotes lines starting with `From ` as `>From `. This is not
+//!   reversible, so such lines are returned as they appear in the file.
+//! * mboxrd quotes lines matching `>*From ` by prepending another `>`,
+//!   and unquoting removes it again.
+//! * mboxcl quotes like mboxo, and additionally records the size of each
+//!   body in a `Content-Length` field.
+//! * mboxcl2 does not quote at all, and relies on `Content-Length`.
+//!
+//! For the Content-Length variants, a missing or inconsistent `Content-Length`
+//! causes us to fall back to looking for the next separator line.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use crate::error::EmailError;
+use crate::headers::render::addr_spec;
+use crate::headers::HeaderFieldInner;
+use crate::parse::email::parse_message;
+use crate::smtp::envelope::ReversePath;
+use crate::{ByteStr, Message, SmtpEnvelope};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MboxFormat {
+    Mboxo,
+    Mboxrd,
+    Mboxcl,
+    Mboxcl2,
+}
+
+impl MboxFormat {
+    fn uses_content_length(self) -> bool {
+        matches!(self, MboxFormat::Mboxcl | MboxFormat::Mboxcl2)
+    }
+}
+
+#[derive(Debug)]
+pub enum MboxError {
+    /// The data at `offset` should have been a `From ` separator line, but isn't.
+    MissingFromLine { offset: usize },
+}
+
+/// The `From sender date` line that precedes a message.
+#[derive(Clone, Debug)]
+pub struct FromLine<'a> {
+    /// The envelope sender, e.g. `MAILER-DAEMON` or `user@example.com`.
+    pub sender: &'a ByteStr,
+    /// The rest of the line; normally an `asctime`-style date,
+    /// possibly followed by other information.
+    pub date: &'a ByteStr,
+}
+
+impl<'a> FromLine<'a> {
+    fn parse(line: &'a [u8]) -> Option<Self> {
+        let rest = line.strip_prefix(b"From ")?;
+        let rest = trim_start(rest);
+        let sender_end = rest
+            .iter()
+            .position(|ch| ch.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let (sender, date) = rest.split_at(sender_end);
+        Some(Self {
+            sender: ByteStr::from_slice(sender),
+            date: ByteStr::from_slice(trim_start(date)),
+        })
+    }
+
+    /// Interpret the date, which should look like `Mon Nov 24 18:22:48 1986`.
+    /// Anything after the year (e.g., a time zone) is ignored.
+    pub fn date_time(&self) -> Option<chrono::NaiveDateTime> {
+        let date = std::str::from_utf8(&self.date.0).ok()?;
+        let fields: Vec<_> = date.split_ascii_whitespace().take(5).collect();
+        chrono::NaiveDateTime::parse_from_str(&fields.join(" "), "%a %b %d %H:%M:%S %Y").ok()
+    }
+}
+
+/// A message read from an mbox file.
+#[derive(Clone, Debug)]
+pub struct MboxEntry<'a> {
+    pub from_line: FromLine<'a>,
+    /// The message, unquoted according to the mbox variant, and with its
+    /// line endings converted to CRLF.
+    pub data: Cow<'a, [u8]>,
+}
+
+impl<'a> MboxEntry<'a> {
+    pub fn message(&self) -> Result<Message<'_>, EmailError<'_>> {
+        parse_message(&self.data)
+    }
+}
+
+/// Iterator over the messages in an mbox file.
+pub struct MboxReader<'a> {
+    input: &'a [u8],
+    offset: usize,
+    format: MboxFormat,
+}
+
+impl<'a> MboxReader<'a> {
+    pub fn new(input: &'a [u8], format: MboxFormat) -> Self {
+        Self {
+            input,
+            offset: 0,
+            format,
+        }
+    }
+}
+
+impl<'a> Iterator for MboxReader<'a> {
+    type Item = Result<MboxEntry<'a>, MboxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.input[self.offset..];
+        if rest.is_empty() {
+            return None;
+        }
+        let (line, after) = split_line(rest);
+        let from_line = match FromLine::parse(line) {
+            Some(from_line) => from_line,
+            None => {
+                let offset = self.offset;
+                self.offset = self.input.len();
+                return Some(Err(MboxError::MissingFromLine { offset }));
+            }
+        };
+        let body_start = rest.len() - after.len();
+        let (message_end, next_start) = if self.format.uses_content_length() {
+            content_length_bounds(after)
+        } else {
+            None
+        }
+        .unwrap_or_else(|| separator_bounds(after));
+        self.offset += body_start + next_start;
+
+        let data = prepare(&after[..message_end], self.format == MboxFormat::Mboxrd);
+        Some(Ok(MboxEntry { from_line, data }))
+    }
+}
+
+/// Appends messages to an mbox file.
+pub struct MboxWriter<W> {
+    out: W,
+    format: MboxFormat,
+}
+
+impl<W: Write> MboxWriter<W> {
+    pub fn new(out: W, format: MboxFormat) -> Self {
+        Self { out, format }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Append `message`. The sender on the separator line is the envelope sender
+    /// if `envelope` is given, and otherwise comes from the Return-Path or From
+    /// field. The date comes from the Date field, or is the current time if there
+    /// is none.
+    pub fn append(&mut self, message: &Message, envelope: Option<&SmtpEnvelope>) -> io::Result<()> {
+        let sender = match envelope {
+            Some(envelope) => match &envelope.from {
+                ReversePath::Null => None,
+                ReversePath::Address(address) => Some(address.to_string().into_bytes()),
+            },
+            None => header_sender(message),
+        };
+        let date = message
+            .header()
+            .iter()
+            .find_map(|hf| match hf.inner() {
+                HeaderFieldInner::OrigDate(date_time) => Some(date_time.naive_utc()),
+                _ => None,
+            })
+            .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+        self.append_bytes(sender.as_deref().unwrap_or(b""), date, &message.to_bytes())
+    }
+
+    /// Append a message that has already been serialized. Line endings are
+    /// converted to LF, and lines that could be mistaken for separators are
+    /// quoted according to the mbox variant. An empty `sender` (e.g., for a
+    /// bounce) is written as `MAILER-DAEMON`.
+    pub fn append_bytes(
+        &mut self,
+        sender: &[u8],
+        date: chrono::NaiveDateTime,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let sender = if sender.is_empty() {
+            &b"MAILER-DAEMON"[..]
+        } else {
+            sender
+        };
+        let (header, body) = split_header(data);
+
+        let mut body_out = Vec::with_capacity(body.len());
+        for line in Lines(body) {
+            self.write_line(line, &mut body_out);
+        }
+        let mut header_out = Vec::with_capacity(header.len() + 32);
+        for line in Lines(header) {
+            let name = line.split(|ch| *ch == b':').next().unwrap_or_default();
+            let is_content_length =
+                name.len() < line.len() && name.eq_ignore_ascii_case(b"Content-Length");
+            if !(self.format.uses_content_length() && is_content_length) {
+                self.write_line(line, &mut header_out);
+            }
+        }
+        if self.format.uses_content_length() {
+            writeln!(header_out, "Content-Length: {}", body_out.len())?;
+        }
+
+        self.out.write_all(b"From ")?;
+        self.out.write_all(sender)?;
+        writeln!(self.out, " {}", date.format("%a %b %e %H:%M:%S %Y"))?;
+        self.out.write_all(&header_out)?;
+        self.out.write_all(b"\n")?;
+        self.out.write_all(&body_out)?;
+        self.out.write_all(b"\n")
+    }
+
+    fn write_line(&self, line: &[u8], out: &mut Vec<u8>) {
+        let quote = match self.format {
+            MboxFormat::Mboxo | MboxFormat::Mboxcl => line.starts_with(b"From "),
+            MboxFormat::Mboxrd => trim_quotes(line).starts_with(b"From "),
+            MboxFormat::Mboxcl2 => false,
+        };
+        if quote {
+            out.push(b'>');
+        }
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+}
+
+fn header_sender(message: &Message) -> Option<Vec<u8>> {
+    if let Some(return_path) = message.header_field("Return-Path") {
+        // An address path has no unquoted whitespace, so only folding and the brackets go.
+        let path = return_path
+            .unfolded_value()
+            .0
+            .iter()
+            .copied()
+            .filter(|ch| !ch.is_ascii_whitespace() && *ch != b'<' && *ch != b'>')
+            .collect();
+        return Some(path);
+    }
+    message.header().iter().find_map(|hf| match hf.inner() {
+        HeaderFieldInner::From(mailboxes) => mailboxes
+            .iter()
+            .find_map(|mb| mb.addr_spec.as_ref())
+            .map(addr_spec),
+        _ => None,
+    })
+}
+
+/// Split a message into its header (without the empty line that ends it) and body.
+fn split_header(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    while pos < data.len() {
+        let (line, after) = split_line(&data[pos..]);
+        if line.is_empty() {
+            return (&data[..pos], after);
+        }
+        pos = data.len() - after.len();
+    }
+    (data, &[])
+}
+
+/// The lines of `input`, without their terminators.
+struct Lines<'a>(&'a [u8]);
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let (line, after) = split_line(self.0);
+        self.0 = after;
+        Some(line)
+    }
+}
+
+fn trim_start(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+/// Split off the first line, returning it without its terminator, and the rest.
+fn split_line(input: &[u8]) -> (&[u8], &[u8]) {
+    match input.iter().position(|ch| *ch == b'\n') {
+        Some(lf) => {
+            let line = &input[..lf];
+            (line.strip_suffix(b"\r").unwrap_or(line), &input[lf + 1..])
+        }
+        None => (input, &[]),
+    }
+}
+
+fn newline_len(input: &[u8]) -> usize {
+    if input.starts_with(b"\r\n") {
+        2
+    } else if input.starts_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+/// Find the end of the message beginning at the start of `input` by looking
+/// for the next separator line. Returns the end of the message (excluding the
+/// empty line preceding the separator), and the start of the separator.
+fn separator_bounds(input: &[u8]) -> (usize, usize) {
+    let mut pos = 0;
+    let mut prev_empty = false;
+    let mut prev_newline = 0;
+    while pos < input.len() {
+        let (line, after) = split_line(&input[pos..]);
+        if prev_empty && pos > 0 && line.starts_with(b"From ") {
+            return (pos - prev_newline, pos);
+        }
+        prev_empty = line.is_empty();
+        prev_newline = input.len() - pos - after.len() - line.len();
+        pos = input.len() - after.len();
+    }
+    // The last message is normally also followed by an empty line.
+    let end = if prev_empty { pos - prev_newline } else { pos };
+    (end, input.len())
+}
+
+/// Like `separator_bounds`, but using the Content-Length field
+/// in the message header. Returns `None` if it is missing, or doesn't
+/// point at the end of the file or an empty line followed by a separator.
+fn content_length_bounds(input: &[u8]) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    let mut content_length = None;
+    loop {
+        if pos >= input.len() {
+            return None;
+        }
+        let (line, after) = split_line(&input[pos..]);
+        pos = input.len() - after.len();
+        if line.is_empty() {
+            break;
+        }
+        let colon = match line.iter().position(|ch| *ch == b':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        if line[..colon].eq_ignore_ascii_case(b"Content-Length") {
+            let value = std::str::from_utf8(&line[colon + 1..]).ok()?;
+            content_length = Some(value.trim().parse::<usize>().ok()?);
+        }
+    }
+    let message_end = pos.checked_add(content_length?)?;
+    if message_end > input.len() {
+        return None;
+    }
+    let after = &input[message_end..];
+    let next_start = message_end + newline_len(after);
+    if next_start == input.len() || input[next_start..].starts_with(b"From ") {
+        Some((message_end, next_start))
+    } else {
+        None
+    }
+}
+
+fn is_quoted_from(line: &[u8]) -> bool {
+    let unquoted = trim_quotes(line);
+    unquoted.len() < line.len() && unquoted.starts_with(b"From ")
+}
+
+fn trim_quotes(mut line: &[u8]) -> &[u8] {
+    while let Some(rest) = line.strip_prefix(b">") {
+        line = rest;
+    }
+    line
+}
+
+/// Convert line endings to CRLF, and if `unquote` is set,
+/// remove one level of mboxrd quoting.
+fn prepare(message: &[u8], unquote: bool) -> Cow<'_, [u8]> {
+    let mut needs_change = false;
+    let mut pos = 0;
+    while pos < message.len() {
+        let (line, after) = split_line(&message[pos..]);
+        let has_lf = message.len() - pos - after.len() > line.len();
+        let has_crlf = message[pos + line.len()..].starts_with(b"\r\n");
+        if (has_lf && !has_crlf) || (unquote && is_quoted_from(line)) {
+            needs_change = true;
+            break;
+        }
+        pos = message.len() - after.len();
+    }
+    if !needs_change {
+        return Cow::Borrowed(message);
+    }
+
+    let mut out = Vec::with_capacity(message.len() + message.len() / 32);
+    let mut pos = 0;
+    while pos < message.len() {
+        let (line, after) = split_line(&message[pos..]);
+        let has_lf = message.len() - pos - after.len() > line.len();
+        let line = if unquote && is_quoted_from(line) {
+            &line[1..]
+        } else {
+            line
+        };
+        out.extend_from_slice(line);
+        if has_lf {
+            out.extend_from_slice(b"\r\n");
+        }
+        pos = message.len() - after.len();
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MboxFormat, MboxReader, MboxWriter};
+    use crate::parse::email::parse_message;
+    use crate::Body;
+
+    #[test]
+    fn test_mboxrd() {
+        let input = b"From alice@example.com Mon Nov 24 18:22:48 1986\n\
+                      Subject: one\n\
+                      \n\
+                      >From here\n\
+                      >>From there\n\
+                      \n\
+                      From bob@example.com  Fri Nov  4 09:01:02 2011 +0000\n\
+                      Subject: two\n\
+                      \n\
+                      From-ish\n\
+                      \n";
+        let entries: Vec<_> = MboxReader::new(input, MboxFormat::Mboxrd)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].from_line.sender.0, *b"alice@example.com");
+        assert_eq!(
+            entries[0].from_line.date_time().unwrap().to_string(),
+            "1986-11-24 18:22:48"
+        );
+        assert_eq!(
+            &*entries[0].data,
+            b"Subject: one\r\n\r\nFrom here\r\n>From there\r\n"
+        );
+        assert_eq!(entries[1].from_line.sender.0, *b"bob@example.com");
+        assert_eq!(
+            entries[1].from_line.date_time().unwrap().to_string(),
+            "2011-11-04 09:01:02"
+        );
+        match entries[1].message().unwrap().body() {
+            Body::SimpleText(text) => assert_eq!(text, "From-ish\r\n"),
+            _ => panic!("expected text body"),
+        }
+    }
+
+    #[test]
+    fn test_mboxcl2() {
+        let input = b"From alice@example.com Mon Nov 24 18:22:48 1986\n\
+                      Content-Length: 21\n\
+                      \n\
+                      Hello\n\
+                      \n\
+                      From the desk\n\
+                      \n\
+                      From bob@example.com Mon Nov 24 18:22:49 1986\n\
+                      Content-Length: 999\n\
+                      \n\
+                      Bad length\n";
+        let entries: Vec<_> = MboxReader::new(input, MboxFormat::Mboxcl2)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            &*entries[0].data,
+            b"Content-Length: 21\r\n\r\nHello\r\n\r\nFrom the desk\r\n"
+        );
+        assert_eq!(
+            &*entries[1].data,
+            b"Content-Length: 999\r\n\r\nBad length\r\n"
+        );
+
+        let mut reader = MboxReader::new(b"Subject: oops\n", MboxFormat::Mboxo);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_write_read() {
+        let first = b"From: Brennan Vincent <brennan@umanwizard.com>\r\n\
+                      Date: Mon, 1 Mar 2021 12:00:00 +0100\r\n\
+                      \r\n\
+                      From here\r\n\
+                      >From there\r\n\
+                      \r\n\
+                      From everywhere\r\n";
+        let second = b"Content-Length: 3\r\n\r\nno newline";
+        for format in [
+            MboxFormat::Mboxo,
+            MboxFormat::Mboxrd,
+            MboxFormat::Mboxcl,
+            MboxFormat::Mboxcl2,
+        ]
+        .iter()
+        .copied()
+        {
+            let mut writer = MboxWriter::new(vec![], format);
+            writer.append(&parse_message(first).unwrap(), None).unwrap();
+            let date = chrono::NaiveDate::from_ymd_opt(2021, 3, 2)
+                .and_then(|d| d.and_hms_opt(3, 4, 5))
+                .unwrap();
+            writer.append_bytes(b"", date, second).unwrap();
+            let mbox = writer.into_inner();
+
+            let entries: Vec<_> = MboxReader::new(&mbox, format).map(Result::unwrap).collect();
+            assert_eq!(entries.len(), 2, "{:?}", format);
+            assert_eq!(entries[0].from_line.sender.0, *b"brennan@umanwizard.com");
+            assert_eq!(entries[0].from_line.date.0, *b"Mon Mar  1 11:00:00 2021");
+            assert_eq!(entries[1].from_line.sender.0, *b"MAILER-DAEMON");
+            assert_eq!(entries[1].from_line.date_time(), Some(date), "{:?}", format);
+            let (first_body, second_data): (&[u8], &[u8]) = match format {
+                MboxFormat::Mboxo => (
+                    b">From here\r\n>From there\r\n\r\n>From everywhere\r\n",
+                    b"Content-Length: 3\r\n\r\nno newline\r\n",
+                ),
+                MboxFormat::Mboxcl => (
+                    b">From here\r\n>From there\r\n\r\n>From everywhere\r\n",
+                    b"Content-Length: 11\r\n\r\nno newline\r\n",
+                ),
+                MboxFormat::Mboxrd => (
+                    b"From here\r\n>From there\r\n\r\nFrom everywhere\r\n",
+                    b"Content-Length: 3\r\n\r\nno newline\r\n",
+                ),
+                MboxFormat::Mboxcl2 => (
+                    b"From here\r\n>From there\r\n\r\nFrom everywhere\r\n",
+                    b"Content-Length: 11\r\n\r\nno newline\r\n",
+                ),
+            };
+            assert!(entries[0].data.ends_with(first_body), "{:?}", format);
+            assert_eq!(&*entries[1].data, second_data, "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! MH folders, as used by nmh.
+//!
+//! An MH folder is a directory in which each message is stored in a file
+//! named by its number. Named sets of messages, such as `unseen`, are listed
+//! in the `.mh_sequences` file of the folder, one per line:
+//!
+//! ```text
+//! unseen: 3-5 8
+//! cur: 2
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::{to_crlf, to_lf};
+use crate::Message;
+
+const SEQUENCES_FILE: &str = ".mh_sequences";
+
+/// The named sequences of a folder.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sequences(pub BTreeMap<String, BTreeSet<u32>>);
+
+impl Sequences {
+    /// Parse the contents of a `.mh_sequences` file. Returns `None` if it is
+    /// malformed. Numbers above `last`, the highest message in the folder,
+    /// are dropped, since there are no such messages.
+    pub fn parse(s: &str, last: u32) -> Option<Self> {
+        let mut sequences = Sequences::default();
+        let mut current: Option<&mut BTreeSet<u32>> = None;
+        for line in s.lines() {
+            let items = if line.starts_with([' ', '\t']) {
+                // A continuation of the previous sequence.
+                line
+            } else if line.is_empty() {
+                continue;
+            } else {
+                let colon = line.find(':')?;
+                let name = line[..colon].trim();
+                if name.is_empty() {
+                    return None;
+                }
+                current = Some(sequences.0.entry(name.to_owned()).or_default());
+                &line[colon + 1..]
+            };
+            let set = current.as_mut()?;
+            for item in items.split_whitespace() {
+                match item.find('-') {
+                    Some(dash) => {
+                        let from: u32 = item[..dash].parse().ok()?;
+                        let to: u32 = item[dash + 1..].parse().ok()?;
+                        set.extend(from..=to.min(last));
+                    }
+                    None => {
+                        let message = item.parse().ok()?;
+                        if message <= last {
+                            set.insert(message);
+                        }
+                    }
+                }
+            }
+        }
+        sequences.0.retain(|_, set| !set.is_empty());
+        Some(sequences)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BTreeSet<u32>> {
+        self.0.get(name)
+    }
+
+    pub fn add(&mut self, name: &str, message: u32) {
+        self.0.entry(name.to_owned()).or_default().insert(message);
+    }
+
+    /// Remove `message` from the sequence `name`. Sequences that become empty
+    /// are dropped.
+    pub fn remove(&mut self, name: &str, message: u32) {
+        if let Some(set) = self.0.get_mut(name) {
+            set.remove(&message);
+            if set.is_empty() {
+                self.0.remove(name);
+            }
+        }
+    }
+
+    /// Remove `message` from every sequence.
+    pub fn remove_message(&mut self, message: u32) {
+        for set in self.0.values_mut() {
+            set.remove(&message);
+        }
+        self.0.retain(|_, set| !set.is_empty());
+    }
+}
+
+/// Formats the sequences as the contents of a `.mh_sequences` file,
+/// with consecutive messages collapsed into ranges.
+impl fmt::Display for Sequences {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, set) in self.0.iter() {
+            write!(f, "{}:", name)?;
+            let mut iter = set.iter().copied().peekable();
+            while let Some(first) = iter.next() {
+                let mut last = first;
+                while iter.peek() == Some(&(last + 1)) {
+                    last = iter.next().unwrap();
+                }
+                if first == last {
+                    write!(f, " {}", first)?;
+                } else {
+                    write!(f, " {}-{}", first, last)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MhFolder {
+    path: PathBuf,
+}
+
+impl MhFolder {
+    /// Open the folder at `path`, which is not checked for existence.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Create the folder at `path` (and its parents) if it doesn't exist yet.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let folder = Self::new(path);
+        fs::create_dir_all(&folder.path)?;
+        Ok(folder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn message_path(&self, message: u32) -> PathBuf {
+        self.path.join(message.to_string())
+    }
+
+    /// The numbers of the messages in the folder, in ascending order.
+    /// Files whose names are not numbers (including messages removed by
+    /// `rmm`, which are renamed to `,N`) are ignored.
+    pub fn list(&self) -> io::Result<Vec<u32>> {
+        let mut messages = vec![];
+        for dirent in fs::read_dir(&self.path)? {
+            let dirent = dirent?;
+            let number = dirent
+                .file_name()
+                .to_str()
+                .filter(|name| name.bytes().all(|ch| ch.is_ascii_digit()))
+                .and_then(|name| name.parse().ok());
+            match number {
+                Some(number) if number > 0 && dirent.file_type()?.is_file() => {
+                    messages.push(number)
+                }
+                _ => {}
+            }
+        }
+        messages.sort_unstable();
+        Ok(messages)
+    }
+
+    /// Read the contents of a message, with line endings converted to CRLF.
+    /// The result can be parsed with `parse_message`.
+    pub fn read(&self, message: u32) -> io::Result<Vec<u8>> {
+        Ok(to_crlf(&fs::read(self.message_path(message))?))
+    }
+
+    /// Add `data` as a new message, numbered one more than the highest
+    /// existing one, and add it to the `unseen` sequence. CRLF line endings
+    /// are stored as LF.
+    pub fn add(&self, data: &[u8]) -> io::Result<u32> {
+        let mut number = self.list()?.last().copied().unwrap_or(0) + 1;
+        let mut file = loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.message_path(number))
+            {
+                Ok(file) => break file,
+                // Someone else added a message concurrently.
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        file.write_all(&to_lf(data))?;
+        file.sync_all()?;
+
+        let mut sequences = self.sequences()?;
+        sequences.add("unseen", number);
+        self.set_sequences(&sequences)?;
+        Ok(number)
+    }
+
+    /// Serialize `message` and add it like `add`.
+    pub fn add_message(&self, message: &Message) -> io::Result<u32> {
+        self.add(&message.to_bytes())
+    }
+
+    /// Delete a message and remove it from all sequences.
+    pub fn remove(&self, message: u32) -> io::Result<()> {
+        fs::remove_file(self.message_path(message))?;
+        let mut sequences = self.sequences()?;
+        sequences.remove_message(message);
+        self.set_sequences(&sequences)
+    }
+
+    /// The sequences of the folder. A missing `.mh_sequences` file means
+    /// there are none.
+    pub fn sequences(&self) -> io::Result<Sequences> {
+        let last = self.list()?.last().copied().unwrap_or(0);
+        let contents = match fs::read_to_string(self.path.join(SEQUENCES_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Sequences::default()),
+            Err(e) => return Err(e),
+        };
+        Sequences::parse(&contents, last).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed .mh_sequences file")
+        })
+    }
+
+    /// Replace the `.mh_sequences` file.
+    pub fn set_sequences(&self, sequences: &Sequences) -> io::Result<()> {
+        // Write a temporary file and rename it, so that readers never
+        // see a partially written file.
+        let tmp_path = self
+            .path
+            .join(format!("{}.{}", SEQUENCES_FILE, std::process::id()));
+        fs::write(&tmp_path, sequences.to_string())?;
+        fs::rename(&tmp_path, self.path.join(SEQUENCES_FILE))
+    }
+
+    /// Renumber the messages consecutively from 1, keeping their order, like
+    /// `folder -pack`. Sequences are updated to match. Returns the old and new
+    /// numbers of the messages that were moved.
+    pub fn pack(&self) -> io::Result<BTreeMap<u32, u32>> {
+        // Read the sequences first, while they still refer to messages that
+        // exist.
+        let mut sequences = self.sequences()?;
+        let mut renumbered = BTreeMap::new();
+        // Messages only ever move to lower numbers, and the list is
+        // ascending, so a rename never overwrites a message still to be moved.
+        for (i, old) in self.list()?.into_iter().enumerate() {
+            let new = i as u32 + 1;
+            if new != old {
+                fs::rename(self.message_path(old), self.message_path(new))?;
+                renumbered.insert(old, new);
+            }
+        }
+        if !renumbered.is_empty() {
+            for set in sequences.0.values_mut() {
+                *set = set
+                    .iter()
+                    .map(|n| renumbered.get(n).copied().unwrap_or(*n))
+                    .collect();
+            }
+            self.set_sequences(&sequences)?;
+        }
+        Ok(renumbered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MhFolder, Sequences};
+    use crate::parse::email::parse_message;
+
+    #[test]
+    fn test_sequences() {
+        let sequences = Sequences::parse("unseen: 1-3 5\n 6 9\ncur: 2\n", 9).unwrap();
+        assert_eq!(sequences.get("unseen").unwrap().len(), 6);
+        assert_eq!(sequences.to_string(), "cur: 2\nunseen: 1-3 5-6 9\n");
+        assert!(Sequences::parse("unseen 1-3\n", 9).is_none());
+        assert!(Sequences::parse("unseen: 1-x\n", 9).is_none());
+        let sequences = Sequences::parse("cur: 1-4294967295 7\n", 3).unwrap();
+        assert_eq!(sequences.to_string(), "cur: 1-3\n");
+    }
+
+    #[test]
+    fn test_add_and_pack() {
+        let dir = std::env::temp_dir().join(format!("bmail-mh-{}", std::process::id()));
+        let folder = MhFolder::create(&dir).unwrap();
+        for subject in &["one", "two", "three"] {
+            let data = format!("Subject: {}\r\n\r\nBody\r\n", subject);
+            folder.add(data.as_bytes()).unwrap();
+        }
+        assert_eq!(
+            std::fs::read(folder.message_path(1)).unwrap(),
+            b"Subject: one\n\nBody\n"
+        );
+        assert_eq!(folder.list().unwrap(), vec![1, 2, 3]);
+        folder.remove(2).unwrap();
+        let mut sequences = folder.sequences().unwrap();
+        sequences.add("flagged", 3);
+        folder.set_sequences(&sequences).unwrap();
+
+        let renumbered = folder.pack().unwrap();
+        assert_eq!(renumbered.into_iter().collect::<Vec<_>>(), vec![(3, 2)]);
+        assert_eq!(folder.list().unwrap(), vec![1, 2]);
+        let sequences = folder.sequences().unwrap();
+        assert_eq!(sequences.to_string(), "flagged: 2\nunseen: 1-2\n");
+
+        let data = folder.read(2).unwrap();
+        let message = parse_message(&data).unwrap();
+        let subject = message.header_field("Subject").unwrap();
+        assert_eq!(subject.unfolded_value().0, *b" three");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
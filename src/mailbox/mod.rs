@@ -0,0 +1,36 @@
+//! Collections of messages stored on disk.
+//!
+//! Messages are stored with LF line endings, as is usual on Unix, whereas
+//! `parse_message` and `Message::to_bytes` use CRLF; the line endings are
+//! converted when messages are read and written.
+
+pub mod maildir;
+pub mod mbox;
+pub mod mh;
+
+/// Convert LF line endings to CRLF, for reading a stored message. Lines
+/// already ending in CRLF are left alone.
+pub(crate) fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 32);
+    let mut prev = None;
+    for ch in data.iter().copied() {
+        if ch == b'\n' && prev != Some(b'\r') {
+            out.push(b'\r');
+        }
+        out.push(ch);
+        prev = Some(ch);
+    }
+    out
+}
+
+/// Convert CRLF line endings to LF, for storing a message.
+pub(crate) fn to_lf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, ch) in data.iter().copied().enumerate() {
+        if ch == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        out.push(ch);
+    }
+    out
+}
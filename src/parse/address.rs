@@ -42,7 +42,7 @@ fn domain_literal(input: &[u8]) -> IResult<&[u8], ByteString, VerboseError<&[u8]
 
 pub fn domain(input: &[u8]) -> IResult<&[u8], Domain, VerboseError<&[u8]>> {
     alt((
-        map(dot_atom, Domain::Name),
+        map(dot_atom, |name| Domain::Name(Cow::Borrowed(name))),
         map(domain_literal, Domain::Literal),
     ))(input)
 }
@@ -233,9 +233,9 @@ where
                          parts,
                          epilogue,
                      }| Body::Multipart {
-                        preamble,
+                        preamble: Cow::Borrowed(preamble),
                         parts,
-                        epilogue,
+                        epilogue: Cow::Borrowed(epilogue),
                     },
                 ))
                 .parse(input)
@@ -335,7 +335,59 @@ where
             (None, None, encoding, false) => MimeParseControl::SimpleBinary { encoding },
         };
 
-        let (i, body) = nom::Parser::into(body(mime_ctl)).parse(i)?;
-        Ok((i, Message::new(hfs, ctype_idx, body, input.len())))
+        let (rest, body) = nom::Parser::into(body(mime_ctl)).parse(i)?;
+        let raw_body = &i[..i.len() - rest.len()];
+        let body_lines = raw_body.iter().filter(|ch| **ch == b'\n').count()
+            + (!raw_body.is_empty() && !raw_body.ends_with(b"\n")) as usize;
+        Ok((
+            rest,
+            Message::new(
+                hfs,
+                ctype_idx,
+                body,
+                input.len(),
+                raw_body.len(),
+                body_lines,
+                &input[..input.len() - rest.len()],
+            ),
+        ))
+    }
+}
+
+/// Parse a complete message (e.g., one read from a file), which must
+/// use CRLF line endings.
+pub fn parse_message(input: &[u8]) -> Result<Message<'_>, EmailError<'_>> {
+    match all_consuming(message()).parse(input) {
+        Ok((_, message)) => Ok(message),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e),
+        Err(nom::Err::Incomplete(_)) => unreachable!("complete parsers never return Incomplete"),
+    }
+}
+
+#[test]
+fn test_into_owned() {
+    let owned = {
+        let input = "From: Brennan Vincent <brennan@umanwizard.com>\r\n\
+                     Content-Type: multipart/mixed; boundary=xyz\r\n\
+                     \r\n\
+                     preamble\r\n\
+                     --xyz\r\n\
+                     \r\n\
+                     Hello!\r\n\
+                     --xyz--\r\n"
+            .as_bytes()
+            .to_vec();
+        let (_, message) = all_consuming(message()).parse(&input).unwrap();
+        message.into_owned()
+    };
+    assert_eq!(owned.header()[0].name().0, *b"From");
+    match owned.body() {
+        Body::Multipart {
+            preamble, parts, ..
+        } => {
+            assert_eq!(&**preamble, b"preamble\r\n");
+            assert_eq!(parts.len(), 1);
+        }
+        _ => panic!("expected multipart body"),
     }
 }
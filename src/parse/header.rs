@@ -7,6 +7,7 @@ use nom::character::complete::crlf;
 use nom::combinator::consumed;
 use nom::combinator::map;
 use nom::combinator::opt;
+use nom::combinator::peek;
 
 use nom::combinator::value;
 
@@ -24,10 +25,10 @@ use crate::ByteStr;
 use super::address::{address, mailbox};
 use super::cfws;
 use super::date_time::date_time;
-use super::mime::{content_transfer_encoding, content_type};
+use super::mime::{content_disposition, content_transfer_encoding, content_type};
 use super::unstructured;
 
-fn is_ftext(ch: u8) -> bool {
+pub(crate) fn is_ftext(ch: u8) -> bool {
     (33 <= ch && ch <= 57) || (59 <= ch && ch <= 126)
 }
 
@@ -52,6 +53,8 @@ fn header_name(input: &[u8]) -> IResult<&[u8], HeaderFieldKind, VerboseError<&[u
         ContentType
     } else if val.eq_ignore_ascii_case(b"content-transfer-encoding") {
         ContentTransferEncoding
+    } else if val.eq_ignore_ascii_case(b"content-disposition") {
+        ContentDisposition
     } else {
         Unstructured
     };
@@ -118,6 +121,13 @@ fn header_inner(
             HeaderFieldInner::ContentTransferEncoding,
         )(i)
         .map_err(nom::Err::convert),
+        // Require the whole value to parse, so that a malformed one (e.g. an
+        // unquoted filename with a space) falls back to unstructured.
+        ContentDisposition => map(
+            terminated(content_disposition, peek(crlf)),
+            HeaderFieldInner::ContentDisposition,
+        )(i)
+        .map_err(nom::Err::convert),
     }
 }
 
@@ -142,3 +152,26 @@ fn test_from() {
     let hs = complete(header_field)(test.as_bytes());
     eprintln!("{:?}", hs);
 }
+
+#[test]
+fn test_malformed_content_disposition() {
+    use nom::combinator::complete;
+
+    let input = b"Content-Disposition: attachment; filename=my file.pdf\r\n";
+    let (_, hf) = complete(header_field)(input).unwrap();
+    match hf.inner() {
+        HeaderFieldInner::Unstructured(value) => {
+            assert_eq!(value.0, b" attachment; filename=my file.pdf".to_vec())
+        }
+        other => panic!("expected an unstructured value, got {:?}", other),
+    }
+
+    let input = b"Content-Disposition: attachment; filename=\"my file.pdf\"\r\n";
+    let (_, hf) = complete(header_field)(input).unwrap();
+    match hf.inner() {
+        HeaderFieldInner::ContentDisposition(cd) => {
+            assert_eq!(cd.parameters["filename"], "my file.pdf")
+        }
+        other => panic!("expected a Content-Disposition, got {:?}", other),
+    }
+}
@@ -4,7 +4,7 @@ use std::collections::HashMap;
 use super::cfws;
 use super::is_vchar;
 use super::quoted_string;
-use crate::headers::mime::{ContentTransferEncoding, ContentType};
+use crate::headers::mime::{ContentDisposition, ContentTransferEncoding, ContentType};
 use crate::{ByteStr, ByteString};
 
 use nom::error::VerboseError;
@@ -41,7 +41,7 @@ fn subtype(input: &[u8]) -> IResult<&[u8], &ByteStr, VerboseError<&[u8]>> {
     map(take_while1(is_content_type_ch), ByteStr::from_slice)(input)
 }
 
-fn is_token_ch(ch: u8) -> bool {
+pub(crate) fn is_token_ch(ch: u8) -> bool {
     is_vchar(ch) && !b"()<>@,;:\\\"/[]?=".iter().any(|ch2| *ch2 == ch)
 }
 
@@ -79,25 +79,78 @@ pub(crate) fn content_transfer_encoding(
     )(input)
 }
 
+fn parameters(input: &[u8]) -> IResult<&[u8], HashMap<String, String>, VerboseError<&[u8]>> {
+    fold_many0(
+        preceded(
+            tuple((opt(cfws), tag(b";"))),
+            opt(preceded(opt(cfws), parameter)),
+        ),
+        HashMap::new(),
+        |mut params, maybe| {
+            // This is `opt` because stuff like "Content-Type: text/plain;;" has been seen...
+            if let Some((k, v)) = maybe {
+                params.insert(k, v);
+            }
+            params
+        },
+    )(input)
+    .map(|(input, params)| (input, decode_extended_parameters(params)))
+}
+
+/// Replace each RFC 2231 extended parameter (`name*=charset'language'value`)
+/// with the plain one it encodes, which it takes precedence over.
+/// Continuations (`name*0*=...`) aren't joined, and are left as they are.
+fn decode_extended_parameters(mut params: HashMap<String, String>) -> HashMap<String, String> {
+    let extended: Vec<String> = params
+        .keys()
+        .filter(|k| k.ends_with('*') && k.matches('*').count() == 1)
+        .cloned()
+        .collect();
+    for k in extended {
+        if let Some(v) = decode_extended_value(&params[&k]) {
+            params.remove(&k);
+            params.insert(k[..k.len() - 1].to_string(), v);
+        }
+    }
+    params
+}
+
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = encoded.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    let encoding = encoding_rs::Encoding::for_label(charset.as_bytes())?;
+    let (text, malformed) = encoding.decode_without_bom_handling(&bytes);
+    if malformed {
+        None
+    } else {
+        Some(text.into_owned())
+    }
+}
+
 pub(crate) fn content_type(input: &[u8]) -> IResult<&[u8], ContentType<'_>, VerboseError<&[u8]>> {
     let (input, (r#type, _, subtype, parameters, _)) = tuple((
         preceded(opt(cfws), r#type),
         preceded(opt(cfws), tag(b"/")),
         preceded(opt(cfws), subtype),
-        fold_many0(
-            preceded(
-                tuple((opt(cfws), tag(b";"))),
-                opt(preceded(opt(cfws), parameter)),
-            ),
-            HashMap::new(),
-            |mut params, maybe| {
-                // This is `opt` because stuff like "Content-Type: text/plain;;" has been seen...
-                if let Some((k, v)) = maybe {
-                    params.insert(k, v);
-                }
-                params
-            },
-        ),
+        parameters,
         tuple((
             opt(tag(b";")), // [RFC] seen in the wild: trailing semicolon
             opt(cfws),
@@ -107,8 +160,27 @@ pub(crate) fn content_type(input: &[u8]) -> IResult<&[u8], ContentType<'_>, Verb
     Ok((
         input,
         ContentType {
-            r#type,
-            subtype,
+            r#type: Cow::Borrowed(r#type),
+            subtype: Cow::Borrowed(subtype),
+            parameters,
+        },
+    ))
+}
+
+// RFC 2183
+pub(crate) fn content_disposition(
+    input: &[u8],
+) -> IResult<&[u8], ContentDisposition<'_>, VerboseError<&[u8]>> {
+    let (input, (disposition, parameters, _)) = tuple((
+        preceded(opt(cfws), take_while1(is_token_ch)),
+        parameters,
+        tuple((opt(tag(b";")), opt(cfws))),
+    ))(input)?;
+
+    Ok((
+        input,
+        ContentDisposition {
+            disposition: Cow::Borrowed(ByteStr::from_slice(disposition)),
             parameters,
         },
     ))
@@ -137,7 +137,7 @@ fn comment(input: &[u8]) -> IResult<&[u8], (), VerboseError<&[u8]>> {
     )(input)
 }
 
-fn is_atext(ch: u8) -> bool {
+pub(crate) fn is_atext(ch: u8) -> bool {
     ch.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".iter().any(|ch2| *ch2 == ch)
 }
 
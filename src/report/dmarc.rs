@@ -0,0 +1,464 @@
+//! DMARC aggregate reports (RFC 7489 section 7.2): XML documents, normally
+//! sent gzipped or zipped as an attachment, that summarize how a receiver
+//! evaluated the mail it got from a domain.
+
+use std::io::{Cursor, Read};
+use std::net::IpAddr;
+
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use roxmltree::{Document, Node};
+use zip::ZipArchive;
+
+use super::{body_bytes, invalid_field, ReportError};
+use crate::auth::dmarc::{Alignment, Policy};
+use crate::auth::spf::Identity;
+use crate::auth::AuthResult;
+use crate::{Body, Message};
+
+/// The most a report may decompress to, so that a small attachment can't
+/// use up all the memory.
+const MAX_REPORT_SIZE: u64 = 64 << 20;
+
+/// The policy that was applied, as published by the domain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishedPolicy {
+    pub domain: String,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    pub policy: Policy,
+    pub subdomain_policy: Option<Policy>,
+    pub percent: u8,
+    pub failure_options: Option<String>,
+}
+
+/// Why the receiver didn't apply the policy, e.g. `forwarded` or `mailing_list`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverrideReason {
+    pub reason_type: String,
+    pub comment: Option<String>,
+}
+
+/// The result of checking one DKIM signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DkimAuthResult {
+    pub domain: String,
+    pub selector: Option<String>,
+    pub result: AuthResult,
+    pub human_result: Option<String>,
+}
+
+/// The result of checking SPF for one identity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpfAuthResult {
+    pub domain: String,
+    pub scope: Option<Identity>,
+    pub result: AuthResult,
+}
+
+/// The messages from one source with the same identifiers and results.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportRecord {
+    pub source_ip: IpAddr,
+    /// How many messages there were.
+    pub count: u64,
+    /// What the receiver did with them.
+    pub disposition: Policy,
+    /// Whether DKIM passed with an aligned domain, i.e. `Pass` or `Fail`.
+    pub dkim: AuthResult,
+    /// Whether SPF passed with an aligned domain.
+    pub spf: AuthResult,
+    pub reasons: Vec<OverrideReason>,
+    pub envelope_to: Option<String>,
+    pub envelope_from: Option<String>,
+    pub header_from: String,
+    pub dkim_results: Vec<DkimAuthResult>,
+    pub spf_results: Vec<SpfAuthResult>,
+}
+
+/// An aggregate report: the `feedback` document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregateReport {
+    /// The organization that sent the report.
+    pub org_name: String,
+    pub email: String,
+    pub extra_contact_info: Option<String>,
+    pub report_id: String,
+    /// The period the report covers.
+    pub begin: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub errors: Vec<String>,
+    pub policy: PublishedPolicy,
+    pub records: Vec<ReportRecord>,
+}
+
+/// Whether `node` is an element called `name`. Only the local name is
+/// compared, since later versions of the format have a namespace.
+fn is_element(node: Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|child| is_element(*child, name))
+}
+
+fn children<'a, 'i: 'a>(
+    node: Node<'a, 'i>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'i>> + 'a {
+    node.children()
+        .filter(move |child| is_element(*child, name))
+}
+
+/// The text of the child element called `name`, if it isn't empty.
+fn text(node: Node, name: &str) -> Option<String> {
+    let text = child(node, name)?.text()?.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_owned())
+    }
+}
+
+fn required_child<'a, 'i>(
+    node: Node<'a, 'i>,
+    name: &'static str,
+) -> Result<Node<'a, 'i>, ReportError> {
+    child(node, name).ok_or(ReportError::MissingField(name))
+}
+
+fn required_text(node: Node, name: &'static str) -> Result<String, ReportError> {
+    text(node, name).ok_or(ReportError::MissingField(name))
+}
+
+/// Parse the text of the child element called `name` with `parse`.
+fn parsed<T>(
+    node: Node,
+    name: &'static str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Option<T>, ReportError> {
+    match text(node, name) {
+        Some(value) => match parse(&value) {
+            Some(parsed) => Ok(Some(parsed)),
+            None => Err(invalid_field(name, &value)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn required_parsed<T>(
+    node: Node,
+    name: &'static str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<T, ReportError> {
+    parsed(node, name, parse)?.ok_or(ReportError::MissingField(name))
+}
+
+fn timestamp(value: &str) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(value.parse().ok()?, 0).single()
+}
+
+impl PublishedPolicy {
+    fn parse(node: Node) -> Result<Self, ReportError> {
+        Ok(PublishedPolicy {
+            domain: required_text(node, "domain")?,
+            dkim_alignment: parsed(node, "adkim", Alignment::parse)?.unwrap_or(Alignment::Relaxed),
+            spf_alignment: parsed(node, "aspf", Alignment::parse)?.unwrap_or(Alignment::Relaxed),
+            policy: required_parsed(node, "p", Policy::parse)?,
+            subdomain_policy: parsed(node, "sp", Policy::parse)?,
+            percent: parsed(node, "pct", |pct| {
+                pct.parse().ok().filter(|pct| *pct <= 100)
+            })?
+            .unwrap_or(100),
+            failure_options: text(node, "fo"),
+        })
+    }
+}
+
+impl ReportRecord {
+    fn parse(node: Node) -> Result<Self, ReportError> {
+        let row = required_child(node, "row")?;
+        let evaluated = required_child(row, "policy_evaluated")?;
+        let identifiers = required_child(node, "identifiers")?;
+        let auth_results = required_child(node, "auth_results")?;
+        let reasons = children(evaluated, "reason")
+            .map(|reason| {
+                Ok(OverrideReason {
+                    reason_type: required_text(reason, "type")?,
+                    comment: text(reason, "comment"),
+                })
+            })
+            .collect::<Result<_, ReportError>>()?;
+        let dkim_results = children(auth_results, "dkim")
+            .map(|dkim| {
+                Ok(DkimAuthResult {
+                    domain: required_text(dkim, "domain")?,
+                    selector: text(dkim, "selector"),
+                    result: required_parsed(dkim, "result", AuthResult::parse)?,
+                    human_result: text(dkim, "human_result"),
+                })
+            })
+            .collect::<Result<_, ReportError>>()?;
+        let spf_results = children(auth_results, "spf")
+            .map(|spf| {
+                let scope = parsed(spf, "scope", |scope| match scope {
+                    "mfrom" => Some(Identity::MailFrom),
+                    "helo" => Some(Identity::Helo),
+                    _ => None,
+                })?;
+                Ok(SpfAuthResult {
+                    domain: required_text(spf, "domain")?,
+                    scope,
+                    result: required_parsed(spf, "result", AuthResult::parse)?,
+                })
+            })
+            .collect::<Result<_, ReportError>>()?;
+        Ok(ReportRecord {
+            source_ip: required_parsed(row, "source_ip", |ip| ip.parse().ok())?,
+            count: required_parsed(row, "count", |count| count.parse().ok())?,
+            disposition: required_parsed(evaluated, "disposition", Policy::parse)?,
+            dkim: required_parsed(evaluated, "dkim", AuthResult::parse)?,
+            spf: required_parsed(evaluated, "spf", AuthResult::parse)?,
+            reasons,
+            envelope_to: text(identifiers, "envelope_to"),
+            envelope_from: text(identifiers, "envelope_from"),
+            header_from: required_text(identifiers, "header_from")?,
+            dkim_results,
+            spf_results,
+        })
+    }
+}
+
+impl AggregateReport {
+    /// Parse the XML of a report.
+    pub fn parse(xml: &str) -> Result<Self, ReportError> {
+        let document =
+            Document::parse(xml).map_err(|e| ReportError::MalformedAttachment(e.to_string()))?;
+        let feedback = document.root_element();
+        if feedback.tag_name().name() != "feedback" {
+            return Err(ReportError::NotAReport);
+        }
+        let metadata = required_child(feedback, "report_metadata")?;
+        let date_range = required_child(metadata, "date_range")?;
+        let errors = children(metadata, "error")
+            .filter_map(|error| error.text())
+            .map(|error| error.trim().to_owned())
+            .collect();
+        let records = children(feedback, "record")
+            .map(ReportRecord::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(AggregateReport {
+            org_name: required_text(metadata, "org_name")?,
+            email: required_text(metadata, "email")?,
+            extra_contact_info: text(metadata, "extra_contact_info"),
+            report_id: required_text(metadata, "report_id")?,
+            begin: required_parsed(date_range, "begin", timestamp)?,
+            end: required_parsed(date_range, "end", timestamp)?,
+            errors,
+            policy: PublishedPolicy::parse(required_child(feedback, "policy_published")?)?,
+            records,
+        })
+    }
+
+    /// Parse a report that may be gzipped or zipped, as it is attached.
+    pub fn decode(data: &[u8]) -> Result<Self, ReportError> {
+        let malformed = |e: &dyn std::error::Error| ReportError::MalformedAttachment(e.to_string());
+        let mut xml = vec![];
+        if data.starts_with(&[0x1f, 0x8b]) {
+            GzDecoder::new(data)
+                .take(MAX_REPORT_SIZE)
+                .read_to_end(&mut xml)
+                .map_err(|e| malformed(&e))?;
+        } else if data.starts_with(b"PK\x03\x04") {
+            let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| malformed(&e))?;
+            // The report is the only file in the archive.
+            let file = archive.by_index(0).map_err(|e| malformed(&e))?;
+            file.take(MAX_REPORT_SIZE)
+                .read_to_end(&mut xml)
+                .map_err(|e| malformed(&e))?;
+        } else {
+            xml.extend_from_slice(data);
+        }
+        let xml = String::from_utf8(xml)
+            .map_err(|_| ReportError::MalformedAttachment("not UTF-8".to_owned()))?;
+        Self::parse(&xml)
+    }
+
+    /// Find and parse the report attached to `message`.
+    pub fn find(message: &Message) -> Result<Self, ReportError> {
+        let mut error = ReportError::NotAReport;
+        for part in message.parts() {
+            if let Body::Multipart { .. } = part.message.body() {
+                continue;
+            }
+            let is_attachment = match part.message.content_type() {
+                Some(ct) => {
+                    let subtype = String::from_utf8_lossy(&ct.subtype.0).to_ascii_lowercase();
+                    matches!(
+                        subtype.as_str(),
+                        "gzip"
+                            | "x-gzip"
+                            | "zip"
+                            | "x-zip"
+                            | "x-zip-compressed"
+                            | "xml"
+                            | "octet-stream"
+                    )
+                }
+                None => false,
+            };
+            if is_attachment {
+                match Self::decode(body_bytes(part.message)) {
+                    Ok(report) => return Ok(report),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use chrono::{TimeZone, Utc};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::AggregateReport;
+    use crate::auth::dmarc::{Alignment, Policy};
+    use crate::auth::spf::Identity;
+    use crate::auth::AuthResult;
+    use crate::parse::email::parse_message;
+    use crate::report::ReportError;
+
+    const REPORT: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<feedback>
+  <report_metadata>
+    <org_name>receiver.example</org_name>
+    <email>noreply-dmarc@receiver.example</email>
+    <report_id>9391651994964116463</report_id>
+    <date_range>
+      <begin>1614556800</begin>
+      <end>1614643199</end>
+    </date_range>
+  </report_metadata>
+  <policy_published>
+    <domain>example.com</domain>
+    <adkim>r</adkim>
+    <aspf>s</aspf>
+    <p>reject</p>
+    <pct>100</pct>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>192.0.2.1</source_ip>
+      <count>2</count>
+      <policy_evaluated>
+        <disposition>none</disposition>
+        <dkim>pass</dkim>
+        <spf>fail</spf>
+        <reason><type>forwarded</type></reason>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>example.com</header_from>
+    </identifiers>
+    <auth_results>
+      <dkim>
+        <domain>example.com</domain>
+        <selector>sel</selector>
+        <result>pass</result>
+      </dkim>
+      <spf>
+        <domain>lists.example.org</domain>
+        <scope>mfrom</scope>
+        <result>softfail</result>
+      </spf>
+    </auth_results>
+  </record>
+</feedback>
+"#;
+
+    #[test]
+    fn test_parse() {
+        let report = AggregateReport::parse(REPORT).unwrap();
+        assert_eq!(report.org_name, "receiver.example");
+        assert_eq!(
+            report.begin,
+            Utc.with_ymd_and_hms(2021, 3, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(report.policy.spf_alignment, Alignment::Strict);
+        assert_eq!(report.policy.policy, Policy::Reject);
+        let record = &report.records[0];
+        assert_eq!(
+            record.source_ip,
+            "192.0.2.1".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(record.count, 2);
+        assert_eq!(record.disposition, Policy::None);
+        assert_eq!(record.dkim, AuthResult::Pass);
+        assert_eq!(record.reasons[0].reason_type, "forwarded");
+        assert_eq!(record.dkim_results[0].selector.as_deref(), Some("sel"));
+        assert_eq!(record.spf_results[0].scope, Some(Identity::MailFrom));
+        assert_eq!(record.spf_results[0].result, AuthResult::SoftFail);
+
+        let broken = REPORT.replace("<count>2</count>", "<count>two</count>");
+        assert_eq!(
+            AggregateReport::parse(&broken),
+            Err(ReportError::InvalidField {
+                name: "count".to_owned(),
+                value: "two".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_attachments() {
+        let expected = AggregateReport::parse(REPORT).unwrap();
+
+        let mut zipped = ZipWriter::new(Cursor::new(vec![]));
+        zipped
+            .start_file(
+                "receiver.example!example.com!1614556800!1614643199.xml",
+                FileOptions::default(),
+            )
+            .unwrap();
+        zipped.write_all(REPORT.as_bytes()).unwrap();
+        let zipped = zipped.finish().unwrap().into_inner();
+        assert_eq!(AggregateReport::decode(&zipped).unwrap(), expected);
+
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(REPORT.as_bytes()).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        let message = format!(
+            "From: noreply-dmarc@receiver.example\r\n\
+             Subject: Report Domain: example.com\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed; boundary=b\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             This is an aggregate report.\r\n\
+             --b\r\n\
+             Content-Type: application/gzip\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {}\r\n\
+             --b--\r\n",
+            base64::encode(&gzipped)
+        );
+        let message = parse_message(message.as_bytes()).unwrap();
+        assert_eq!(AggregateReport::find(&message).unwrap(), expected);
+
+        let message = parse_message(b"Subject: Hi\r\n\r\nHello.\r\n").unwrap();
+        assert_eq!(
+            AggregateReport::find(&message),
+            Err(ReportError::NotAReport)
+        );
+    }
+}
@@ -0,0 +1,713 @@
+//! Delivery status notifications (RFC 3464), i.e. bounces and their
+//! relatives, with enhanced status codes (RFC 3463).
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset};
+
+use std::borrow::Cow;
+
+use super::{
+    assemble_report, body_bytes, find_report_parts, invalid_field, parse_date, parse_field_groups,
+    returned_part, simple_part, text_part, unstructured, Fields, ReportError, ReturnedContent,
+    TypedValue,
+};
+use crate::headers::address::{AddrSpec, Address, Domain, Mailbox};
+use crate::headers::HeaderFieldInner;
+use crate::smtp::envelope::{Notify, Ret, ReversePath, SmtpAddress};
+use crate::{ByteString, HeaderField, Message, SmtpEnvelope};
+
+/// An enhanced status code such as `5.1.1`: its class (2 for success, 4 for a
+/// transient failure or 5 for a permanent one), subject and detail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StatusCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl StatusCode {
+    pub fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class == 2
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.class == 4
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.class == 5
+    }
+}
+
+impl FromStr for StatusCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The code may be followed by a comment, e.g. `5.0.0 (permanent failure)`.
+        let code = s.split_whitespace().next().ok_or(())?;
+        let mut numbers = code.split('.');
+        let mut next = |max_len| match numbers.next() {
+            Some(n)
+                if !n.is_empty()
+                    && n.len() <= max_len
+                    && n.bytes().all(|ch| ch.is_ascii_digit()) =>
+            {
+                n.parse::<u16>().map_err(|_| ())
+            }
+            _ => Err(()),
+        };
+        let class = next(1)?;
+        let subject = next(3)?;
+        let detail = next(3)?;
+        if numbers.next().is_some() || !matches!(class, 2 | 4 | 5) {
+            return Err(());
+        }
+        Ok(StatusCode::new(class as u8, subject, detail))
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// What happened to the message for a recipient.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Failed,
+    Delayed,
+    Delivered,
+    /// Passed on to a system that doesn't send DSNs.
+    Relayed,
+    /// Delivered, and forwarded to further recipients (e.g., by a mailing list).
+    Expanded,
+}
+
+impl Action {
+    fn parse(value: &str) -> Option<Self> {
+        // An action may carry a comment, which we ignore.
+        let action = value
+            .split(|ch: char| ch == '(' || ch.is_whitespace())
+            .next()?;
+        Some(match action.to_ascii_lowercase().as_str() {
+            "failed" => Action::Failed,
+            "delayed" => Action::Delayed,
+            "delivered" => Action::Delivered,
+            "relayed" => Action::Relayed,
+            "expanded" => Action::Expanded,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Failed => "failed",
+            Action::Delayed => "delayed",
+            Action::Delivered => "delivered",
+            Action::Relayed => "relayed",
+            Action::Expanded => "expanded",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+/// The fields describing the message as a whole.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PerMessageFields {
+    pub original_envelope_id: Option<String>,
+    pub reporting_mta: Option<TypedValue>,
+    pub dsn_gateway: Option<TypedValue>,
+    pub received_from_mta: Option<TypedValue>,
+    pub arrival_date: Option<DateTime<FixedOffset>>,
+    /// Fields not defined by RFC 3464, such as the common `X-Postfix-Queue-ID`,
+    /// and optional ones whose values couldn't be parsed.
+    pub extensions: Fields,
+}
+
+/// Store `value` in `field` if it could be parsed, and say whether it was.
+fn store<T>(field: &mut Option<T>, value: Option<T>) -> bool {
+    match value {
+        Some(value) => {
+            *field = Some(value);
+            true
+        }
+        None => false,
+    }
+}
+
+impl PerMessageFields {
+    fn parse(fields: &Fields) -> Self {
+        let mut result = PerMessageFields::default();
+        for (name, value) in fields.0.iter() {
+            let typed = || TypedValue::parse(name, value).ok();
+            let parsed = match name.to_ascii_lowercase().as_str() {
+                "original-envelope-id" => {
+                    store(&mut result.original_envelope_id, Some(value.clone()))
+                }
+                "reporting-mta" => store(&mut result.reporting_mta, typed()),
+                "dsn-gateway" => store(&mut result.dsn_gateway, typed()),
+                "received-from-mta" => store(&mut result.received_from_mta, typed()),
+                "arrival-date" => store(&mut result.arrival_date, parse_date(value)),
+                _ => false,
+            };
+            if !parsed {
+                result.extensions.0.push((name.clone(), value.clone()));
+            }
+        }
+        result
+    }
+
+    fn to_fields(&self) -> Fields {
+        let mut fields = Fields::default();
+        if let Some(envid) = &self.original_envelope_id {
+            fields.push("Original-Envelope-Id", envid);
+        }
+        if let Some(mta) = &self.reporting_mta {
+            fields.push("Reporting-MTA", mta);
+        }
+        if let Some(gateway) = &self.dsn_gateway {
+            fields.push("DSN-Gateway", gateway);
+        }
+        if let Some(mta) = &self.received_from_mta {
+            fields.push("Received-From-MTA", mta);
+        }
+        if let Some(date) = &self.arrival_date {
+            fields.push("Arrival-Date", date.to_rfc2822());
+        }
+        fields.0.extend(self.extensions.0.iter().cloned());
+        fields
+    }
+}
+
+/// The fields describing what happened for one recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PerRecipientFields {
+    pub original_recipient: Option<TypedValue>,
+    pub final_recipient: TypedValue,
+    pub action: Action,
+    pub status: StatusCode,
+    pub remote_mta: Option<TypedValue>,
+    /// E.g. `smtp; 550 5.1.1 User unknown`.
+    pub diagnostic_code: Option<TypedValue>,
+    pub last_attempt_date: Option<DateTime<FixedOffset>>,
+    pub final_log_id: Option<String>,
+    pub will_retry_until: Option<DateTime<FixedOffset>>,
+    /// As in `PerMessageFields`, unknown fields and optional ones whose
+    /// values couldn't be parsed.
+    pub extensions: Fields,
+}
+
+impl PerRecipientFields {
+    /// Fields for `final_recipient`, with the others empty.
+    pub fn new(final_recipient: TypedValue, action: Action, status: StatusCode) -> Self {
+        Self {
+            original_recipient: None,
+            final_recipient,
+            action,
+            status,
+            remote_mta: None,
+            diagnostic_code: None,
+            last_attempt_date: None,
+            final_log_id: None,
+            will_retry_until: None,
+            extensions: Fields::default(),
+        }
+    }
+
+    fn parse(fields: &Fields) -> Result<Self, ReportError> {
+        let required = |name| fields.get(name).ok_or(ReportError::MissingField(name));
+        let final_recipient = TypedValue::parse("Final-Recipient", required("Final-Recipient")?)?;
+        let action = required("Action")?;
+        let action = Action::parse(action).ok_or_else(|| invalid_field("Action", action))?;
+        let status = required("Status")?;
+        let status = status
+            .parse()
+            .map_err(|()| invalid_field("Status", status))?;
+        let mut result = PerRecipientFields::new(final_recipient, action, status);
+        for (name, value) in fields.0.iter() {
+            let typed = || TypedValue::parse(name, value).ok();
+            let parsed = match name.to_ascii_lowercase().as_str() {
+                "final-recipient" | "action" | "status" => true,
+                "original-recipient" => store(&mut result.original_recipient, typed()),
+                "remote-mta" => store(&mut result.remote_mta, typed()),
+                "diagnostic-code" => store(&mut result.diagnostic_code, typed()),
+                "last-attempt-date" => store(&mut result.last_attempt_date, parse_date(value)),
+                "final-log-id" => store(&mut result.final_log_id, Some(value.clone())),
+                "will-retry-until" => store(&mut result.will_retry_until, parse_date(value)),
+                _ => false,
+            };
+            if !parsed {
+                result.extensions.0.push((name.clone(), value.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    fn to_fields(&self) -> Fields {
+        let mut fields = Fields::default();
+        if let Some(recipient) = &self.original_recipient {
+            fields.push("Original-Recipient", recipient);
+        }
+        fields.push("Final-Recipient", &self.final_recipient);
+        fields.push("Action", self.action);
+        fields.push("Status", self.status);
+        if let Some(mta) = &self.remote_mta {
+            fields.push("Remote-MTA", mta);
+        }
+        if let Some(diagnostic) = &self.diagnostic_code {
+            fields.push("Diagnostic-Code", diagnostic);
+        }
+        if let Some(date) = &self.last_attempt_date {
+            fields.push("Last-Attempt-Date", date.to_rfc2822());
+        }
+        if let Some(id) = &self.final_log_id {
+            fields.push("Final-Log-ID", id);
+        }
+        if let Some(date) = &self.will_retry_until {
+            fields.push("Will-Retry-Until", date.to_rfc2822());
+        }
+        fields.0.extend(self.extensions.0.iter().cloned());
+        fields
+    }
+}
+
+/// The content of a `message/delivery-status` part.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeliveryStatus {
+    pub message: PerMessageFields,
+    pub recipients: Vec<PerRecipientFields>,
+}
+
+impl DeliveryStatus {
+    /// Parse the body of a `message/delivery-status` part. Only a missing
+    /// or malformed `Final-Recipient`, `Action` or `Status` is an error;
+    /// other values that can't be parsed are kept in `extensions`.
+    pub fn parse(body: &[u8]) -> Result<Self, ReportError> {
+        let groups = parse_field_groups(body)?;
+        let (message, recipients) = match groups.split_first() {
+            Some(split) => split,
+            None => return Err(ReportError::MalformedFields),
+        };
+        Ok(DeliveryStatus {
+            message: PerMessageFields::parse(message),
+            recipients: recipients
+                .iter()
+                .map(PerRecipientFields::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Append the fields, in the form of a `message/delivery-status` body, to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        self.message.to_fields().write(out);
+        for recipient in self.recipients.iter() {
+            out.extend_from_slice(b"\r\n");
+            recipient.to_fields().write(out);
+        }
+    }
+}
+
+/// A `multipart/report; report-type=delivery-status` message, or part.
+#[derive(Clone, Debug)]
+pub struct DeliveryReport<'m, 'a> {
+    pub report: &'m Message<'a>,
+    /// The human-readable explanation, normally `text/plain`.
+    pub explanation: Option<&'m Message<'a>>,
+    pub status: DeliveryStatus,
+    /// The message that couldn't be delivered, or its header.
+    pub returned: Option<ReturnedContent<'m, 'a>>,
+}
+
+impl<'m, 'a> DeliveryReport<'m, 'a> {
+    /// Find and parse the delivery report in `message`, which may be the
+    /// report itself or contain it.
+    pub fn find(message: &'m Message<'a>) -> Result<Self, ReportError> {
+        let (section, report, status_part, explanation) =
+            find_report_parts(message, "delivery-status")?;
+        let status = DeliveryStatus::parse(body_bytes(status_part))?;
+        Ok(DeliveryReport {
+            report,
+            explanation,
+            status,
+            returned: ReturnedContent::find(&section, report),
+        })
+    }
+
+    /// The recipients for which delivery failed.
+    pub fn failed(&self) -> impl Iterator<Item = &PerRecipientFields> {
+        self.status
+            .recipients
+            .iter()
+            .filter(|r| r.action == Action::Failed)
+    }
+}
+
+/// Builds the notification sent to the reverse-path of a message that
+/// couldn't be delivered to some of its recipients.
+///
+/// The original message is returned in full, or just its header if the
+/// sender asked for that with `RET=HDRS`. Recipients that asked not to be
+/// told about failures (with `NOTIFY`) are left out.
+#[derive(Clone, Debug)]
+pub struct DsnBuilder<'m, 'a> {
+    reporting_mta: String,
+    original: &'m Message<'a>,
+    envelope: &'m SmtpEnvelope,
+    arrival_date: Option<DateTime<FixedOffset>>,
+    recipients: Vec<PerRecipientFields>,
+}
+
+impl<'m, 'a> DsnBuilder<'m, 'a> {
+    /// `reporting_mta` is the host name of the MTA sending the notification.
+    pub fn new(reporting_mta: &str, original: &'m Message<'a>, envelope: &'m SmtpEnvelope) -> Self {
+        Self {
+            reporting_mta: reporting_mta.to_owned(),
+            original,
+            envelope,
+            arrival_date: None,
+            recipients: vec![],
+        }
+    }
+
+    /// When the reporting MTA received the message.
+    pub fn arrival_date(mut self, date: DateTime<FixedOffset>) -> Self {
+        self.arrival_date = Some(date);
+        self
+    }
+
+    /// Report that delivery to `recipient` failed with `status`. `diagnostic`
+    /// is the reply of the remote SMTP server, e.g. `550 5.1.1 User unknown`.
+    pub fn failure(
+        mut self,
+        recipient: &SmtpAddress,
+        status: StatusCode,
+        diagnostic: &str,
+    ) -> Self {
+        let params = self
+            .envelope
+            .to
+            .iter()
+            .find(|r| r.address == *recipient)
+            .map(|r| &r.params);
+        let notify = params.and_then(|params| params.notify);
+        let notify_failure = match notify {
+            Some(Notify::Never) => false,
+            Some(Notify::On(on)) => on.failure(),
+            None => true,
+        };
+        if !notify_failure {
+            return self;
+        }
+        let mut fields = PerRecipientFields::new(
+            TypedValue::new("rfc822", recipient.to_string()),
+            Action::Failed,
+            status,
+        );
+        fields.original_recipient = params
+            .and_then(|params| params.orcpt.as_ref())
+            .map(|orcpt| TypedValue::new(&orcpt.addr_type, orcpt.address.clone()));
+        fields.diagnostic_code = Some(TypedValue::new("smtp", diagnostic));
+        self.recipients.push(fields);
+        self
+    }
+
+    /// Report on a recipient with arbitrary fields, e.g. for a delay. Unlike
+    /// with `failure`, the recipient's `NOTIFY` parameter isn't consulted.
+    pub fn recipient(mut self, fields: PerRecipientFields) -> Self {
+        self.recipients.push(fields);
+        self
+    }
+
+    /// The content of the `message/delivery-status` part.
+    pub fn delivery_status(&self) -> DeliveryStatus {
+        DeliveryStatus {
+            message: PerMessageFields {
+                original_envelope_id: self.envelope.params.envid.clone(),
+                reporting_mta: Some(TypedValue::new("dns", self.reporting_mta.clone())),
+                arrival_date: self.arrival_date,
+                ..PerMessageFields::default()
+            },
+            recipients: self.recipients.clone(),
+        }
+    }
+
+    /// Build the notification, dated `date`. Returns `None` if there is
+    /// nothing to report, or nobody to report it to because the reverse-path
+    /// is null (i.e., the original message was itself a notification), or
+    /// if one of the notification's fields can't be written (see
+    /// `HeaderField::from_inner`).
+    pub fn build(&self, date: DateTime<FixedOffset>) -> Option<Message<'static>> {
+        let sender = match &self.envelope.from {
+            ReversePath::Address(sender) => sender,
+            ReversePath::Null => return None,
+        };
+        if self.recipients.is_empty() {
+            return None;
+        }
+        let has = |action| self.recipients.iter().any(|r| r.action == action);
+        let (subject, summary) = if has(Action::Failed) {
+            ("Failure", "could not be delivered to")
+        } else if has(Action::Delayed) {
+            ("Delay", "has not yet been delivered to")
+        } else {
+            ("Success", "was delivered to")
+        };
+
+        let mut text = format!(
+            "This is the mail system at {}.\r\n\r\n\
+             Your message {} the following recipients:\r\n\r\n",
+            self.reporting_mta, summary
+        );
+        for r in self.recipients.iter() {
+            text.push_str(&format!("<{}>: {}", r.final_recipient.value, r.action));
+            match &r.diagnostic_code {
+                Some(diagnostic) => text.push_str(&format!(", {}\r\n", diagnostic.value)),
+                None => text.push_str(&format!(", status {}\r\n", r.status)),
+            }
+        }
+        let mut status = vec![];
+        self.delivery_status().write(&mut status);
+        let headers_only = self.envelope.params.ret == Some(Ret::Headers);
+        let parts = vec![
+            text_part(text),
+            simple_part("message", "delivery-status", status),
+            returned_part(self.original, headers_only),
+        ];
+
+        let from = Mailbox {
+            display_name: ["Mail", "Delivery", "System"]
+                .iter()
+                .map(|word| ByteString(word.as_bytes().to_vec()))
+                .collect(),
+            addr_spec: Some(AddrSpec {
+                local_part: Cow::Owned(ByteString(b"MAILER-DAEMON".to_vec())),
+                domain: Domain::Name(Cow::Owned(ByteString(
+                    self.reporting_mta.as_bytes().to_vec(),
+                ))),
+            }),
+        };
+        let to = Mailbox {
+            display_name: vec![],
+            addr_spec: Some(sender.to_addr_spec()),
+        };
+        let header = vec![
+            HeaderField::from_inner("From", HeaderFieldInner::From(vec![from])).ok()?,
+            HeaderField::from_inner("To", HeaderFieldInner::To(vec![Address::Mailbox(to)])).ok()?,
+            unstructured(
+                "Subject",
+                &format!("Delivery Status Notification ({})", subject),
+            )
+            .ok()?,
+            HeaderField::from_inner("Date", HeaderFieldInner::OrigDate(date)).ok()?,
+            // RFC 3834: this shouldn't provoke further automatic replies.
+            unstructured("Auto-Submitted", "auto-replied").ok()?,
+        ];
+        Some(assemble_report(header, "delivery-status", parts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, TimeZone};
+
+    use super::{Action, DeliveryReport, DsnBuilder, StatusCode};
+    use crate::parse::email::parse_message;
+    use crate::smtp::envelope::{parse_mail, parse_rcpt, ReversePath};
+    use crate::SmtpEnvelope;
+
+    #[test]
+    fn test_status_code() {
+        let code: StatusCode = "5.1.10 (recipient address rejected)".parse().unwrap();
+        assert_eq!(code, StatusCode::new(5, 1, 10));
+        assert!(code.is_permanent());
+        assert_eq!(code.to_string(), "5.1.10");
+        for bad in &["3.1.1", "5.1", "5.1.1.1", "5.x.1", "5.1.1000", ""] {
+            assert!(bad.parse::<StatusCode>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_bounce() {
+        let input = "From: MAILER-DAEMON@mx.example.com\r\n\
+                     Subject: Undelivered Mail Returned to Sender\r\n\
+                     Content-Type: multipart/report; report-type=delivery-status;\r\n\
+                     \tboundary=\"B\"\r\n\
+                     \r\n\
+                     --B\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     I'm sorry to have to inform you that your message could not be delivered.\r\n\
+                     --B\r\n\
+                     Content-Type: message/delivery-status\r\n\
+                     \r\n\
+                     Reporting-MTA: dns; mx.example.com\r\n\
+                     X-Postfix-Queue-ID: 3F1A2B\r\n\
+                     Original-Envelope-Id: QQ314\r\n\
+                     Arrival-Date: Mon, 1 Mar 2021 12:00:00 +0100\r\n\
+                     DSN-Gateway: gw.example.com\r\n\
+                     \r\n\
+                     Final-Recipient: rfc822; nobody@example.org\r\n\
+                     Original-Recipient: rfc822;nobody@example.org\r\n\
+                     Action: failed\r\n\
+                     Status: 5.1.1\r\n\
+                     Remote-MTA: dns; mx.example.org\r\n\
+                     Diagnostic-Code: smtp; 550 5.1.1 <nobody@example.org>:\r\n\
+                     \x20   Recipient address rejected: User unknown\r\n\
+                     \r\n\
+                     Final-Recipient: rfc822; slow@example.org\r\n\
+                     Action: delayed\r\n\
+                     Status: 4.4.1\r\n\
+                     Will-Retry-Until: Tue, 2 Mar 2021 12:00:00 +0100\r\n\
+                     Last-Attempt-Date: yesterday\r\n\
+                     \r\n\
+                     --B\r\n\
+                     Content-Type: text/rfc822-headers\r\n\
+                     \r\n\
+                     From: brennan@umanwizard.com\r\n\
+                     Subject: hello\r\n\
+                     --B--\r\n";
+        let message = parse_message(input.as_bytes()).unwrap();
+        let report = DeliveryReport::find(&message).unwrap();
+        assert!(report.explanation.is_some());
+
+        let per_message = &report.status.message;
+        assert_eq!(
+            per_message.reporting_mta.as_ref().unwrap().value,
+            "mx.example.com"
+        );
+        assert_eq!(per_message.original_envelope_id.as_deref(), Some("QQ314"));
+        assert_eq!(
+            per_message.arrival_date.unwrap().to_rfc3339(),
+            "2021-03-01T12:00:00+01:00"
+        );
+        assert_eq!(
+            per_message.extensions.get("x-postfix-queue-id"),
+            Some("3F1A2B")
+        );
+        // Values that can't be parsed don't spoil the report.
+        assert_eq!(per_message.dsn_gateway, None);
+        assert_eq!(
+            per_message.extensions.get("DSN-Gateway"),
+            Some("gw.example.com")
+        );
+
+        assert_eq!(report.status.recipients.len(), 2);
+        let failed: Vec<_> = report.failed().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].final_recipient.value_type, "rfc822");
+        assert_eq!(failed[0].final_recipient.value, "nobody@example.org");
+        assert_eq!(failed[0].status, StatusCode::new(5, 1, 1));
+        assert_eq!(
+            failed[0].diagnostic_code.as_ref().unwrap().value,
+            "550 5.1.1 <nobody@example.org>:    Recipient address rejected: User unknown"
+        );
+        let delayed = &report.status.recipients[1];
+        assert_eq!(delayed.action, Action::Delayed);
+        assert!(delayed.will_retry_until.is_some());
+        assert_eq!(delayed.last_attempt_date, None);
+        assert_eq!(
+            delayed.extensions.get("Last-Attempt-Date"),
+            Some("yesterday")
+        );
+
+        let returned = report.returned.unwrap();
+        assert!(returned.headers_only);
+        assert_eq!(returned.section.to_string(), "3");
+        let data = returned.data();
+        let original = parse_message(&data).unwrap();
+        assert!(original.header_field("Subject").is_some());
+    }
+
+    #[test]
+    fn test_build_bounce() {
+        let original = parse_message(
+            b"From: sender@example.com\r\n\
+              To: a@example.org, b@example.org\r\n\
+              Subject: hello\r\n\
+              \r\n\
+              Hi!\r\n",
+        )
+        .unwrap();
+        let (from, params) = parse_mail("FROM:<sender@example.com> RET=HDRS ENVID=QQ314").unwrap();
+        let envelope = SmtpEnvelope {
+            from,
+            params,
+            to: vec![
+                parse_rcpt("TO:<a@example.org> ORCPT=rfc822;A@example.org").unwrap(),
+                parse_rcpt("TO:<b@example.org> NOTIFY=DELAY").unwrap(),
+            ],
+        };
+        let date = FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2021, 3, 1, 12, 0, 0)
+            .unwrap();
+        let builder = DsnBuilder::new("mx.example.com", &original, &envelope)
+            .arrival_date(date)
+            .failure(
+                &envelope.to[0].address,
+                StatusCode::new(5, 1, 1),
+                "550 5.1.1 User unknown",
+            )
+            .failure(
+                &envelope.to[1].address,
+                StatusCode::new(5, 2, 2),
+                "552 5.2.2 Mailbox full",
+            );
+        let dsn = builder.build(date).unwrap().to_bytes();
+
+        let dsn = parse_message(&dsn).unwrap();
+        let subject = dsn.header_field("Subject").unwrap().unfolded_value();
+        assert_eq!(&subject.0, b" Delivery Status Notification (Failure)");
+        let to = dsn.header_field("To").unwrap().unfolded_value();
+        assert_eq!(&to.0, b" sender@example.com");
+        let report = DeliveryReport::find(&dsn).unwrap();
+        let status = &report.status;
+        assert_eq!(
+            status.message.original_envelope_id.as_deref(),
+            Some("QQ314")
+        );
+        assert_eq!(status.message.arrival_date, Some(date));
+        // b@example.org only wanted to hear about delays.
+        assert_eq!(status.recipients.len(), 1);
+        let failed = &status.recipients[0];
+        assert_eq!(failed.final_recipient.value, "a@example.org");
+        assert_eq!(
+            failed.original_recipient.as_ref().unwrap().value,
+            "A@example.org"
+        );
+        assert_eq!(failed.status, StatusCode::new(5, 1, 1));
+        assert_eq!(failed.action, Action::Failed);
+        assert_eq!(
+            failed.diagnostic_code.as_ref().unwrap().value,
+            "550 5.1.1 User unknown"
+        );
+        let returned = report.returned.unwrap();
+        assert!(returned.headers_only);
+        let data = returned.data();
+        let returned = parse_message(&data).unwrap();
+        assert!(returned.header_field("Subject").is_some());
+
+        // Never bounce a bounce.
+        let mut envelope = envelope.clone();
+        envelope.from = ReversePath::Null;
+        let builder = DsnBuilder::new("mx.example.com", &original, &envelope).failure(
+            &envelope.to[0].address,
+            StatusCode::new(5, 1, 1),
+            "550 5.1.1 User unknown",
+        );
+        assert!(builder.build(date).is_none());
+    }
+}
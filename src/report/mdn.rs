@@ -0,0 +1,481 @@
+//! Message disposition notifications (RFC 8098), i.e. read receipts: finding
+//! out whether a sender asked for one, parsing them, and building them.
+
+use std::fmt;
+
+use chrono::{DateTime, FixedOffset};
+use nom::bytes::complete::tag;
+use nom::combinator::all_consuming;
+use nom::multi::separated_list1;
+
+use super::{
+    assemble_report, body_bytes, find_report_parts, invalid_field, parse_field_groups,
+    returned_part, simple_part, text_part, unstructured, Fields, ReportError, ReturnedContent,
+    TypedValue,
+};
+use crate::headers::address::{Address, Mailbox};
+use crate::headers::render;
+use crate::headers::HeaderFieldInner;
+use crate::parse::address::mailbox;
+use crate::{HeaderField, Message};
+
+/// The addresses in the `Disposition-Notification-To` field of `message`,
+/// if the sender asked for a notification and the field is well-formed.
+///
+/// RFC 8098 section 2.1 suggests asking the user before sending a notification
+/// to an address other than the `Return-Path`.
+pub fn notification_requested(message: &Message) -> Option<Vec<Mailbox<'static>>> {
+    let field = message.header_field("Disposition-Notification-To")?;
+    let value = &field.unfolded_value().0;
+    let (_, mailboxes) = all_consuming(separated_list1(tag(b","), mailbox))(value).ok()?;
+    Some(mailboxes.into_iter().map(Mailbox::into_owned).collect())
+}
+
+/// Whether the disposition was the result of an action by the user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ActionMode {
+    Manual,
+    Automatic,
+}
+
+/// Whether the user explicitly agreed to send the notification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SendingMode {
+    Manual,
+    Automatic,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DispositionType {
+    /// Shown to the user, which doesn't mean it was read.
+    Displayed,
+    Deleted,
+    /// Sent somewhere (e.g. printed or forwarded) without being displayed.
+    Dispatched,
+    /// Processed in some other way, e.g. by an autoresponder.
+    Processed,
+}
+
+/// The value of the `Disposition` field, e.g.
+/// `manual-action/MDN-sent-manually; displayed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disposition {
+    pub action_mode: ActionMode,
+    pub sending_mode: SendingMode,
+    pub disposition_type: DispositionType,
+    /// E.g. `error`, lowercased.
+    pub modifiers: Vec<String>,
+}
+
+impl Disposition {
+    pub fn new(
+        action_mode: ActionMode,
+        sending_mode: SendingMode,
+        disposition_type: DispositionType,
+    ) -> Self {
+        Self {
+            action_mode,
+            sending_mode,
+            disposition_type,
+            modifiers: vec![],
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let (modes, disposition) = split_once(value, ';')?;
+        let (action_mode, sending_mode) = split_once(modes, '/')?;
+        let (disposition_type, modifiers) = match split_once(disposition, '/') {
+            Some((disposition_type, modifiers)) => (disposition_type, Some(modifiers)),
+            None => (disposition.trim(), None),
+        };
+        let action_mode = match action_mode.to_ascii_lowercase().as_str() {
+            "manual-action" => ActionMode::Manual,
+            "automatic-action" => ActionMode::Automatic,
+            _ => return None,
+        };
+        let sending_mode = match sending_mode.to_ascii_lowercase().as_str() {
+            "mdn-sent-manually" => SendingMode::Manual,
+            "mdn-sent-automatically" => SendingMode::Automatic,
+            _ => return None,
+        };
+        let disposition_type = match disposition_type.to_ascii_lowercase().as_str() {
+            "displayed" => DispositionType::Displayed,
+            "deleted" => DispositionType::Deleted,
+            "dispatched" => DispositionType::Dispatched,
+            "processed" => DispositionType::Processed,
+            _ => return None,
+        };
+        let modifiers = modifiers
+            .into_iter()
+            .flat_map(|modifiers| modifiers.split(','))
+            .map(|modifier| modifier.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        if modifiers.iter().any(String::is_empty) {
+            return None;
+        }
+        Some(Disposition {
+            action_mode,
+            sending_mode,
+            disposition_type,
+            modifiers,
+        })
+    }
+}
+
+/// Split `s` at the first `delimiter`, trimming both halves.
+fn split_once(s: &str, delimiter: char) -> Option<(&str, &str)> {
+    let i = s.find(delimiter)?;
+    Some((s[..i].trim(), s[i + 1..].trim()))
+}
+
+impl fmt::Display for Disposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action_mode = match self.action_mode {
+            ActionMode::Manual => "manual-action",
+            ActionMode::Automatic => "automatic-action",
+        };
+        let sending_mode = match self.sending_mode {
+            SendingMode::Manual => "MDN-sent-manually",
+            SendingMode::Automatic => "MDN-sent-automatically",
+        };
+        write!(
+            f,
+            "{}/{}; {}",
+            action_mode, sending_mode, self.disposition_type
+        )?;
+        if !self.modifiers.is_empty() {
+            write!(f, "/{}", self.modifiers.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DispositionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let disposition_type = match self {
+            DispositionType::Displayed => "displayed",
+            DispositionType::Deleted => "deleted",
+            DispositionType::Dispatched => "dispatched",
+            DispositionType::Processed => "processed",
+        };
+        write!(f, "{}", disposition_type)
+    }
+}
+
+/// The content of a `message/disposition-notification` part.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DispositionNotification {
+    /// E.g. `mail.example.com; ExampleMail 1.0`.
+    pub reporting_ua: Option<String>,
+    pub mdn_gateway: Option<TypedValue>,
+    pub original_recipient: Option<TypedValue>,
+    pub final_recipient: TypedValue,
+    /// Including the angle brackets.
+    pub original_message_id: Option<String>,
+    pub disposition: Disposition,
+    /// The values of any `Error` fields.
+    pub errors: Vec<String>,
+    pub extensions: Fields,
+}
+
+impl DispositionNotification {
+    pub fn new(final_recipient: TypedValue, disposition: Disposition) -> Self {
+        Self {
+            reporting_ua: None,
+            mdn_gateway: None,
+            original_recipient: None,
+            final_recipient,
+            original_message_id: None,
+            disposition,
+            errors: vec![],
+            extensions: Fields::default(),
+        }
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self, ReportError> {
+        let fields = match &parse_field_groups(body)?[..] {
+            [fields] => fields.clone(),
+            _ => return Err(ReportError::MalformedFields),
+        };
+        let required = |name| fields.get(name).ok_or(ReportError::MissingField(name));
+        let final_recipient = TypedValue::parse("Final-Recipient", required("Final-Recipient")?)?;
+        let disposition = required("Disposition")?;
+        let disposition = Disposition::parse(disposition)
+            .ok_or_else(|| invalid_field("Disposition", disposition))?;
+        let mut result = DispositionNotification::new(final_recipient, disposition);
+        for (name, value) in fields.0.iter() {
+            match name.to_ascii_lowercase().as_str() {
+                "final-recipient" | "disposition" => {}
+                "reporting-ua" => result.reporting_ua = Some(value.clone()),
+                "mdn-gateway" => result.mdn_gateway = Some(TypedValue::parse(name, value)?),
+                "original-recipient" => {
+                    result.original_recipient = Some(TypedValue::parse(name, value)?)
+                }
+                "original-message-id" => result.original_message_id = Some(value.clone()),
+                "error" => result.errors.push(value.clone()),
+                _ => result.extensions.0.push((name.clone(), value.clone())),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Append the fields, in the form of a `message/disposition-notification`
+    /// body, to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let mut fields = Fields::default();
+        if let Some(ua) = &self.reporting_ua {
+            fields.push("Reporting-UA", ua);
+        }
+        if let Some(gateway) = &self.mdn_gateway {
+            fields.push("MDN-Gateway", gateway);
+        }
+        if let Some(recipient) = &self.original_recipient {
+            fields.push("Original-Recipient", recipient);
+        }
+        fields.push("Final-Recipient", &self.final_recipient);
+        if let Some(id) = &self.original_message_id {
+            fields.push("Original-Message-ID", id);
+        }
+        fields.push("Disposition", &self.disposition);
+        for error in self.errors.iter() {
+            fields.push("Error", error);
+        }
+        fields.0.extend(self.extensions.0.iter().cloned());
+        fields.write(out);
+    }
+}
+
+/// A `multipart/report; report-type=disposition-notification` message, or part.
+#[derive(Clone, Debug)]
+pub struct DispositionReport<'m, 'a> {
+    pub report: &'m Message<'a>,
+    /// The human-readable explanation, normally `text/plain`.
+    pub explanation: Option<&'m Message<'a>>,
+    pub notification: DispositionNotification,
+    /// The original message, or its header.
+    pub returned: Option<ReturnedContent<'m, 'a>>,
+}
+
+impl<'m, 'a> DispositionReport<'m, 'a> {
+    /// Find and parse the disposition notification in `message`, which may be
+    /// the report itself or contain it.
+    pub fn find(message: &'m Message<'a>) -> Result<Self, ReportError> {
+        let (section, report, notification_part, explanation) =
+            find_report_parts(message, "disposition-notification")?;
+        let notification = DispositionNotification::parse(body_bytes(notification_part))?;
+        Ok(DispositionReport {
+            report,
+            explanation,
+            notification,
+            returned: ReturnedContent::find(&section, report),
+        })
+    }
+}
+
+/// Builds the notification sent in reply to a message whose sender asked for
+/// one with `Disposition-Notification-To`.
+#[derive(Clone, Debug)]
+pub struct MdnBuilder<'m, 'a> {
+    original: &'m Message<'a>,
+    from: Mailbox<'static>,
+    reporting_ua: Option<String>,
+    return_headers: bool,
+}
+
+impl<'m, 'a> MdnBuilder<'m, 'a> {
+    /// `from` is the recipient of `original` who is sending the notification.
+    pub fn new(original: &'m Message<'a>, from: Mailbox<'static>) -> Self {
+        Self {
+            original,
+            from,
+            reporting_ua: None,
+            return_headers: false,
+        }
+    }
+
+    /// The `Reporting-UA` field, e.g. `mail.example.com; ExampleMail 1.0`.
+    pub fn reporting_ua(mut self, ua: &str) -> Self {
+        self.reporting_ua = Some(ua.to_owned());
+        self
+    }
+
+    /// Whether to include the header of the original message as a third part.
+    pub fn return_headers(mut self, yes: bool) -> Self {
+        self.return_headers = yes;
+        self
+    }
+
+    fn original_field(&self, name: &str) -> Option<String> {
+        self.original.header_field(name).map(|hf| {
+            String::from_utf8_lossy(&hf.unfolded_value().0)
+                .trim()
+                .to_owned()
+        })
+    }
+
+    /// The content of the `message/disposition-notification` part.
+    pub fn notification(&self, disposition: Disposition) -> DispositionNotification {
+        let final_recipient = self
+            .from
+            .addr_spec
+            .as_ref()
+            .map(|spec| String::from_utf8_lossy(&render::addr_spec(spec)).into_owned())
+            .unwrap_or_default();
+        let mut notification =
+            DispositionNotification::new(TypedValue::new("rfc822", final_recipient), disposition);
+        notification.reporting_ua = self.reporting_ua.clone();
+        // Added by the receiving MTA, if it supports DSNs (RFC 8098 section 2.3).
+        notification.original_recipient = self
+            .original_field("Original-Recipient")
+            .and_then(|value| TypedValue::parse("Original-Recipient", &value).ok());
+        notification.original_message_id = self.original_field("Message-ID");
+        notification
+    }
+
+    /// Build the notification, dated `date`. Returns `None` if the original
+    /// message didn't ask for one, or if one of the notification's fields
+    /// can't be written (see `HeaderField::from_inner`).
+    pub fn build(
+        &self,
+        disposition: Disposition,
+        date: DateTime<FixedOffset>,
+    ) -> Option<Message<'static>> {
+        let to = notification_requested(self.original)?;
+        let subject = self.original_field("Subject").unwrap_or_default();
+        let recipient = self.notification(disposition.clone()).final_recipient.value;
+
+        let text = format!(
+            "This is a notification about the message with subject \"{}\" \
+             sent to {}.\r\n\r\n\
+             The message has been {}. This is no guarantee that it has been \
+             read or understood.\r\n",
+            subject, recipient, disposition.disposition_type
+        );
+        let mut notification = vec![];
+        self.notification(disposition.clone())
+            .write(&mut notification);
+        let mut parts = vec![
+            text_part(text),
+            simple_part("message", "disposition-notification", notification),
+        ];
+        if self.return_headers {
+            parts.push(returned_part(self.original, true));
+        }
+
+        let subject = match disposition.disposition_type {
+            DispositionType::Displayed => format!("Read: {}", subject),
+            _ => format!("Disposition notification: {}", subject),
+        };
+        let mut header = vec![
+            HeaderField::from_inner("From", HeaderFieldInner::From(vec![self.from.clone()]))
+                .ok()?,
+            HeaderField::from_inner(
+                "To",
+                HeaderFieldInner::To(to.into_iter().map(Address::Mailbox).collect()),
+            )
+            .ok()?,
+            unstructured("Subject", &subject).ok()?,
+            HeaderField::from_inner("Date", HeaderFieldInner::OrigDate(date)).ok()?,
+        ];
+        if let Some(id) = self.original_field("Message-ID") {
+            header.push(unstructured("In-Reply-To", &id).ok()?);
+            header.push(unstructured("References", &id).ok()?);
+        }
+        if disposition.sending_mode == SendingMode::Automatic {
+            header.push(unstructured("Auto-Submitted", "auto-replied").ok()?);
+        }
+        Some(assemble_report(header, "disposition-notification", parts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, TimeZone};
+
+    use super::{
+        notification_requested, ActionMode, Disposition, DispositionReport, DispositionType,
+        MdnBuilder, SendingMode,
+    };
+    use crate::parse::email::parse_message;
+
+    #[test]
+    fn test_disposition() {
+        let disposition =
+            Disposition::parse("automatic-action/MDN-sent-automatically; processed/error").unwrap();
+        assert_eq!(disposition.action_mode, ActionMode::Automatic);
+        assert_eq!(disposition.sending_mode, SendingMode::Automatic);
+        assert_eq!(disposition.disposition_type, DispositionType::Processed);
+        assert_eq!(disposition.modifiers, vec!["error"]);
+        assert_eq!(
+            disposition.to_string(),
+            "automatic-action/MDN-sent-automatically; processed/error"
+        );
+        assert!(Disposition::parse("manual-action; displayed").is_none());
+        assert!(Disposition::parse("manual-action/MDN-sent-manually; read").is_none());
+    }
+
+    #[test]
+    fn test_read_receipt() {
+        let original = parse_message(
+            b"From: Alice <alice@example.com>\r\n\
+              To: bob@example.org\r\n\
+              Subject: lunch?\r\n\
+              Message-ID: <123@example.com>\r\n\
+              Original-Recipient: rfc822;Bob@example.org\r\n\
+              Disposition-Notification-To: Alice <alice@example.com>\r\n\
+              \r\n\
+              Noon?\r\n",
+        )
+        .unwrap();
+        let requested = notification_requested(&original).unwrap();
+        assert_eq!(requested.len(), 1);
+
+        let bob = parse_message(b"From: Bob <bob@example.org>\r\n\r\n").unwrap();
+        let bob = match bob.header_field("From").unwrap().inner() {
+            crate::headers::HeaderFieldInner::From(from) => from[0].clone().into_owned(),
+            _ => unreachable!(),
+        };
+        let date = FixedOffset::east_opt(0)
+            .unwrap()
+            .with_ymd_and_hms(2021, 3, 1, 12, 0, 0)
+            .unwrap();
+        let disposition = Disposition::new(
+            ActionMode::Manual,
+            SendingMode::Manual,
+            DispositionType::Displayed,
+        );
+        let mdn = MdnBuilder::new(&original, bob)
+            .reporting_ua("mail.example.org; ExampleMail 1.0")
+            .return_headers(true)
+            .build(disposition.clone(), date)
+            .unwrap()
+            .to_bytes();
+
+        let mdn = parse_message(&mdn).unwrap();
+        let subject = mdn.header_field("Subject").unwrap().unfolded_value();
+        assert_eq!(&subject.0, b" Read: lunch?");
+        let report = DispositionReport::find(&mdn).unwrap();
+        let notification = &report.notification;
+        assert_eq!(notification.disposition, disposition);
+        assert_eq!(notification.final_recipient.value, "bob@example.org");
+        assert_eq!(
+            notification.original_recipient.as_ref().unwrap().value,
+            "Bob@example.org"
+        );
+        assert_eq!(
+            notification.original_message_id.as_deref(),
+            Some("<123@example.com>")
+        );
+        assert_eq!(
+            notification.reporting_ua.as_deref(),
+            Some("mail.example.org; ExampleMail 1.0")
+        );
+        assert!(report.returned.unwrap().headers_only);
+
+        let unrequested = parse_message(b"From: alice@example.com\r\n\r\n").unwrap();
+        let alice = notification_requested(&original).unwrap().remove(0);
+        assert!(MdnBuilder::new(&unrequested, alice)
+            .build(disposition, date)
+            .is_none());
+    }
+}
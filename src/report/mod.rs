@@ -0,0 +1,366 @@
+//! Machine-readable reports about other messages, sent as `multipart/report`
+//! (RFC 6522). The first part of a report is a human-readable explanation,
+//! the second a machine-readable one in a format given by the `report-type`
+//! parameter, and the optional third part is the message being reported on,
+//! or just its header. DMARC aggregate reports, which are XML attachments
+//! rather than `multipart/report`, are in `dmarc`.
+
+pub mod dmarc;
+pub mod dsn;
+pub mod mdn;
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::crypto::is_type;
+use crate::headers::mime::{ContentTransferEncoding, ContentType};
+use crate::headers::{FieldError, HeaderFieldInner};
+use crate::parse::date_time::date_time;
+use crate::parse::header::header_field;
+use crate::section::SectionPath;
+use crate::{Body, ByteString, HeaderField, Message};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReportError {
+    /// There is no `multipart/report` part with the expected report type.
+    NotAReport,
+    /// The report doesn't have a machine-readable part of the expected type.
+    MissingStatusPart,
+    /// The machine-readable part isn't made up of header-like fields.
+    MalformedFields,
+    MissingField(&'static str),
+    InvalidField {
+        name: String,
+        value: String,
+    },
+    /// An attachment couldn't be decompressed, or isn't well-formed XML.
+    MalformedAttachment(String),
+}
+
+/// A group of `name: value` fields, as found in the machine-readable
+/// part of a report. Values are unfolded and trimmed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields(pub Vec<(String, String)>);
+
+impl Fields {
+    /// The value of the first field with the given name (compared case-insensitively).
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn push(&mut self, name: &str, value: impl fmt::Display) {
+        self.0.push((name.to_owned(), value.to_string()));
+    }
+
+    /// Append the fields, each terminated by CRLF, to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        for (name, value) in self.0.iter() {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+/// Split the body of a machine-readable part into groups of fields, which
+/// are separated by blank lines.
+pub(crate) fn parse_field_groups(body: &[u8]) -> Result<Vec<Fields>, ReportError> {
+    // The body may have lost its CRLFs along the way (e.g., if it was base64
+    // encoded by a Unix program), but the header parser requires them.
+    let mut normalized = Vec::with_capacity(body.len());
+    for (i, ch) in body.iter().copied().enumerate() {
+        if ch == b'\n' && (i == 0 || body[i - 1] != b'\r') {
+            normalized.push(b'\r');
+        }
+        normalized.push(ch);
+    }
+    if !normalized.ends_with(b"\r\n") {
+        normalized.extend_from_slice(b"\r\n");
+    }
+
+    let mut groups = vec![];
+    let mut i = &normalized[..];
+    loop {
+        while let Some(rest) = i.strip_prefix(b"\r\n") {
+            i = rest;
+        }
+        if i.is_empty() {
+            return Ok(groups);
+        }
+        let mut fields = Fields::default();
+        while let Ok((rest, hf)) = header_field(i) {
+            let name = String::from_utf8_lossy(&hf.name().0).into_owned();
+            let value = String::from_utf8_lossy(&hf.unfolded_value().0);
+            fields.0.push((name, value.trim().to_owned()));
+            i = rest;
+        }
+        if fields.0.is_empty() {
+            return Err(ReportError::MalformedFields);
+        }
+        groups.push(fields);
+    }
+}
+
+pub(crate) fn parse_date(value: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    match date_time(value.as_bytes()) {
+        Ok(([], date)) => Some(date),
+        _ => None,
+    }
+}
+
+pub(crate) fn invalid_field(name: &str, value: &str) -> ReportError {
+    ReportError::InvalidField {
+        name: name.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+/// A value qualified by its type, e.g. `rfc822; user@example.com` or
+/// `dns; mx.example.com`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypedValue {
+    pub value_type: String,
+    pub value: String,
+}
+
+impl TypedValue {
+    pub fn new(value_type: &str, value: impl Into<String>) -> Self {
+        Self {
+            value_type: value_type.to_owned(),
+            value: value.into(),
+        }
+    }
+
+    pub(crate) fn parse(name: &str, value: &str) -> Result<Self, ReportError> {
+        match value.find(';') {
+            Some(i) if !value[..i].trim().is_empty() => Ok(Self::new(
+                value[..i].trim(),
+                value[i + 1..].trim().to_owned(),
+            )),
+            _ => Err(invalid_field(name, value)),
+        }
+    }
+}
+
+impl fmt::Display for TypedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; {}", self.value_type, self.value)
+    }
+}
+
+/// The bytes of a non-multipart body, after any transfer encoding was removed.
+pub(crate) fn body_bytes<'m>(part: &'m Message) -> &'m [u8] {
+    match part.body() {
+        Body::SimpleText(text) => text.as_bytes(),
+        Body::SimpleBinary(data) => data,
+        Body::Multipart { .. } => &[],
+    }
+}
+
+/// Find the first `multipart/report` part with the given report type.
+fn find_report<'m, 'a>(
+    message: &'m Message<'a>,
+    report_type: &str,
+) -> Option<(SectionPath, &'m Message<'a>)> {
+    let is_report = |m: &Message| {
+        let ct = m.content_type();
+        is_type(ct, "multipart", "report")
+            && matches!(ct.and_then(|ct| ct.parameters.get("report-type")),
+                        Some(rt) if rt.eq_ignore_ascii_case(report_type))
+    };
+    if is_report(message) {
+        return Some((SectionPath::default(), message));
+    }
+    message
+        .parts()
+        .find(|part| is_report(part.message))
+        .map(|part| (part.section, part.message))
+}
+
+/// Find the report with the given report type in `message`, and return its
+/// section, the report itself, its machine-readable `message/<report type>`
+/// part and its human-readable explanation, if any.
+pub(crate) fn find_report_parts<'m, 'a>(
+    message: &'m Message<'a>,
+    report_type: &str,
+) -> Result<
+    (
+        SectionPath,
+        &'m Message<'a>,
+        &'m Message<'a>,
+        Option<&'m Message<'a>>,
+    ),
+    ReportError,
+> {
+    let (section, report) = find_report(message, report_type).ok_or(ReportError::NotAReport)?;
+    let parts = match report.body() {
+        Body::Multipart { parts, .. } => parts,
+        _ => return Err(ReportError::NotAReport),
+    };
+    let machine_part = parts
+        .iter()
+        .find(|part| is_type(part.content_type(), "message", report_type))
+        .ok_or(ReportError::MissingStatusPart)?;
+    let explanation = parts
+        .first()
+        .filter(|part| !std::ptr::eq(*part, machine_part));
+    Ok((section, report, machine_part, explanation))
+}
+
+/// The optional third part of a report: the message it is about, or its header.
+#[derive(Clone, Debug)]
+pub struct ReturnedContent<'m, 'a> {
+    pub section: SectionPath,
+    pub part: &'m Message<'a>,
+    /// Whether only the header was returned (`text/rfc822-headers`), rather
+    /// than the whole message (`message/rfc822`).
+    pub headers_only: bool,
+}
+
+impl<'m, 'a> ReturnedContent<'m, 'a> {
+    /// The returned message (or header) in a form that can be
+    /// parsed with `parse_message`.
+    pub fn data(&self) -> Cow<'m, [u8]> {
+        let data = body_bytes(self.part);
+        if self.headers_only && !data.ends_with(b"\r\n\r\n") {
+            let mut data = data.to_vec();
+            if !data.ends_with(b"\r\n") {
+                data.extend_from_slice(b"\r\n");
+            }
+            data.extend_from_slice(b"\r\n");
+            Cow::Owned(data)
+        } else {
+            Cow::Borrowed(data)
+        }
+    }
+
+    /// Look for returned content among the parts of a report, which is
+    /// at `section`.
+    pub(crate) fn find(section: &SectionPath, report: &'m Message<'a>) -> Option<Self> {
+        let parts = match report.body() {
+            Body::Multipart { parts, .. } => parts,
+            _ => return None,
+        };
+        parts.iter().enumerate().skip(1).find_map(|(i, part)| {
+            let ct = part.content_type();
+            let headers_only = if is_type(ct, "message", "rfc822") {
+                false
+            } else if is_type(ct, "text", "rfc822-headers") {
+                true
+            } else {
+                return None;
+            };
+            let mut path = section.clone();
+            path.0.push(i + 1);
+            Some(ReturnedContent {
+                section: path,
+                part,
+                headers_only,
+            })
+        })
+    }
+}
+
+pub(crate) fn unstructured(name: &str, value: &str) -> Result<HeaderField<'static>, FieldError> {
+    HeaderField::from_inner(
+        name,
+        HeaderFieldInner::Unstructured(ByteString(value.as_bytes().to_vec())),
+    )
+}
+
+fn content_type(r#type: &str, subtype: &str, parameters: &[(&str, &str)]) -> HeaderField<'static> {
+    let ct = ContentType {
+        r#type: Cow::Owned(ByteString(r#type.as_bytes().to_vec())),
+        subtype: Cow::Owned(ByteString(subtype.as_bytes().to_vec())),
+        parameters: parameters
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    };
+    HeaderField::from_inner("Content-Type", HeaderFieldInner::ContentType(ct))
+        .expect("report content types are valid")
+}
+
+/// A non-multipart part containing `data`, which is sent as 8bit if it isn't ASCII.
+pub(crate) fn simple_part(r#type: &str, subtype: &str, data: Vec<u8>) -> Message<'static> {
+    let mut header = vec![content_type(r#type, subtype, &[])];
+    if !data.is_ascii() {
+        header.push(
+            HeaderField::from_inner(
+                "Content-Transfer-Encoding",
+                HeaderFieldInner::ContentTransferEncoding(ContentTransferEncoding::EightBit),
+            )
+            .expect("Content-Transfer-Encoding is valid"),
+        );
+    }
+    Message::from_parts(header, Body::SimpleBinary(data))
+}
+
+/// The human-readable first part of a report.
+pub(crate) fn text_part(text: String) -> Message<'static> {
+    let mut header = vec![];
+    if text.is_ascii() {
+        header.push(content_type("text", "plain", &[("charset", "us-ascii")]));
+    } else {
+        header.push(content_type("text", "plain", &[("charset", "utf-8")]));
+        header.push(
+            HeaderField::from_inner(
+                "Content-Transfer-Encoding",
+                HeaderFieldInner::ContentTransferEncoding(ContentTransferEncoding::QuotedPrintable),
+            )
+            .expect("Content-Transfer-Encoding is valid"),
+        );
+    }
+    Message::from_parts(header, Body::SimpleText(text))
+}
+
+/// The optional third part of a report, containing `original` or its header.
+pub(crate) fn returned_part(original: &Message, headers_only: bool) -> Message<'static> {
+    if headers_only {
+        let mut data = vec![];
+        for hf in original.header() {
+            hf.write(&mut data);
+        }
+        simple_part("text", "rfc822-headers", data)
+    } else {
+        simple_part("message", "rfc822", original.to_bytes())
+    }
+}
+
+/// Put `parts` together into a report, adding the MIME fields to `header`.
+pub(crate) fn assemble_report(
+    mut header: Vec<HeaderField<'static>>,
+    report_type: &str,
+    parts: Vec<Message<'static>>,
+) -> Message<'static> {
+    // Pick a boundary that doesn't occur in any of the parts.
+    let contents: Vec<_> = parts.iter().map(Message::to_bytes).collect();
+    let boundary = (0..)
+        .map(|n| format!("=_report_{}", n))
+        .find(|boundary| {
+            contents.iter().all(|data| {
+                !data
+                    .windows(boundary.len())
+                    .any(|window| window == boundary.as_bytes())
+            })
+        })
+        .expect("some boundary is unused");
+    header.push(unstructured("MIME-Version", "1.0").expect("MIME-Version is valid"));
+    header.push(content_type(
+        "multipart",
+        "report",
+        &[("report-type", report_type), ("boundary", &boundary)],
+    ));
+    Message::from_parts(
+        header,
+        Body::Multipart {
+            preamble: Cow::Borrowed(b""),
+            parts,
+            epilogue: Cow::Borrowed(b""),
+        },
+    )
+}
@@ -0,0 +1,562 @@
+//! Addressing of MIME parts by IMAP section specifiers (RFC 3501 section 6.4.5).
+//!
+//! Parts are numbered from 1 within each multipart, and nested parts are
+//! addressed by joining the numbers with dots: `2.1.3` is the third part of
+//! the first part of the second part. A message that is not multipart has
+//! exactly one part, `1`, which is its body.
+//!
+//! An attached message (a message/rfc822 part) isn't parsed along with the
+//! message containing it, so `Message::parts`, `part` and `part_mut` treat it
+//! as a single part and stop there. `Message::section` parses it on demand and
+//! continues into it, as IMAP does: there, `2.1` may be the first part of a
+//! message attached as part 2.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::headers::mime::ContentType;
+use crate::parse::email::parse_message;
+use crate::{Body, Message};
+
+/// A (possibly empty) list of 1-based part numbers.
+/// The empty path refers to the whole message.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SectionPath(pub Vec<usize>);
+
+impl SectionPath {
+    pub fn depth(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl fmt::Display for SectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, n) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", n)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SectionText {
+    /// The header of the message (or of an encapsulated message).
+    Header,
+    /// The fields of the header with the given names, e.g.
+    /// `HEADER.FIELDS (From To)`.
+    HeaderFields(Vec<String>),
+    /// The fields of the header without the given names, e.g.
+    /// `HEADER.FIELDS.NOT (Received)`.
+    HeaderFieldsNot(Vec<String>),
+    /// The body of the message (or of an encapsulated message), without its header.
+    Text,
+    /// The MIME header of a body part.
+    Mime,
+}
+
+/// A full section specifier, e.g. `1.2`, `HEADER`, `2.MIME` or
+/// `HEADER.FIELDS (Subject)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SectionSpec {
+    pub path: SectionPath,
+    pub text: Option<SectionText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSection(pub String);
+
+impl FromStr for SectionSpec {
+    type Err = InvalidSection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSection(s.to_owned());
+        let mut spec = SectionSpec::default();
+        if s.is_empty() {
+            return Ok(spec);
+        }
+        // Only HEADER.FIELDS is followed by anything, a list of names.
+        let (s, list) = match s.split_once(' ') {
+            Some((s, list)) => (s, Some(list)),
+            None => (s, None),
+        };
+        let mut components = s.split('.').peekable();
+        while let Some(component) = components.next() {
+            let text = if component.eq_ignore_ascii_case("HEADER") {
+                if components
+                    .next_if(|c| c.eq_ignore_ascii_case("FIELDS"))
+                    .is_some()
+                {
+                    let not = components
+                        .next_if(|c| c.eq_ignore_ascii_case("NOT"))
+                        .is_some();
+                    let names = list.and_then(header_list).ok_or_else(invalid)?;
+                    Some(if not {
+                        SectionText::HeaderFieldsNot(names)
+                    } else {
+                        SectionText::HeaderFields(names)
+                    })
+                } else {
+                    Some(SectionText::Header)
+                }
+            } else if component.eq_ignore_ascii_case("TEXT") {
+                Some(SectionText::Text)
+            } else if component.eq_ignore_ascii_case("MIME") {
+                Some(SectionText::Mime)
+            } else {
+                None
+            };
+            match text {
+                Some(text) => {
+                    // The subsection must be last, and MIME only makes sense for a body part.
+                    if components.peek().is_some()
+                        || (text == SectionText::Mime && spec.path.0.is_empty())
+                    {
+                        return Err(invalid());
+                    }
+                    spec.text = Some(text);
+                }
+                None => {
+                    if !component.bytes().all(|ch| ch.is_ascii_digit()) {
+                        return Err(invalid());
+                    }
+                    match component.parse() {
+                        Ok(n) if n > 0 => spec.path.0.push(n),
+                        _ => return Err(invalid()),
+                    }
+                }
+            }
+        }
+        let has_list = matches!(
+            spec.text,
+            Some(SectionText::HeaderFields(_)) | Some(SectionText::HeaderFieldsNot(_))
+        );
+        if list.is_some() != has_list {
+            return Err(invalid());
+        }
+        Ok(spec)
+    }
+}
+
+/// Parse a parenthesized list of field names, e.g. `(From To)`.
+fn header_list(s: &str) -> Option<Vec<String>> {
+    let names = s.strip_prefix('(')?.strip_suffix(')')?;
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|ch| ch.is_ascii_graphic() && !b":()".contains(&ch))
+    };
+    names
+        .split(' ')
+        .map(|name| Some(name.to_owned()).filter(|name| is_name(name)))
+        .collect()
+}
+
+impl fmt::Display for SectionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(text) = &self.text {
+            if !self.path.0.is_empty() {
+                write!(f, ".")?;
+            }
+            match text {
+                SectionText::Header => write!(f, "HEADER")?,
+                SectionText::HeaderFields(names) => {
+                    write!(f, "HEADER.FIELDS ({})", names.join(" "))?
+                }
+                SectionText::HeaderFieldsNot(names) => {
+                    write!(f, "HEADER.FIELDS.NOT ({})", names.join(" "))?
+                }
+                SectionText::Text => write!(f, "TEXT")?,
+                SectionText::Mime => write!(f, "MIME")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A MIME part, as yielded by `Message::parts`.
+#[derive(Clone, Debug)]
+pub struct Part<'m, 'a> {
+    pub section: SectionPath,
+    /// The Content-Type of the enclosing multipart, if any.
+    pub parent_content_type: Option<&'m ContentType<'a>>,
+    pub message: &'m Message<'a>,
+}
+
+impl<'m, 'a> Part<'m, 'a> {
+    pub fn depth(&self) -> usize {
+        self.section.depth()
+    }
+}
+
+/// Depth-first, pre-order iterator over the parts of a message.
+pub struct Parts<'m, 'a> {
+    // Multiparts currently being walked, with the number
+    // of children of each that have already been visited.
+    stack: Vec<(&'m Message<'a>, usize)>,
+    // The body of a non-multipart message, which is its only part.
+    single: Option<&'m Message<'a>>,
+}
+
+impl<'m, 'a> Iterator for Parts<'m, 'a> {
+    type Item = Part<'m, 'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(message) = self.single.take() {
+            return Some(Part {
+                section: SectionPath(vec![1]),
+                parent_content_type: None,
+                message,
+            });
+        }
+        loop {
+            let (container, visited) = self.stack.last_mut()?;
+            let container: &'m Message<'a> = container;
+            let children = match container.body() {
+                Body::Multipart { parts, .. } => parts,
+                _ => unreachable!("only multiparts are pushed on the stack"),
+            };
+            if *visited == children.len() {
+                self.stack.pop();
+                continue;
+            }
+            let child = &children[*visited];
+            *visited += 1;
+            let section = SectionPath(self.stack.iter().map(|(_, n)| *n).collect());
+            if let Body::Multipart { .. } = child.body() {
+                self.stack.push((child, 0));
+            }
+            return Some(Part {
+                section,
+                parent_content_type: container.content_type(),
+                message: child,
+            });
+        }
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Iterate over all the MIME parts of this message (including nested
+    /// multiparts themselves), in the order of their section numbers.
+    /// An attached message/rfc822 part is yielded, but not the parts of the
+    /// message it encapsulates.
+    pub fn parts<'m>(&'m self) -> Parts<'m, 'a> {
+        match self.body() {
+            Body::Multipart { .. } => Parts {
+                stack: vec![(self, 0)],
+                single: None,
+            },
+            _ => Parts {
+                stack: vec![],
+                single: Some(self),
+            },
+        }
+    }
+
+    /// Look up a part by its section path. The empty path refers to the
+    /// whole message. Unlike `section`, this doesn't continue into an attached
+    /// message/rfc822 part, so for one attached as part 2, `2` is found but
+    /// `2.1` isn't.
+    pub fn part(&self, path: &SectionPath) -> Option<&Message<'a>> {
+        let mut cur = self;
+        for (depth, n) in path.0.iter().copied().enumerate() {
+            cur = match cur.body() {
+                Body::Multipart { parts, .. } => parts.get(n.checked_sub(1)?)?,
+                _ if depth == 0 && n == 1 => cur,
+                _ => return None,
+            };
+        }
+        Some(cur)
+    }
+
+    /// Like `part`, but allows the part to be edited.
+    pub fn part_mut(&mut self, path: &SectionPath) -> Option<&mut Message<'a>> {
+        let mut cur = self;
+        for (depth, n) in path.0.iter().copied().enumerate() {
+            match cur.body() {
+                Body::Multipart { .. } => {}
+                _ if depth == 0 && n == 1 => continue,
+                _ => return None,
+            }
+            cur = match cur.body_mut() {
+                Body::Multipart { parts, .. } => parts.get_mut(n.checked_sub(1)?)?,
+                _ => unreachable!(),
+            };
+        }
+        Some(cur)
+    }
+
+    /// The contents of the section `spec`, as IMAP's `BODY[<spec>]` returns
+    /// them: the message or part exactly as it appeared in the input, still
+    /// transfer-encoded. Headers include the blank line that ends them.
+    /// Unlike `part`, part numbers continue into the message that a
+    /// message/rfc822 part encapsulates, and `HEADER`, `TEXT` and
+    /// `HEADER.FIELDS` are only valid for the whole message or such a part.
+    /// Empty for messages built with `from_parts`.
+    pub fn section(&self, spec: &SectionSpec) -> Option<Cow<'a, [u8]>> {
+        if spec.path.0.is_empty() {
+            return match &spec.text {
+                None => Some(slice(&self.raw, ..)),
+                Some(SectionText::Mime) => None,
+                Some(text) => self.message_text(text),
+            };
+        }
+        let mut cur = self;
+        for (depth, n) in spec.path.0.iter().copied().enumerate() {
+            cur = match cur.body() {
+                Body::Multipart { parts, .. } => parts.get(n.checked_sub(1)?)?,
+                _ if depth == 0 && n == 1 => cur,
+                _ => {
+                    let rest = SectionSpec {
+                        path: SectionPath(spec.path.0[depth..].to_vec()),
+                        text: spec.text.clone(),
+                    };
+                    return cur.encapsulated(|message| message.section(&rest));
+                }
+            };
+        }
+        match &spec.text {
+            None => Some(slice(&cur.raw, cur.header_len()..)),
+            Some(SectionText::Mime) => Some(slice(&cur.raw, ..cur.header_len())),
+            Some(text) => cur.encapsulated(|message| message.message_text(text)),
+        }
+    }
+
+    /// The `HEADER`, `HEADER.FIELDS` or `TEXT` section of this message.
+    fn message_text(&self, text: &SectionText) -> Option<Cow<'a, [u8]>> {
+        let selected = |names: &[String], wanted: bool| {
+            let mut out = vec![];
+            for hf in self.header() {
+                let found = names
+                    .iter()
+                    .any(|name| hf.name().0.eq_ignore_ascii_case(name.as_bytes()));
+                if found == wanted {
+                    hf.write(&mut out);
+                }
+            }
+            out.extend_from_slice(b"\r\n");
+            Cow::Owned(out)
+        };
+        match text {
+            SectionText::Header => Some(slice(&self.raw, ..self.header_len())),
+            SectionText::HeaderFields(names) => Some(selected(names, true)),
+            SectionText::HeaderFieldsNot(names) => Some(selected(names, false)),
+            SectionText::Text => Some(slice(&self.raw, self.header_len()..)),
+            SectionText::Mime => None,
+        }
+    }
+
+    /// The length of the header in the input, including the blank line.
+    fn header_len(&self) -> usize {
+        self.raw.len() - self.raw_body().len()
+    }
+
+    /// Call `f` with the message that this message/rfc822 part encapsulates.
+    fn encapsulated(
+        &self,
+        f: impl for<'m> FnOnce(&Message<'m>) -> Option<Cow<'m, [u8]>>,
+    ) -> Option<Cow<'a, [u8]>> {
+        let ct = self.content_type()?;
+        if !(ct.r#type.0.eq_ignore_ascii_case(b"message")
+            && ct.subtype.0.eq_ignore_ascii_case(b"rfc822"))
+        {
+            return None;
+        }
+        let body = self.header_len()..;
+        match &self.raw {
+            Cow::Borrowed(data) => f(&parse_message(&data[body]).ok()?),
+            Cow::Owned(data) => {
+                let section = f(&parse_message(&data[body]).ok()?)?;
+                Some(Cow::Owned(section.into_owned()))
+            }
+        }
+    }
+}
+
+/// Part of `data`, borrowed if it is.
+fn slice<'a>(
+    data: &Cow<'a, [u8]>,
+    range: impl std::slice::SliceIndex<[u8], Output = [u8]>,
+) -> Cow<'a, [u8]> {
+    match data {
+        Cow::Borrowed(data) => Cow::Borrowed(&data[range]),
+        Cow::Owned(data) => Cow::Owned(data[range].to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+    use nom::Parser;
+
+    use super::{SectionPath, SectionSpec, SectionText};
+    use crate::parse::email::{message, parse_message};
+
+    #[test]
+    fn test_section_spec() {
+        for s in &[
+            "",
+            "1",
+            "2.1.3",
+            "HEADER",
+            "TEXT",
+            "1.2.MIME",
+            "3.HEADER",
+            "HEADER.FIELDS (From To)",
+            "2.HEADER.FIELDS.NOT (Received)",
+        ] {
+            let spec: SectionSpec = s.parse().unwrap();
+            assert_eq!(&spec.to_string(), s);
+        }
+        let spec: SectionSpec = "4.2.text".parse().unwrap();
+        assert_eq!(spec.path, SectionPath(vec![4, 2]));
+        assert_eq!(spec.text, Some(SectionText::Text));
+        let spec: SectionSpec = "header.fields.not (x-spam)".parse().unwrap();
+        assert_eq!(
+            spec.text,
+            Some(SectionText::HeaderFieldsNot(vec!["x-spam".to_owned()]))
+        );
+        for s in &[
+            "0",
+            "1..2",
+            "MIME",
+            "HEADER.1",
+            "1.x",
+            "+1",
+            "HEADER.FIELDS",
+            "HEADER.FIELDS ()",
+            "HEADER.FIELDS (From",
+            "HEADER (From)",
+            "HEADER.FIELDS.1 (From)",
+        ] {
+            assert!(s.parse::<SectionSpec>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parts() {
+        let input = "Content-Type: multipart/mixed; boundary=outer\r\n\
+                     \r\n\
+                     --outer\r\n\
+                     \r\n\
+                     First\r\n\
+                     --outer\r\n\
+                     Content-Type: multipart/alternative; boundary=inner\r\n\
+                     \r\n\
+                     --inner\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     Plain\r\n\
+                     --inner\r\n\
+                     Content-Type: text/html\r\n\
+                     \r\n\
+                     <p>HTML</p>\r\n\
+                     --inner--\r\n\
+                     --outer--\r\n";
+        let (_, m) = all_consuming(message()).parse(input.as_bytes()).unwrap();
+        let sections: Vec<_> = m
+            .parts()
+            .map(|p| {
+                let parent = p.parent_content_type.unwrap();
+                (p.section.to_string(), p.depth(), parent.subtype.0.to_vec())
+            })
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                ("1".to_owned(), 1, b"mixed".to_vec()),
+                ("2".to_owned(), 1, b"mixed".to_vec()),
+                ("2.1".to_owned(), 2, b"alternative".to_vec()),
+                ("2.2".to_owned(), 2, b"alternative".to_vec()),
+            ]
+        );
+        for part in m.parts() {
+            assert!(std::ptr::eq(m.part(&part.section).unwrap(), part.message));
+        }
+        assert!(m.part(&SectionPath(vec![3])).is_none());
+        assert!(m.part(&SectionPath(vec![1, 1])).is_none());
+
+        let html = m.part(&SectionPath(vec![2, 2])).unwrap();
+        assert_eq!(html.content_type().unwrap().subtype.0, *b"html");
+
+        let (_, simple) = all_consuming(message())
+            .parse(b"Subject: hi\r\n\r\nbody\r\n")
+            .unwrap();
+        let parts: Vec<_> = simple.parts().map(|p| p.section).collect();
+        assert_eq!(parts, vec![SectionPath(vec![1])]);
+        assert!(std::ptr::eq(
+            simple.part(&SectionPath(vec![1])).unwrap(),
+            &simple
+        ));
+    }
+
+    #[test]
+    fn test_section() {
+        let input = "Subject: outer\r\n\
+                     Content-Type: multipart/mixed; boundary=b\r\n\
+                     \r\n\
+                     --b\r\n\
+                     \r\n\
+                     First\r\n\
+                     --b\r\n\
+                     Content-Type: message/rfc822\r\n\
+                     \r\n\
+                     Subject: inner\r\n\
+                     From: a@example.com\r\n\
+                     Content-Type: multipart/alternative; boundary=c\r\n\
+                     \r\n\
+                     --c\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     Plain\r\n\
+                     --c--\r\n\
+                     --b--\r\n";
+        let m = parse_message(input.as_bytes()).unwrap();
+        let section = |s: &str| {
+            m.section(&s.parse().unwrap())
+                .map(|data| String::from_utf8(data.into_owned()).unwrap())
+        };
+        assert_eq!(section("").unwrap(), input);
+        assert!(section("HEADER").unwrap().starts_with("Subject: outer\r\n"));
+        assert!(section("TEXT").unwrap().starts_with("--b\r\n"));
+        assert_eq!(section("1").unwrap(), "First");
+        assert_eq!(section("1.MIME").unwrap(), "\r\n");
+        assert_eq!(section("1.HEADER"), None);
+        assert_eq!(
+            section("2.MIME").unwrap(),
+            "Content-Type: message/rfc822\r\n\r\n"
+        );
+        assert!(section("2").unwrap().starts_with("Subject: inner\r\n"));
+        assert_eq!(
+            section("2.HEADER.FIELDS (FROM subject)").unwrap(),
+            "Subject: inner\r\nFrom: a@example.com\r\n\r\n"
+        );
+        assert_eq!(
+            section("2.HEADER.FIELDS.NOT (Content-Type Subject)").unwrap(),
+            "From: a@example.com\r\n\r\n"
+        );
+        assert!(section("2.TEXT").unwrap().starts_with("--c\r\n"));
+        // Part numbers continue into the encapsulated message, but only here.
+        assert_eq!(section("2.1").unwrap(), "Plain");
+        let attached = m.part(&SectionPath(vec![2])).unwrap();
+        assert_eq!(attached.content_type().unwrap().subtype.0, *b"rfc822");
+        assert!(m.part(&SectionPath(vec![2, 1])).is_none());
+        let parts: Vec<_> = m.parts().map(|p| p.section.to_string()).collect();
+        assert_eq!(parts, vec!["1", "2"]);
+        assert_eq!(
+            section("2.1.MIME").unwrap(),
+            "Content-Type: text/plain\r\n\r\n"
+        );
+        assert_eq!(section("2.2"), None);
+        assert_eq!(section("3"), None);
+
+        let owned = m.clone().into_owned();
+        let spec = "2.1".parse().unwrap();
+        assert_eq!(owned.section(&spec).unwrap(), &b"Plain"[..]);
+    }
+}
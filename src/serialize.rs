@@ -0,0 +1,155 @@
+//! Serde support for the parsed message tree (enabled by the `serde` feature).
+//!
+//! The JSON shape is the one produced by serde's default derives, with
+//! the following conventions, which we intend to keep stable:
+//!
+//! * Byte strings (header names, raw values, display names, multipart
+//!   preambles and epilogues, binary bodies, ...) are serialized as a
+//!   plain string when they are valid UTF-8, and otherwise as an object
+//!   `{"base64": "..."}` holding the standard base64 encoding of the bytes.
+//! * Enums are externally tagged: e.g. a `From` header value is
+//!   `{"From": [...]}`, and a text body is `{"SimpleText": "..."}`.
+//! * Dates are RFC 3339 strings, as serialized by `chrono`.
+//!
+//! The input a message was parsed from (`Message::raw` and
+//! `Message::raw_body`) isn't serialized, since every part would repeat
+//! its share of it; after deserializing, it is empty, as for a message built
+//! with `Message::from_parts`.
+//!
+//! Deserialization always produces owned data, so the result can be
+//! treated like the output of `Message::into_owned`.
+
+use std::borrow::Cow;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{ByteStr, ByteString, Message};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum BytesRepr<'a> {
+    Text(Cow<'a, str>),
+    Base64 { base64: String },
+}
+
+impl Serialize for ByteStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match std::str::from_utf8(&self.0) {
+            Ok(s) => BytesRepr::Text(Cow::Borrowed(s)),
+            Err(_) => BytesRepr::Base64 {
+                base64: base64::encode(&self.0),
+            },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl Serialize for ByteString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match BytesRepr::deserialize(deserializer)? {
+            BytesRepr::Text(s) => Ok(ByteString(s.into_owned().into_bytes())),
+            BytesRepr::Base64 { base64 } => base64::decode(base64)
+                .map(ByteString)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+// `Message` derives its (de)serialization as inherent functions, so that
+// the index of its Content-Type field, which isn't serialized, can be
+// recomputed rather than trusted.
+impl Serialize for Message<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Message::serialize(self, serializer)
+    }
+}
+
+impl<'de, 'a> Deserialize<'de> for Message<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut message = Message::deserialize(deserializer)?;
+        message.reindex();
+        Ok(message)
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on raw byte fields
+/// (`Vec<u8>`, `Cow<[u8]>`), so that they get the same representation
+/// as `ByteStr`.
+pub(crate) mod bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::{ByteStr, ByteString};
+
+    pub fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: AsRef<[u8]>,
+    {
+        ByteStr::from_slice(bytes.as_ref()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: From<Vec<u8>>,
+    {
+        ByteString::deserialize(deserializer).map(|bs| bs.0.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+    use nom::Parser;
+
+    use crate::parse::email::message;
+    use crate::{Body, ByteString, Message};
+
+    #[test]
+    fn test_bytes_repr() {
+        let json = serde_json::to_string(&ByteString(b"hello".to_vec())).unwrap();
+        assert_eq!(json, r#""hello""#);
+        let json = serde_json::to_string(&ByteString(vec![0xff, 0xfe])).unwrap();
+        assert_eq!(json, r#"{"base64":"//4="}"#);
+        let bs: ByteString = serde_json::from_str(&json).unwrap();
+        assert_eq!(bs.0, vec![0xff, 0xfe]);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let input = "From: Brennan Vincent <brennan@umanwizard.com>\r\n\
+                     Date: Mon, 1 Mar 2021 12:00:00 +0000\r\n\
+                     Content-Type: text/plain; charset=utf-8\r\n\
+                     \r\n\
+                     Hello!\r\n";
+        let (_, parsed) = all_consuming(message()).parse(input.as_bytes()).unwrap();
+        let json = serde_json::to_string(&parsed).unwrap();
+        let round_tripped: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_tripped.header().len(), 3);
+        assert_eq!(round_tripped.header()[0].name().0, *b"From");
+        match round_tripped.body() {
+            Body::SimpleText(text) => assert_eq!(text, "Hello!\r\n"),
+            _ => panic!("expected text body"),
+        }
+        assert_eq!(json, serde_json::to_string(&round_tripped).unwrap());
+        assert!(!json.contains("\"raw\""));
+        assert!(round_tripped.raw().is_empty() && round_tripped.raw_body().is_empty());
+
+        // The Content-Type field is found again, wherever it now is.
+        assert!(!json.contains("content_type"));
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["header"].as_array_mut().unwrap().remove(0);
+        let edited: Message = serde_json::from_value(value).unwrap();
+        assert_eq!(edited.content_type().unwrap().subtype.0, *b"plain");
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["header"].as_array_mut().unwrap().truncate(1);
+        let edited: Message = serde_json::from_value(value).unwrap();
+        assert!(edited.content_type().is_none());
+    }
+}
@@ -0,0 +1,534 @@
+//! A blocking SMTP client for submitting messages.
+//!
+//! The client works over any stream that can be read and written, such as a
+//! `TcpStream` or a TLS stream wrapping one; establishing the connection (and
+//! negotiating TLS) is left to the caller.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+use super::envelope::{BodyType, ReversePath};
+use super::{dot_stuff, Connection, Reply};
+use crate::{Message, SmtpEnvelope};
+
+#[derive(Debug)]
+pub enum SmtpError {
+    Io(io::Error),
+    /// The server replied with an unexpected code to `command`.
+    Rejected {
+        command: String,
+        reply: Reply,
+    },
+    /// Every recipient was rejected, so no message was sent. The replies
+    /// to the RCPT commands are given in the order of the recipients.
+    AllRecipientsRejected(Vec<(String, Reply)>),
+    /// The message is larger than the limit advertised by the server.
+    MessageTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    /// The message or envelope needs an extension the server doesn't offer,
+    /// e.g. `8BITMIME` for a message with 8-bit content.
+    Unsupported(&'static str),
+}
+
+impl From<io::Error> for SmtpError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The service extensions advertised in a reply to EHLO.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// The parameters of each extension, keyed by the uppercased keyword.
+    pub keywords: BTreeMap<String, Vec<String>>,
+}
+
+impl Extensions {
+    fn parse(reply: &Reply) -> Self {
+        let mut keywords = BTreeMap::new();
+        // The first line is the server's greeting.
+        for line in reply.lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            // Some old servers advertise `AUTH=PLAIN LOGIN`.
+            let (keyword, first_param) = match keyword.find('=') {
+                Some(i) => (&keyword[..i], Some(&keyword[i + 1..])),
+                None => (keyword, None),
+            };
+            let params = keywords
+                .entry(keyword.to_ascii_uppercase())
+                .or_insert_with(Vec::new);
+            params.extend(first_param.into_iter().chain(words).map(str::to_owned));
+        }
+        Self { keywords }
+    }
+
+    pub fn supports(&self, keyword: &str) -> bool {
+        self.keywords.contains_key(&keyword.to_ascii_uppercase())
+    }
+
+    /// The maximum message size from the `SIZE` extension (RFC 1870).
+    /// `Some(0)` means the server supports the extension but has no fixed limit.
+    pub fn size(&self) -> Option<u64> {
+        let params = self.keywords.get("SIZE")?;
+        Some(params.first().and_then(|s| s.parse().ok()).unwrap_or(0))
+    }
+
+    pub fn auth_mechanisms(&self) -> impl Iterator<Item = &str> {
+        self.keywords
+            .get("AUTH")
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuthMechanism {
+    /// RFC 4616
+    Plain,
+    /// The obsolete but widely deployed `LOGIN` mechanism.
+    Login,
+}
+
+impl AuthMechanism {
+    fn name(self) -> &'static str {
+        match self {
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN",
+        }
+    }
+}
+
+/// The outcome of a successful `send`.
+#[derive(Clone, Debug)]
+pub struct SendReport {
+    /// The reply to each RCPT command, in the order of the recipients.
+    /// The message was only delivered to those with positive replies.
+    pub recipients: Vec<(String, Reply)>,
+    /// The reply to the end of the message data.
+    pub data: Reply,
+}
+
+impl SendReport {
+    pub fn accepted(&self) -> impl Iterator<Item = &str> {
+        self.recipients
+            .iter()
+            .filter(|(_, reply)| reply.is_positive())
+            .map(|(rcpt, _)| rcpt.as_str())
+    }
+}
+
+pub struct SmtpClient<S> {
+    conn: Connection<S>,
+    greeting: Reply,
+    extensions: Option<Extensions>,
+}
+
+impl<S: Read + Write> SmtpClient<S> {
+    /// Start a session on `stream`, reading the server's greeting.
+    pub fn connect(stream: S) -> Result<Self, SmtpError> {
+        let mut conn = Connection::new(stream);
+        let greeting = conn.read_reply()?;
+        if greeting.code != 220 {
+            return Err(SmtpError::Rejected {
+                command: String::new(),
+                reply: greeting,
+            });
+        }
+        Ok(Self {
+            conn,
+            greeting,
+            extensions: None,
+        })
+    }
+
+    pub fn greeting(&self) -> &Reply {
+        &self.greeting
+    }
+
+    /// The extensions offered by the server, once `ehlo` has succeeded.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.extensions.as_ref()
+    }
+
+    /// Identify ourselves as `domain`. If the server doesn't understand EHLO,
+    /// fall back to HELO, which offers no extensions.
+    pub fn ehlo(&mut self, domain: &str) -> Result<&Extensions, SmtpError> {
+        let ehlo = format!("EHLO {}", domain);
+        let reply = self.command(&ehlo)?;
+        let extensions = if reply.code == 250 {
+            Extensions::parse(&reply)
+        } else if reply.is_permanent() {
+            let helo = format!("HELO {}", domain);
+            let reply = self.command(&helo)?;
+            expect(&helo, reply, 250)?;
+            Extensions::default()
+        } else {
+            return Err(SmtpError::Rejected {
+                command: ehlo,
+                reply,
+            });
+        };
+        // A new EHLO (e.g. after STARTTLS or AUTH) may offer different ones.
+        Ok(self.extensions.insert(extensions))
+    }
+
+    /// Authenticate with the given mechanism, which the server must advertise.
+    pub fn auth(
+        &mut self,
+        mechanism: AuthMechanism,
+        username: &str,
+        password: &str,
+    ) -> Result<(), SmtpError> {
+        let offered = self
+            .extensions
+            .iter()
+            .flat_map(Extensions::auth_mechanisms)
+            .any(|m| m.eq_ignore_ascii_case(mechanism.name()));
+        if !offered {
+            return Err(SmtpError::Unsupported("AUTH"));
+        }
+        match mechanism {
+            AuthMechanism::Plain => {
+                let response = base64::encode(format!("\0{}\0{}", username, password));
+                let reply = self.command(&format!("AUTH PLAIN {}", response))?;
+                expect("AUTH PLAIN", reply, 235)?;
+            }
+            AuthMechanism::Login => {
+                // The server prompts for each value in turn.
+                let reply = self.command("AUTH LOGIN")?;
+                expect("AUTH LOGIN", reply, 334)?;
+                let reply = self.command(&base64::encode(username))?;
+                expect("AUTH LOGIN", reply, 334)?;
+                let reply = self.command(&base64::encode(password))?;
+                expect("AUTH LOGIN", reply, 235)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `message`, which must already be serialized, to the recipients of
+    /// `envelope`. The message is sent as long as at least one recipient is
+    /// accepted; check the returned report for the others.
+    pub fn send(
+        &mut self,
+        envelope: &SmtpEnvelope,
+        message: &[u8],
+    ) -> Result<SendReport, SmtpError> {
+        let extensions = self.extensions.clone().unwrap_or_default();
+        let recipients: Vec<String> = envelope
+            .to
+            .iter()
+            .map(|rcpt| rcpt.address.to_string())
+            .collect();
+
+        let mut params = envelope.params.clone();
+        // SIZE is only a declaration, so leave it out if the server doesn't
+        // understand it, and otherwise make sure it's right.
+        params.size = None;
+        if let Some(limit) = extensions.size() {
+            let size = message.len() as u64;
+            if limit != 0 && size > limit {
+                return Err(SmtpError::MessageTooLarge { size, limit });
+            }
+            params.size = Some(size);
+        }
+        if !message.is_ascii() && params.body.is_none() {
+            params.body = Some(BodyType::EightBitMime);
+        }
+        match params.body {
+            Some(BodyType::EightBitMime) if !extensions.supports("8BITMIME") => {
+                return Err(SmtpError::Unsupported("8BITMIME"));
+            }
+            // Binary content can only be sent with BDAT, which we don't use.
+            Some(BodyType::BinaryMime) => return Err(SmtpError::Unsupported("BINARYMIME")),
+            _ => {}
+        }
+        let from_ascii = match &envelope.from {
+            ReversePath::Null => true,
+            ReversePath::Address(address) => address.is_ascii(),
+        };
+        if !from_ascii || envelope.to.iter().any(|rcpt| !rcpt.address.is_ascii()) {
+            params.smtputf8 = true;
+        }
+        if params.smtputf8 && !extensions.supports("SMTPUTF8") {
+            return Err(SmtpError::Unsupported("SMTPUTF8"));
+        }
+        let uses_dsn = params.uses_dsn() || envelope.to.iter().any(|rcpt| rcpt.params.uses_dsn());
+        if uses_dsn && !extensions.supports("DSN") {
+            return Err(SmtpError::Unsupported("DSN"));
+        }
+
+        let mail = format!("MAIL FROM:{}{}", envelope.from, params);
+        let rcpts: Vec<String> = envelope
+            .to
+            .iter()
+            .map(|rcpt| format!("RCPT TO:<{}>{}", rcpt.address, rcpt.params))
+            .collect();
+
+        // With PIPELINING (RFC 2920), everything up to and including DATA
+        // can be sent at once; otherwise, wait for each reply in turn.
+        let (mail_reply, rcpt_replies, data_reply) = if extensions.supports("PIPELINING") {
+            let mut batch = String::new();
+            for command in std::iter::once(&mail).chain(rcpts.iter()) {
+                batch.push_str(command);
+                batch.push_str("\r\n");
+            }
+            batch.push_str("DATA\r\n");
+            self.conn.write_all(batch.as_bytes())?;
+            self.conn.flush()?;
+            let mail_reply = self.conn.read_reply()?;
+            let rcpt_replies = (0..rcpts.len())
+                .map(|_| self.conn.read_reply())
+                .collect::<io::Result<Vec<_>>>()?;
+            let data_reply = self.conn.read_reply()?;
+            (mail_reply, rcpt_replies, Some(data_reply))
+        } else {
+            let mail_reply = self.command(&mail)?;
+            let mut rcpt_replies = vec![];
+            if mail_reply.code == 250 {
+                for rcpt in rcpts.iter() {
+                    rcpt_replies.push(self.command(rcpt)?);
+                }
+            }
+            (mail_reply, rcpt_replies, None)
+        };
+
+        let any_accepted = rcpt_replies.iter().any(Reply::is_positive);
+        let failure = if mail_reply.code != 250 {
+            Some(SmtpError::Rejected {
+                command: mail,
+                reply: mail_reply,
+            })
+        } else if !any_accepted {
+            Some(SmtpError::AllRecipientsRejected(
+                recipients
+                    .iter()
+                    .cloned()
+                    .zip(rcpt_replies.iter().cloned())
+                    .collect(),
+            ))
+        } else {
+            None
+        };
+        if let Some(failure) = failure {
+            match data_reply {
+                // The server is waiting for the message even though it has
+                // nowhere to deliver it; end it right away.
+                Some(reply) if reply.code == 354 => {
+                    self.conn.write_all(b".\r\n")?;
+                    self.conn.flush()?;
+                    self.conn.read_reply()?;
+                }
+                _ => {
+                    let reply = self.command("RSET")?;
+                    expect("RSET", reply, 250)?;
+                }
+            }
+            return Err(failure);
+        }
+
+        let data_reply = match data_reply {
+            Some(reply) => reply,
+            None => self.command("DATA")?,
+        };
+        expect("DATA", data_reply, 354)?;
+        let mut data = Vec::with_capacity(message.len() + message.len() / 64 + 5);
+        dot_stuff(message, &mut data);
+        self.conn.write_all(&data)?;
+        self.conn.flush()?;
+        let reply = self.conn.read_reply()?;
+        let data = expect("DATA", reply, 250)?;
+
+        Ok(SendReport {
+            recipients: recipients.into_iter().zip(rcpt_replies).collect(),
+            data,
+        })
+    }
+
+    /// Serialize `message` and send it like `send`.
+    pub fn send_message(
+        &mut self,
+        envelope: &SmtpEnvelope,
+        message: &Message,
+    ) -> Result<SendReport, SmtpError> {
+        self.send(envelope, &message.to_bytes())
+    }
+
+    /// End the session and return the underlying stream.
+    pub fn quit(mut self) -> Result<S, SmtpError> {
+        let reply = self.command("QUIT")?;
+        expect("QUIT", reply, 221)?;
+        Ok(self.conn.stream)
+    }
+
+    fn command(&mut self, command: &str) -> io::Result<Reply> {
+        self.conn.write_all(command.as_bytes())?;
+        self.conn.write_all(b"\r\n")?;
+        self.conn.flush()?;
+        self.conn.read_reply()
+    }
+}
+
+fn expect(command: &str, reply: Reply, code: u16) -> Result<Reply, SmtpError> {
+    if reply.code == code {
+        Ok(reply)
+    } else {
+        Err(SmtpError::Rejected {
+            command: command.to_owned(),
+            reply,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use super::{AuthMechanism, SmtpClient, SmtpError};
+    use crate::smtp::envelope::ReversePath;
+    use crate::SmtpEnvelope;
+
+    /// A server that plays back canned replies, recording what it was sent.
+    struct FakeServer {
+        replies: Cursor<Vec<u8>>,
+        received: Vec<u8>,
+    }
+
+    impl FakeServer {
+        fn new(replies: &str) -> Self {
+            Self {
+                replies: Cursor::new(replies.as_bytes().to_vec()),
+                received: vec![],
+            }
+        }
+    }
+
+    impl Read for FakeServer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeServer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn envelope(to: &str) -> SmtpEnvelope {
+        let from = "brennan@umanwizard.com".parse().unwrap();
+        SmtpEnvelope::new(ReversePath::Address(from), vec![to.parse().unwrap()])
+    }
+
+    #[test]
+    fn test_pipelined_send() {
+        let server = FakeServer::new(
+            "220 mx.example.com ESMTP\r\n\
+             250-mx.example.com\r\n\
+             250-PIPELINING\r\n\
+             250-SIZE 1000\r\n\
+             250-8BITMIME\r\n\
+             250 AUTH=PLAIN LOGIN\r\n\
+             235 ok\r\n\
+             250 sender ok\r\n\
+             250 recipient ok\r\n\
+             354 go ahead\r\n\
+             250 queued as 1234\r\n\
+             221 bye\r\n",
+        );
+        let mut client = SmtpClient::connect(server).unwrap();
+        let extensions = client.ehlo("client.example.com").unwrap();
+        assert_eq!(extensions.size(), Some(1000));
+        assert!(extensions.supports("8bitmime"));
+        assert_eq!(
+            extensions.auth_mechanisms().collect::<Vec<_>>(),
+            vec!["PLAIN", "LOGIN"]
+        );
+        client.auth(AuthMechanism::Plain, "user", "pass").unwrap();
+        let report = client
+            .send(
+                &envelope("mary@example.com"),
+                "Subject: caf\u{e9}\r\n\r\n.hidden\r\n".as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            report.accepted().collect::<Vec<_>>(),
+            vec!["mary@example.com"]
+        );
+        assert_eq!(report.data.lines, vec!["queued as 1234"]);
+        let server = client.quit().unwrap();
+        assert_eq!(
+            String::from_utf8(server.received).unwrap(),
+            "EHLO client.example.com\r\n\
+             AUTH PLAIN AHVzZXIAcGFzcw==\r\n\
+             MAIL FROM:<brennan@umanwizard.com> SIZE=27 BODY=8BITMIME\r\n\
+             RCPT TO:<mary@example.com>\r\n\
+             DATA\r\n\
+             Subject: caf\u{e9}\r\n\
+             \r\n\
+             ..hidden\r\n\
+             .\r\n\
+             QUIT\r\n"
+        );
+    }
+
+    #[test]
+    fn test_ehlo_again() {
+        let server = FakeServer::new(
+            "220 mx.example.com ESMTP\r\n\
+             250-mx.example.com\r\n\
+             250-SIZE 1000\r\n\
+             250 AUTH=PLAIN\r\n\
+             235 ok\r\n\
+             250-mx.example.com\r\n\
+             250 SIZE 2000\r\n",
+        );
+        let mut client = SmtpClient::connect(server).unwrap();
+        client.ehlo("client.example.com").unwrap();
+        client.auth(AuthMechanism::Plain, "user", "pass").unwrap();
+        let extensions = client.ehlo("client.example.com").unwrap();
+        assert_eq!(extensions.size(), Some(2000));
+        let extensions = client.extensions().unwrap();
+        assert_eq!(extensions.size(), Some(2000));
+        assert_eq!(extensions.auth_mechanisms().count(), 0);
+    }
+
+    #[test]
+    fn test_rejected_recipient() {
+        let server = FakeServer::new(
+            "220 mx.example.com\r\n\
+             502 what?\r\n\
+             250 mx.example.com\r\n\
+             250 ok\r\n\
+             550 no such user\r\n\
+             250 reset\r\n",
+        );
+        let mut client = SmtpClient::connect(server).unwrap();
+        assert!(client
+            .ehlo("client.example.com")
+            .unwrap()
+            .keywords
+            .is_empty());
+        match client.send(&envelope("nobody@example.com"), b"Subject: hi\r\n\r\n") {
+            Err(SmtpError::AllRecipientsRejected(replies)) => {
+                assert_eq!(replies.len(), 1);
+                assert_eq!(replies[0].1.code, 550);
+            }
+            other => panic!("{:?}", other),
+        }
+        match client.send(&envelope("mary@example.com"), b"Subject: \xff\r\n\r\n") {
+            Err(SmtpError::Unsupported("8BITMIME")) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
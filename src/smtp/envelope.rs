@@ -0,0 +1,737 @@
+//! The parts of an SMTP envelope: reverse- and forward-paths (RFC 5321
+//! section 4.1.2), and the parameters of the MAIL and RCPT commands defined
+//! by the SIZE (RFC 1870), 8BITMIME (RFC 6152), BINARYMIME (RFC 3030),
+//! SMTPUTF8 (RFC 6531) and DSN (RFC 3461) extensions.
+//!
+//! Addresses may contain UTF-8, as allowed by SMTPUTF8; whether that is
+//! acceptable in a given transaction is up to the caller.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::headers::address::{AddrSpec, Domain};
+use crate::parse::is_atext;
+use crate::ByteString;
+
+/// RFC 5321 section 4.5.3.1.1
+const MAX_LOCAL_PART: usize = 64;
+/// RFC 5321 section 4.5.3.1.2
+const MAX_DOMAIN: usize = 255;
+/// RFC 3461 section 4.4
+const MAX_ENVID: usize = 100;
+/// RFC 3461 section 4.2
+const MAX_ORCPT: usize = 500;
+/// The path of the postmaster of the server itself.
+const POSTMASTER: &str = "<Postmaster>";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The command argument doesn't start with a well-formed path.
+    InvalidPath(String),
+    /// A parameter is malformed, or has a value that isn't allowed.
+    InvalidParameter(String),
+    DuplicateParameter(String),
+    /// A parameter that belongs to the other command, e.g. `NOTIFY` on MAIL.
+    MisplacedParameter(String),
+}
+
+/// A mailbox as it appears in an SMTP path, e.g. `user@example.com`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SmtpAddress {
+    /// The local part, with any quoting removed.
+    pub local_part: String,
+    /// A domain name, or an address literal including its brackets. Empty
+    /// only for the recipient `<Postmaster>`, the postmaster of the server
+    /// itself.
+    pub domain: String,
+}
+
+impl SmtpAddress {
+    pub fn is_ascii(&self) -> bool {
+        self.local_part.is_ascii() && self.domain.is_ascii()
+    }
+
+    /// The address in the form used in header fields.
+    pub fn to_addr_spec(&self) -> AddrSpec<'static> {
+        let domain = match self
+            .domain
+            .strip_prefix('[')
+            .and_then(|literal| literal.strip_suffix(']'))
+        {
+            Some(literal) => Domain::Literal(ByteString(literal.as_bytes().to_vec())),
+            None => Domain::Name(Cow::Owned(ByteString(self.domain.as_bytes().to_vec()))),
+        };
+        AddrSpec {
+            local_part: Cow::Owned(ByteString(self.local_part.as_bytes().to_vec())),
+            domain,
+        }
+    }
+
+    /// Parse an address from the start of `s`, returning it and the rest of `s`.
+    fn parse_prefix(s: &str) -> Option<(Self, &str)> {
+        let (local_part, rest) = if let Some(rest) = s.strip_prefix('"') {
+            let mut local_part = String::new();
+            let mut chars = rest.char_indices();
+            loop {
+                match chars.next()? {
+                    (i, '"') => break (local_part, &rest[i + 1..]),
+                    (_, '\\') => match chars.next()? {
+                        (_, ch) if (' '..='~').contains(&ch) => local_part.push(ch),
+                        _ => return None,
+                    },
+                    (_, ch) if is_qtext(ch) => local_part.push(ch),
+                    _ => return None,
+                }
+            }
+        } else {
+            let end = s.find(|ch| !is_atext_utf8(ch) && ch != '.')?;
+            let local_part = &s[..end];
+            if !is_dot_string(local_part) {
+                return None;
+            }
+            (local_part.to_owned(), &s[end..])
+        };
+        if local_part.len() > MAX_LOCAL_PART {
+            return None;
+        }
+        let rest = rest.strip_prefix('@')?;
+        let end = if rest.starts_with('[') {
+            rest.find(']')? + 1
+        } else {
+            rest.find(|ch: char| !(ch.is_alphanumeric() || ch == '-' || ch == '.'))
+                .unwrap_or(rest.len())
+        };
+        let domain = &rest[..end];
+        if !is_domain(domain) {
+            return None;
+        }
+        let address = SmtpAddress {
+            local_part,
+            domain: domain.to_owned(),
+        };
+        Some((address, &rest[end..]))
+    }
+}
+
+impl FromStr for SmtpAddress {
+    type Err = EnvelopeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match SmtpAddress::parse_prefix(s) {
+            Some((address, "")) => Ok(address),
+            _ => Err(EnvelopeError::InvalidPath(s.to_owned())),
+        }
+    }
+}
+
+/// Formats the address as it would appear in a path, quoting the local part
+/// if necessary.
+impl fmt::Display for SmtpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_dot_string(&self.local_part) {
+            write!(f, "{}", self.local_part)?;
+        } else {
+            write!(f, "\"")?;
+            for ch in self.local_part.chars() {
+                if ch == '"' || ch == '\\' {
+                    write!(f, "\\")?;
+                }
+                write!(f, "{}", ch)?;
+            }
+            write!(f, "\"")?;
+        }
+        if self.domain.is_empty() {
+            return Ok(());
+        }
+        write!(f, "@{}", self.domain)
+    }
+}
+
+fn is_atext_utf8(ch: char) -> bool {
+    if ch.is_ascii() {
+        is_atext(ch as u8)
+    } else {
+        true
+    }
+}
+
+fn is_qtext(ch: char) -> bool {
+    // %d32-33 / %d35-91 / %d93-126, or UTF-8.
+    !ch.is_ascii() || ((' '..='~').contains(&ch) && ch != '"' && ch != '\\')
+}
+
+fn is_dot_string(s: &str) -> bool {
+    s.split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext_utf8))
+}
+
+fn is_domain(domain: &str) -> bool {
+    if domain.len() > MAX_DOMAIN {
+        return false;
+    }
+    if let Some(literal) = domain.strip_prefix('[') {
+        // dcontent is %d33-90 / %d94-126; we don't check the tagged forms further.
+        return match literal.strip_suffix(']') {
+            Some(literal) => {
+                !literal.is_empty()
+                    && literal
+                        .bytes()
+                        .all(|ch| (33..=126).contains(&ch) && !b"[\\]".contains(&ch))
+            }
+            None => false,
+        };
+    }
+    domain.split('.').all(|label| {
+        let first = label.chars().next();
+        let last = label.chars().last();
+        matches!(first, Some(ch) if ch.is_alphanumeric())
+            && matches!(last, Some(ch) if ch.is_alphanumeric())
+            && label.chars().all(|ch| ch.is_alphanumeric() || ch == '-')
+    })
+}
+
+/// Parse a path in angle brackets from the start of `s`, returning the
+/// address and the rest of `s`. The obsolete source route (`@a.example,@b.example:`)
+/// is accepted but dropped. `Ok(None)` is the null path `<>`.
+fn parse_path(s: &str) -> Result<(Option<SmtpAddress>, &str), EnvelopeError> {
+    let invalid = || EnvelopeError::InvalidPath(s.to_owned());
+    let mut rest = s.strip_prefix('<').ok_or_else(invalid)?;
+    if let Some(rest) = rest.strip_prefix('>') {
+        return Ok((None, rest));
+    }
+    if rest.starts_with('@') {
+        let colon = rest.find(':').ok_or_else(invalid)?;
+        let route_ok = rest[..colon]
+            .split(',')
+            .all(|hop| matches!(hop.strip_prefix('@'), Some(domain) if is_domain(domain)));
+        if !route_ok {
+            return Err(invalid());
+        }
+        rest = &rest[colon + 1..];
+    }
+    let (address, rest) = SmtpAddress::parse_prefix(rest).ok_or_else(invalid)?;
+    let rest = rest.strip_prefix('>').ok_or_else(invalid)?;
+    Ok((Some(address), rest))
+}
+
+/// Split the parameters after a path into keywords and values, checking the
+/// esmtp-param syntax of RFC 5321 section 4.1.2 and rejecting duplicates.
+fn parse_params(s: &str) -> Result<Vec<(String, Option<&str>)>, EnvelopeError> {
+    if !s.is_empty() && !s.starts_with(' ') {
+        return Err(EnvelopeError::InvalidParameter(s.to_owned()));
+    }
+    let mut params: Vec<(String, Option<&str>)> = vec![];
+    for param in s.split(' ').filter(|param| !param.is_empty()) {
+        let (keyword, value) = match param.find('=') {
+            Some(i) => (&param[..i], Some(&param[i + 1..])),
+            None => (param, None),
+        };
+        let keyword_ok = keyword.starts_with(|ch: char| ch.is_ascii_alphanumeric())
+            && keyword
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-');
+        let value_ok = match value {
+            None => true,
+            Some(value) => {
+                !value.is_empty()
+                    && value
+                        .chars()
+                        .all(|ch| ch > ' ' && ch != '=' && ch != '\x7f')
+            }
+        };
+        if !keyword_ok || !value_ok {
+            return Err(EnvelopeError::InvalidParameter(param.to_owned()));
+        }
+        let keyword = keyword.to_ascii_uppercase();
+        if params.iter().any(|(seen, _)| *seen == keyword) {
+            return Err(EnvelopeError::DuplicateParameter(keyword));
+        }
+        params.push((keyword, value));
+    }
+    Ok(params)
+}
+
+/// Decode xtext (RFC 3461 section 4): printable ASCII other than `+` and `=`,
+/// with other octets written as `+XX`.
+pub fn decode_xtext(s: &str) -> Option<String> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(ch) = bytes.next() {
+        match ch {
+            b'+' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(|ch| matches!(ch, b'0'..=b'9' | b'A'..=b'F')) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'!'..=b'~' if ch != b'=' => out.push(ch),
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+pub fn encode_xtext(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.bytes() {
+        match ch {
+            b'!'..=b'~' if ch != b'+' && ch != b'=' => out.push(ch as char),
+            _ => out.push_str(&format!("+{:02X}", ch)),
+        }
+    }
+    out
+}
+
+fn invalid_param(keyword: &str, value: Option<&str>) -> EnvelopeError {
+    match value {
+        Some(value) => EnvelopeError::InvalidParameter(format!("{}={}", keyword, value)),
+        None => EnvelopeError::InvalidParameter(keyword.to_owned()),
+    }
+}
+
+/// The reverse-path given in MAIL FROM.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ReversePath {
+    /// `<>`, used for bounces and other notifications that must not
+    /// themselves cause bounces.
+    #[default]
+    Null,
+    Address(SmtpAddress),
+}
+
+/// Formats the path in angle brackets, as in a MAIL command.
+impl fmt::Display for ReversePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReversePath::Null => write!(f, "<>"),
+            ReversePath::Address(address) => write!(f, "<{}>", address),
+        }
+    }
+}
+
+/// The value of the BODY parameter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BodyType {
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
+}
+
+/// The value of the RET parameter: what to include in a failure DSN.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ret {
+    Full,
+    Headers,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailParameters {
+    pub size: Option<u64>,
+    pub body: Option<BodyType>,
+    pub smtputf8: bool,
+    pub ret: Option<Ret>,
+    /// The envelope identifier, decoded from xtext.
+    pub envid: Option<String>,
+    /// Parameters of other extensions, with uppercased keywords.
+    pub other: Vec<(String, Option<String>)>,
+}
+
+impl MailParameters {
+    /// Whether any DSN parameters are present.
+    pub fn uses_dsn(&self) -> bool {
+        self.ret.is_some() || self.envid.is_some()
+    }
+}
+
+/// Formats the parameters as they follow the path in a MAIL command,
+/// each preceded by a space.
+impl fmt::Display for MailParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, " SIZE={}", size)?;
+        }
+        if let Some(body) = self.body {
+            let body = match body {
+                BodyType::SevenBit => "7BIT",
+                BodyType::EightBitMime => "8BITMIME",
+                BodyType::BinaryMime => "BINARYMIME",
+            };
+            write!(f, " BODY={}", body)?;
+        }
+        if self.smtputf8 {
+            write!(f, " SMTPUTF8")?;
+        }
+        if let Some(ret) = self.ret {
+            let ret = match ret {
+                Ret::Full => "FULL",
+                Ret::Headers => "HDRS",
+            };
+            write!(f, " RET={}", ret)?;
+        }
+        if let Some(envid) = &self.envid {
+            write!(f, " ENVID={}", encode_xtext(envid))?;
+        }
+        write_other(f, &self.other)
+    }
+}
+
+fn write_other(f: &mut fmt::Formatter<'_>, other: &[(String, Option<String>)]) -> fmt::Result {
+    for (keyword, value) in other {
+        write!(f, " {}", keyword)?;
+        if let Some(value) = value {
+            write!(f, "={}", value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse the argument of a MAIL command, e.g. `FROM:<a@example.com> SIZE=1000`.
+pub fn parse_mail(arg: &str) -> Result<(ReversePath, MailParameters), EnvelopeError> {
+    let rest = strip_keyword(arg, "FROM:")?;
+    let (path, rest) = parse_path(rest)?;
+    let path = match path {
+        Some(address) => ReversePath::Address(address),
+        None => ReversePath::Null,
+    };
+    let mut params = MailParameters::default();
+    for (keyword, value) in parse_params(rest)? {
+        let invalid = || invalid_param(&keyword, value);
+        match (keyword.as_str(), value) {
+            ("SIZE", Some(value)) => {
+                if !value.bytes().all(|ch| ch.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                params.size = Some(value.parse().map_err(|_| invalid())?);
+            }
+            ("BODY", Some(value)) => {
+                params.body = Some(match value.to_ascii_uppercase().as_str() {
+                    "7BIT" => BodyType::SevenBit,
+                    "8BITMIME" => BodyType::EightBitMime,
+                    "BINARYMIME" => BodyType::BinaryMime,
+                    _ => return Err(invalid()),
+                })
+            }
+            ("SMTPUTF8", None) => params.smtputf8 = true,
+            ("RET", Some(value)) => {
+                params.ret = Some(match value.to_ascii_uppercase().as_str() {
+                    "FULL" => Ret::Full,
+                    "HDRS" => Ret::Headers,
+                    _ => return Err(invalid()),
+                })
+            }
+            ("ENVID", Some(value)) => match decode_xtext(value) {
+                Some(envid) if envid.len() <= MAX_ENVID => params.envid = Some(envid),
+                _ => return Err(invalid()),
+            },
+            ("SIZE", None)
+            | ("BODY", None)
+            | ("SMTPUTF8", Some(_))
+            | ("RET", None)
+            | ("ENVID", None) => return Err(invalid()),
+            ("NOTIFY", _) | ("ORCPT", _) => return Err(EnvelopeError::MisplacedParameter(keyword)),
+            _ => params
+                .other
+                .push((keyword.clone(), value.map(str::to_owned))),
+        }
+    }
+    Ok((path, params))
+}
+
+/// The value of the NOTIFY parameter: when to send a DSN.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Notify {
+    Never,
+    On(Conditions),
+}
+
+/// The conditions in a NOTIFY parameter other than NEVER, of which there is
+/// always at least one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Conditions {
+    success: bool,
+    failure: bool,
+    delay: bool,
+}
+
+impl Conditions {
+    /// Returns `None` if no condition is set.
+    pub fn new(success: bool, failure: bool, delay: bool) -> Option<Self> {
+        if !(success || failure || delay) {
+            return None;
+        }
+        Some(Self {
+            success,
+            failure,
+            delay,
+        })
+    }
+
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    pub fn failure(&self) -> bool {
+        self.failure
+    }
+
+    pub fn delay(&self) -> bool {
+        self.delay
+    }
+}
+
+/// The value of the ORCPT parameter: the recipient as originally specified.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OriginalRecipient {
+    /// E.g. `rfc822`.
+    pub addr_type: String,
+    /// The address, decoded from xtext.
+    pub address: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RcptParameters {
+    pub notify: Option<Notify>,
+    pub orcpt: Option<OriginalRecipient>,
+    /// Parameters of other extensions, with uppercased keywords.
+    pub other: Vec<(String, Option<String>)>,
+}
+
+impl RcptParameters {
+    /// Whether any DSN parameters are present.
+    pub fn uses_dsn(&self) -> bool {
+        self.notify.is_some() || self.orcpt.is_some()
+    }
+}
+
+/// Formats the parameters as they follow the path in a RCPT command,
+/// each preceded by a space.
+impl fmt::Display for RcptParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.notify {
+            None => {}
+            Some(Notify::Never) => write!(f, " NOTIFY=NEVER")?,
+            Some(Notify::On(on)) => {
+                let conditions: Vec<_> = [
+                    (on.success, "SUCCESS"),
+                    (on.failure, "FAILURE"),
+                    (on.delay, "DELAY"),
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect();
+                write!(f, " NOTIFY={}", conditions.join(","))?;
+            }
+        }
+        if let Some(orcpt) = &self.orcpt {
+            write!(
+                f,
+                " ORCPT={};{}",
+                orcpt.addr_type,
+                encode_xtext(&orcpt.address)
+            )?;
+        }
+        write_other(f, &self.other)
+    }
+}
+
+/// A forward-path given in RCPT TO, with its parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipient {
+    pub address: SmtpAddress,
+    pub params: RcptParameters,
+}
+
+impl Recipient {
+    pub fn new(address: SmtpAddress) -> Self {
+        Self {
+            address,
+            params: RcptParameters::default(),
+        }
+    }
+}
+
+/// Parse the argument of a RCPT command, e.g. `TO:<a@example.com> NOTIFY=NEVER`.
+pub fn parse_rcpt(arg: &str) -> Result<Recipient, EnvelopeError> {
+    let rest = strip_keyword(arg, "TO:")?;
+    // Every server must accept mail for its postmaster without a domain
+    // (RFC 5321 section 4.1.1.3).
+    let postmaster = match rest.get(..POSTMASTER.len()) {
+        Some(head) if head.eq_ignore_ascii_case(POSTMASTER) => {
+            let address = SmtpAddress {
+                local_part: head[1..head.len() - 1].to_owned(),
+                domain: String::new(),
+            };
+            Some((address, &rest[POSTMASTER.len()..]))
+        }
+        _ => None,
+    };
+    let (address, rest) = match postmaster {
+        Some(postmaster) => postmaster,
+        None => match parse_path(rest)? {
+            (Some(address), rest) => (address, rest),
+            (None, _) => return Err(EnvelopeError::InvalidPath(rest.to_owned())),
+        },
+    };
+    let mut params = RcptParameters::default();
+    for (keyword, value) in parse_params(rest)? {
+        let invalid = || invalid_param(&keyword, value);
+        match (keyword.as_str(), value) {
+            ("NOTIFY", Some(value)) => {
+                let mut conditions = [false; 3];
+                let mut never = false;
+                for condition in value.split(',') {
+                    let i = match condition.to_ascii_uppercase().as_str() {
+                        "NEVER" => {
+                            never = true;
+                            continue;
+                        }
+                        "SUCCESS" => 0,
+                        "FAILURE" => 1,
+                        "DELAY" => 2,
+                        _ => return Err(invalid()),
+                    };
+                    if conditions[i] {
+                        return Err(invalid());
+                    }
+                    conditions[i] = true;
+                }
+                params.notify = Some(match (never, conditions) {
+                    (true, [false, false, false]) if !value.contains(',') => Notify::Never,
+                    (false, [success, failure, delay]) => {
+                        Notify::On(Conditions::new(success, failure, delay).ok_or_else(invalid)?)
+                    }
+                    // NEVER can't be combined with anything.
+                    _ => return Err(invalid()),
+                });
+            }
+            ("ORCPT", Some(value)) => {
+                let semicolon = value.find(';').ok_or_else(invalid)?;
+                let addr_type = &value[..semicolon];
+                let address = decode_xtext(&value[semicolon + 1..]).ok_or_else(invalid)?;
+                if addr_type.is_empty()
+                    || !addr_type.bytes().all(is_atext)
+                    || address.is_empty()
+                    || value.len() > MAX_ORCPT
+                {
+                    return Err(invalid());
+                }
+                params.orcpt = Some(OriginalRecipient {
+                    addr_type: addr_type.to_owned(),
+                    address,
+                });
+            }
+            ("NOTIFY", None) | ("ORCPT", None) => return Err(invalid()),
+            ("SIZE", _) | ("BODY", _) | ("SMTPUTF8", _) | ("RET", _) | ("ENVID", _) => {
+                return Err(EnvelopeError::MisplacedParameter(keyword))
+            }
+            _ => params
+                .other
+                .push((keyword.clone(), value.map(str::to_owned))),
+        }
+    }
+    Ok(Recipient { address, params })
+}
+
+fn strip_keyword<'a>(arg: &'a str, keyword: &str) -> Result<&'a str, EnvelopeError> {
+    match arg.get(..keyword.len()) {
+        // RFC 5321 doesn't allow a space after the colon, but many clients send one.
+        Some(head) if head.eq_ignore_ascii_case(keyword) => Ok(arg[keyword.len()..].trim_start()),
+        _ => Err(EnvelopeError::InvalidPath(arg.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mail, parse_rcpt, BodyType, Conditions, Notify, Ret, ReversePath};
+
+    #[test]
+    fn test_parse_mail() {
+        let (path, params) = parse_mail(
+            "FROM:<\"odd user\"@example.com> SIZE=1000 body=8bitmime RET=HDRS ENVID=QQ+2B314 X-FOO",
+        )
+        .unwrap();
+        match &path {
+            ReversePath::Address(address) => {
+                assert_eq!(address.local_part, "odd user");
+                assert_eq!(address.domain, "example.com");
+            }
+            ReversePath::Null => panic!("expected an address"),
+        }
+        assert_eq!(params.size, Some(1000));
+        assert_eq!(params.body, Some(BodyType::EightBitMime));
+        assert_eq!(params.ret, Some(Ret::Headers));
+        assert_eq!(params.envid.as_deref(), Some("QQ+314"));
+        assert_eq!(params.other, vec![("X-FOO".to_owned(), None)]);
+        assert_eq!(
+            format!("{}{}", path, params),
+            "<\"odd user\"@example.com> SIZE=1000 BODY=8BITMIME RET=HDRS ENVID=QQ+2B314 X-FOO"
+        );
+
+        let (path, params) = parse_mail("FROM:<> SMTPUTF8").unwrap();
+        assert_eq!(path, ReversePath::Null);
+        assert!(params.smtputf8);
+        assert_eq!(
+            parse_mail("FROM:<@relay.example.com:a@[192.0.2.1]>")
+                .unwrap()
+                .0,
+            ReversePath::Address("a@[192.0.2.1]".parse().unwrap())
+        );
+
+        for bad in &[
+            "FROM:a@example.com",
+            "FROM:<a..b@example.com>",
+            "FROM:<a@-example.com>",
+            "FROM:<a@example.com>SIZE=1",
+            "FROM:<a@example.com> SIZE=1 SIZE=2",
+            "FROM:<a@example.com> SIZE=-1",
+            "FROM:<a@example.com> BODY=9BIT",
+            "FROM:<a@example.com> SMTPUTF8=yes",
+            "FROM:<a@example.com> ENVID=a+2b",
+            "FROM:<a@example.com> NOTIFY=NEVER",
+        ] {
+            assert!(parse_mail(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_rcpt() {
+        let rcpt =
+            parse_rcpt("TO:<b@example.com> NOTIFY=failure,DELAY ORCPT=rfc822;b+2Bx@example.com")
+                .unwrap();
+        assert_eq!(rcpt.address.to_string(), "b@example.com");
+        assert_eq!(
+            rcpt.params.notify,
+            Some(Notify::On(Conditions::new(false, true, true).unwrap()))
+        );
+        let postmaster = parse_rcpt("TO:<postmaster> NOTIFY=FAILURE").unwrap();
+        assert_eq!(postmaster.address.local_part, "postmaster");
+        assert_eq!(postmaster.address.to_string(), "postmaster");
+        assert_eq!(Conditions::new(false, false, false), None);
+        let orcpt = rcpt.params.orcpt.as_ref().unwrap();
+        assert_eq!(orcpt.addr_type, "rfc822");
+        assert_eq!(orcpt.address, "b+x@example.com");
+        assert_eq!(
+            rcpt.params.to_string(),
+            " NOTIFY=FAILURE,DELAY ORCPT=rfc822;b+2Bx@example.com"
+        );
+        assert_eq!(
+            parse_rcpt("to: <\u{e9}l\u{e8}ve@\u{e9}cole.example> NOTIFY=NEVER")
+                .unwrap()
+                .params
+                .notify,
+            Some(Notify::Never)
+        );
+        for bad in &[
+            "TO:<>",
+            "TO:<b@example.com> NOTIFY=NEVER,SUCCESS",
+            "TO:<b@example.com> NOTIFY=SUCCESS,SUCCESS",
+            "TO:<b@example.com> ORCPT=b@example.com",
+            "TO:<b@example.com> SIZE=10",
+        ] {
+            assert!(parse_rcpt(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! SMTP (RFC 5321) and its extensions.
+
+pub mod client;
+pub mod envelope;
+pub mod server;
+
+use std::io::{self, Read, Write};
+
+/// The longest line we accept from the peer. RFC 5321 section 4.5.3.1.4 only
+/// requires 1000 octets for text lines, but extensions make command and reply
+/// lines longer, so be lenient.
+const MAX_LINE: usize = 64 * 1024;
+
+/// A reply to an SMTP command: a three-digit code and one or more lines of text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(code: u16, text: &str) -> Self {
+        Self {
+            code,
+            lines: text.split('\n').map(str::to_owned).collect(),
+        }
+    }
+
+    /// 2yz: the command succeeded.
+    pub fn is_positive(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    /// 3yz: the command was accepted, and more input is expected.
+    pub fn is_intermediate(&self) -> bool {
+        (300..400).contains(&self.code)
+    }
+
+    /// 4yz: the command failed, but might succeed if retried later.
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    /// 5yz: the command failed, and retrying won't help.
+    pub fn is_permanent(&self) -> bool {
+        (500..600).contains(&self.code)
+    }
+
+    /// Serialize the reply, using `-` to mark all lines but the last as continued.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let last = self.lines.len().saturating_sub(1);
+        for (i, line) in self.lines.iter().enumerate() {
+            let sep = if i == last { ' ' } else { '-' };
+            out.extend_from_slice(format!("{}{}{}\r\n", self.code, sep, line).as_bytes());
+        }
+        if self.lines.is_empty() {
+            out.extend_from_slice(format!("{}\r\n", self.code).as_bytes());
+        }
+    }
+}
+
+/// Parse one line of a reply into its code, whether more lines follow,
+/// and its text.
+fn parse_reply_line(line: &[u8]) -> Option<(u16, bool, String)> {
+    if line.len() < 3 || !line[..3].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let code = std::str::from_utf8(&line[..3]).ok()?.parse().ok()?;
+    let (more, text) = match line.get(3) {
+        None => (false, &b""[..]),
+        Some(b' ') => (false, &line[4..]),
+        Some(b'-') => (true, &line[4..]),
+        Some(_) => return None,
+    };
+    Some((code, more, String::from_utf8_lossy(text).into_owned()))
+}
+
+/// Buffered line-oriented access to a stream that is both read and written.
+struct Connection<S> {
+    stream: S,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Read more data into the buffer. Returns false at end of stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        // Drop what has been read, so that the buffer only ever holds about
+        // a line, however long the input.
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let mut chunk = [0; 4096];
+        let n = loop {
+            match self.stream.read(&mut chunk) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n != 0)
+    }
+
+    /// Read a line, without its terminator. Lines are normally terminated by
+    /// CRLF, but a bare LF is accepted too. Returns `None` at end of stream.
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.read_raw_line()?.map(|mut line| {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            line
+        }))
+    }
+
+    /// Read a line up to and including the LF that ends it, keeping any CR
+    /// before it as it is. Returns `None` at end of stream.
+    fn read_raw_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // How much of the line has been searched; `fill` moves the start.
+        let mut searched = 0;
+        loop {
+            let start = self.pos + searched;
+            if let Some(i) = self.buf[start..].iter().position(|ch| *ch == b'\n') {
+                let end = start + i + 1;
+                let line = self.buf[self.pos..end].to_vec();
+                self.pos = end;
+                return Ok(Some(line));
+            }
+            searched = self.buf.len() - self.pos;
+            if searched > MAX_LINE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+            if !self.fill()? {
+                return if self.pos == self.buf.len() {
+                    Ok(None)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+        }
+    }
+
+    /// Read at most `max` bytes: those already buffered, or else those given
+    /// by the next read.
+    fn read_some(&mut self, max: usize) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() && !self.fill()? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let n = max.min(self.buf.len() - self.pos);
+        self.pos += n;
+        Ok(&self.buf[self.pos - n..self.pos])
+    }
+
+    /// Whether the peer has sent data that we haven't read yet.
+    fn has_buffered_input(&self) -> bool {
+        self.pos < self.buf.len()
+    }
+
+    /// Read a (possibly multi-line) reply.
+    fn read_reply(&mut self) -> io::Result<Reply> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed SMTP reply");
+        let mut reply: Option<Reply> = None;
+        loop {
+            let line = self.read_line()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let (code, more, text) = parse_reply_line(&line).ok_or_else(malformed)?;
+            match &mut reply {
+                None => {
+                    reply = Some(Reply {
+                        code,
+                        lines: vec![text],
+                    })
+                }
+                Some(reply) if reply.code == code => reply.lines.push(text),
+                Some(_) => return Err(malformed()),
+            }
+            if !more {
+                return Ok(reply.unwrap());
+            }
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Write `data` as the content of a DATA command: lines are terminated with
+/// CRLF, those starting with `.` get another one prepended, and the final
+/// `.` line is appended.
+fn dot_stuff(data: &[u8], out: &mut Vec<u8>) {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (line, after) = match rest.iter().position(|ch| *ch == b'\n') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, &[][..]),
+        };
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            out.push(b'.');
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+        rest = after;
+    }
+    out.extend_from_slice(b".\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{dot_stuff, parse_reply_line, Connection, Reply};
+
+    #[test]
+    fn test_reply_lines() {
+        assert_eq!(
+            parse_reply_line(b"250-PIPELINING"),
+            Some((250, true, "PIPELINING".to_owned()))
+        );
+        assert_eq!(parse_reply_line(b"354"), Some((354, false, String::new())));
+        assert_eq!(parse_reply_line(b"25x ok"), None);
+        assert_eq!(parse_reply_line(b"250_ok"), None);
+
+        let mut out = vec![];
+        Reply::new(250, "first\nsecond").write(&mut out);
+        assert_eq!(out, b"250-first\r\n250 second\r\n");
+    }
+
+    #[test]
+    fn test_dot_stuff() {
+        let mut out = vec![];
+        dot_stuff(b"a\r\n.b\n..c\r\nd", &mut out);
+        assert_eq!(out, b"a\r\n..b\r\n...c\r\nd\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_buffer_is_compacted() {
+        let input = "line\r\n".repeat(10_000);
+        let mut conn = Connection::new(Cursor::new(input.into_bytes()));
+        let mut lines = 0;
+        while let Some(line) = conn.read_line().unwrap() {
+            assert_eq!(line, b"line");
+            assert!(conn.buf.len() <= 2 * 4096);
+            lines += 1;
+        }
+        assert_eq!(lines, 10_000);
+    }
+}
@@ -0,0 +1,751 @@
+//! The server side of SMTP and LMTP (RFC 2033).
+//!
+//! `SmtpServer` runs the protocol over a stream, and hands each message that
+//! is received to a `Handler`, which decides what to do with it. The two
+//! protocols differ in how the outcome of a delivery is reported: an SMTP
+//! server sends one reply for the whole message, whereas an LMTP server sends
+//! one for each recipient.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use chrono::{DateTime, FixedOffset, Local};
+
+use super::envelope::{
+    parse_mail, parse_rcpt, BodyType, EnvelopeError, MailParameters, Recipient, ReversePath,
+    SmtpAddress,
+};
+use super::{Connection, Reply};
+use crate::error::EmailError;
+use crate::parse::email::parse_message;
+use crate::report::dsn::{DsnBuilder, StatusCode};
+use crate::{Message, SmtpEnvelope};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Smtp,
+    Lmtp,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The name the server uses for itself in its greeting and replies.
+    pub hostname: String,
+    pub protocol: Protocol,
+    /// The largest message accepted, advertised with the `SIZE` extension.
+    pub max_size: Option<u64>,
+}
+
+impl ServerConfig {
+    pub fn new(hostname: impl Into<String>, protocol: Protocol) -> Self {
+        Self {
+            hostname: hostname.into(),
+            protocol,
+            max_size: None,
+        }
+    }
+}
+
+/// A received message, for delivery to one recipient.
+#[derive(Clone, Debug)]
+pub struct Delivery<'a> {
+    pub envelope: &'a SmtpEnvelope,
+    /// The recipient to deliver to; one of those in `envelope`.
+    pub recipient: &'a Recipient,
+    /// The message as received, with the dot-stuffing removed.
+    pub data: &'a [u8],
+}
+
+impl<'a> Delivery<'a> {
+    pub fn message(&self) -> Result<Message<'a>, EmailError<'a>> {
+        parse_message(self.data)
+    }
+}
+
+/// Decides what happens to the messages a server receives. Returning
+/// `Err(reply)` from any method rejects the command with that reply.
+pub trait Handler {
+    /// Whether the handler implements the DSN extension (RFC 3461): it
+    /// honors the NOTIFY, RET, ENVID and ORCPT parameters, e.g. with
+    /// `report::dsn::DsnBuilder`, and passes them on when relaying.
+    /// Otherwise DSN isn't advertised, and its parameters are rejected.
+    fn supports_dsn(&self) -> bool {
+        false
+    }
+
+    /// Whether the handler accepts binary messages (RFC 3030), which it has
+    /// to convert before relaying them to a server without BINARYMIME.
+    /// Otherwise BINARYMIME isn't advertised, and `BODY=BINARYMIME` is
+    /// rejected.
+    fn supports_binarymime(&self) -> bool {
+        false
+    }
+
+    /// Called for MAIL, once its parameters have been validated.
+    fn mail(&mut self, from: &ReversePath, params: &MailParameters) -> Result<(), Reply> {
+        let _ = (from, params);
+        Ok(())
+    }
+
+    /// Called for each RCPT. `envelope` has the recipients accepted so far.
+    fn rcpt(&mut self, envelope: &SmtpEnvelope, recipient: &Recipient) -> Result<(), Reply> {
+        let _ = (envelope, recipient);
+        Ok(())
+    }
+
+    /// Called once the message has been received, once for each recipient
+    /// accepted by `rcpt`. With LMTP, the outcome is reported to the client
+    /// for each recipient; with SMTP, see `bounce`.
+    fn deliver(&mut self, delivery: &Delivery) -> Result<(), Reply>;
+
+    /// Called with SMTP when `deliver` failed for some recipients but not
+    /// others. The message has then been accepted, so the server is
+    /// responsible for telling the sender about the failures: `dsn` is a
+    /// delivery status notification to send to `to`, with a null
+    /// reverse-path. It isn't called if the recipients asked not to be told
+    /// with `NOTIFY`, or if the reverse-path is null.
+    fn bounce(&mut self, to: &SmtpAddress, dsn: Message<'static>);
+}
+
+/// The state of the current mail transaction.
+#[derive(Default)]
+struct Transaction {
+    /// Set by MAIL; RCPT adds the recipients.
+    envelope: Option<SmtpEnvelope>,
+    /// The chunks received so far with BDAT.
+    chunks: Option<Vec<u8>>,
+    /// A BDAT chunk was rejected, so the rest must be discarded.
+    chunks_failed: bool,
+}
+
+pub struct SmtpServer<S, H> {
+    conn: Connection<S>,
+    config: ServerConfig,
+    handler: H,
+    greeted: bool,
+    transaction: Transaction,
+    replies: Vec<u8>,
+}
+
+impl<S: Read + Write, H: Handler> SmtpServer<S, H> {
+    pub fn new(stream: S, config: ServerConfig, handler: H) -> Self {
+        Self {
+            conn: Connection::new(stream),
+            config,
+            handler,
+            greeted: false,
+            transaction: Transaction::default(),
+            replies: vec![],
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    /// Run the session until the client quits or closes the connection.
+    pub fn run(&mut self) -> io::Result<()> {
+        let greeting = match self.config.protocol {
+            Protocol::Smtp => "ESMTP",
+            Protocol::Lmtp => "LMTP",
+        };
+        self.reply(220, &format!("{} {}", self.config.hostname, greeting))?;
+        while let Some(line) = self.conn.read_line()? {
+            if !self.command(&line)? {
+                break;
+            }
+        }
+        self.flush()
+    }
+
+    /// Handle one command line. Returns false once the session is over.
+    fn command(&mut self, line: &[u8]) -> io::Result<bool> {
+        let line = String::from_utf8_lossy(line);
+        let (verb, arg) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (&line[..], ""),
+        };
+        let verb = verb.to_ascii_uppercase();
+        let lmtp = self.config.protocol == Protocol::Lmtp;
+        match verb.as_str() {
+            "HELO" if !lmtp => {
+                self.transaction = Transaction::default();
+                self.greeted = true;
+                let text = self.config.hostname.clone();
+                self.reply(250, &text)?;
+            }
+            "EHLO" if !lmtp => self.ehlo()?,
+            "LHLO" if lmtp => self.ehlo()?,
+            "MAIL" => self.mail(arg)?,
+            "RCPT" => self.rcpt(arg)?,
+            "DATA" => self.data()?,
+            "BDAT" => self.bdat(arg)?,
+            "RSET" => {
+                self.transaction = Transaction::default();
+                self.reply(250, "OK")?;
+            }
+            "NOOP" => self.reply(250, "OK")?,
+            "VRFY" => self.reply(252, "Cannot VRFY user")?,
+            "QUIT" => {
+                let text = format!("{} closing connection", self.config.hostname);
+                self.reply(221, &text)?;
+                return Ok(false);
+            }
+            _ => self.reply(500, "Command not recognized")?,
+        }
+        Ok(true)
+    }
+
+    fn ehlo(&mut self) -> io::Result<()> {
+        self.transaction = Transaction::default();
+        self.greeted = true;
+        let mut lines = vec![
+            self.config.hostname.clone(),
+            "PIPELINING".to_owned(),
+            "8BITMIME".to_owned(),
+            "SMTPUTF8".to_owned(),
+            "CHUNKING".to_owned(),
+        ];
+        if self.handler.supports_binarymime() {
+            lines.push("BINARYMIME".to_owned());
+        }
+        if self.handler.supports_dsn() {
+            lines.push("DSN".to_owned());
+        }
+        if let Some(max_size) = self.config.max_size {
+            lines.push(format!("SIZE {}", max_size));
+        }
+        self.reply(250, &lines.join("\n"))
+    }
+
+    fn mail(&mut self, arg: &str) -> io::Result<()> {
+        if !self.greeted {
+            return self.reply(503, "Send HELO/EHLO first");
+        }
+        if self.transaction.envelope.is_some() {
+            return self.reply(503, "Nested MAIL command");
+        }
+        let (from, params) = match parse_mail(arg) {
+            Ok(parsed) => parsed,
+            Err(e) => return self.syntax_error(e, "Syntax: MAIL FROM:<address>"),
+        };
+        let binarymime = params.body == Some(BodyType::BinaryMime);
+        if !params.other.is_empty()
+            || (params.uses_dsn() && !self.handler.supports_dsn())
+            || (binarymime && !self.handler.supports_binarymime())
+        {
+            return self.reply(555, "Unsupported parameter");
+        }
+        if let (Some(size), Some(max_size)) = (params.size, self.config.max_size) {
+            if size > max_size {
+                return self.reply(552, "Message size exceeds fixed maximum message size");
+            }
+        }
+        if let ReversePath::Address(address) = &from {
+            if !address.is_ascii() && !params.smtputf8 {
+                return self.reply(553, "Non-ASCII address requires SMTPUTF8");
+            }
+        }
+        match self.handler.mail(&from, &params) {
+            Ok(()) => {
+                self.transaction.envelope = Some(SmtpEnvelope {
+                    from,
+                    params,
+                    to: vec![],
+                });
+                self.reply(250, "OK")
+            }
+            Err(reply) => self.send_reply(&reply),
+        }
+    }
+
+    fn rcpt(&mut self, arg: &str) -> io::Result<()> {
+        let smtputf8 = match &self.transaction.envelope {
+            Some(envelope) => envelope.params.smtputf8,
+            None => return self.reply(503, "Need MAIL before RCPT"),
+        };
+        let recipient = match parse_rcpt(arg) {
+            Ok(recipient) => recipient,
+            Err(e) => return self.syntax_error(e, "Syntax: RCPT TO:<address>"),
+        };
+        if !recipient.params.other.is_empty()
+            || (recipient.params.uses_dsn() && !self.handler.supports_dsn())
+        {
+            return self.reply(555, "Unsupported parameter");
+        }
+        if !recipient.address.is_ascii() && !smtputf8 {
+            return self.reply(553, "Non-ASCII address requires SMTPUTF8");
+        }
+        let envelope = self.transaction.envelope.as_mut().unwrap();
+        match self.handler.rcpt(envelope, &recipient) {
+            Ok(()) => {
+                envelope.to.push(recipient);
+                self.reply(250, "OK")
+            }
+            Err(reply) => self.send_reply(&reply),
+        }
+    }
+
+    fn syntax_error(&mut self, e: EnvelopeError, usage: &str) -> io::Result<()> {
+        match e {
+            EnvelopeError::InvalidPath(_) => self.reply(501, usage),
+            EnvelopeError::InvalidParameter(param) => {
+                self.reply(501, &format!("Invalid parameter {}", param))
+            }
+            EnvelopeError::DuplicateParameter(param) => {
+                self.reply(501, &format!("Duplicate parameter {}", param))
+            }
+            EnvelopeError::MisplacedParameter(param) => {
+                self.reply(555, &format!("Unsupported parameter {}", param))
+            }
+        }
+    }
+
+    fn data(&mut self) -> io::Result<()> {
+        let envelope = match &self.transaction.envelope {
+            Some(envelope) if self.transaction.chunks.is_none() => envelope,
+            _ => return self.reply(503, "Bad sequence of commands"),
+        };
+        if envelope.params.body == Some(BodyType::BinaryMime) {
+            return self.reply(503, "BINARYMIME requires BDAT");
+        }
+        if envelope.to.is_empty() {
+            return self.reply(554, "No valid recipients");
+        }
+        self.reply(354, "End data with <CR><LF>.<CR><LF>")?;
+        self.flush()?;
+
+        let mut data = vec![];
+        let mut too_large = false;
+        // Only CRLF "." CRLF ends the data. Anything else that other software
+        // might take for its end, such as LF "." LF, could smuggle in another
+        // message, so a message with a bare CR or LF is rejected.
+        let mut bare_cr_or_lf = false;
+        // Whether the previous line ended with CRLF.
+        let mut after_crlf = true;
+        loop {
+            let line = self
+                .conn
+                .read_raw_line()?
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            let line = match line.strip_suffix(b"\r\n") {
+                Some(line) if !line.contains(&b'\r') => line,
+                _ => {
+                    bare_cr_or_lf = true;
+                    after_crlf = false;
+                    continue;
+                }
+            };
+            if line == b"." && after_crlf {
+                break;
+            }
+            after_crlf = true;
+            // Keep reading to the end of an oversized message, but don't keep it.
+            if too_large || bare_cr_or_lf {
+                continue;
+            }
+            data.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+            data.extend_from_slice(b"\r\n");
+            too_large = self.exceeds_max_size(data.len());
+        }
+        if bare_cr_or_lf {
+            self.transaction = Transaction::default();
+            return self.reply(554, "Bare CR or LF in message data");
+        }
+        if too_large {
+            self.transaction = Transaction::default();
+            return self.reply(552, "Message size exceeds fixed maximum message size");
+        }
+        self.deliver(&data)
+    }
+
+    fn bdat(&mut self, arg: &str) -> io::Result<()> {
+        let mut args = arg.split_whitespace();
+        let size = args.next().and_then(|size| size.parse::<u64>().ok());
+        let last = match args.next() {
+            None => false,
+            Some(last) if last.eq_ignore_ascii_case("LAST") => true,
+            Some(_) => return self.reply(501, "Syntax: BDAT <size> [LAST]"),
+        };
+        let size = match size {
+            Some(size) => size,
+            None => return self.reply(501, "Syntax: BDAT <size> [LAST]"),
+        };
+        let rejection = match &self.transaction.envelope {
+            None => Some(Reply::new(503, "Need MAIL before BDAT")),
+            Some(envelope) if envelope.to.is_empty() => {
+                Some(Reply::new(554, "No valid recipients"))
+            }
+            Some(_) => None,
+        };
+
+        // The chunk follows the command immediately, so it must always be read,
+        // even if the command is going to be rejected (RFC 3030 section 3).
+        // It's read a piece at a time, and only kept while it may be accepted,
+        // since the size is up to the client.
+        let mut keep = rejection.is_none() && !self.transaction.chunks_failed;
+        let mut data = if keep {
+            self.transaction.chunks.take().unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let mut remaining = size;
+        while remaining > 0 {
+            let max = usize::try_from(remaining).unwrap_or(usize::MAX);
+            let piece = self.conn.read_some(max)?;
+            remaining -= piece.len() as u64;
+            if keep {
+                data.extend_from_slice(piece);
+                if self.exceeds_max_size(data.len()) {
+                    keep = false;
+                    data = vec![];
+                    self.transaction.chunks_failed = true;
+                }
+            }
+        }
+
+        if let Some(reply) = rejection {
+            return self.send_reply(&reply);
+        }
+        if self.transaction.chunks_failed {
+            self.transaction.chunks = Some(vec![]);
+            if last {
+                self.transaction = Transaction::default();
+            }
+            return self.reply(552, "Message size exceeds fixed maximum message size");
+        }
+        if last {
+            self.deliver(&data)
+        } else {
+            self.transaction.chunks = Some(data);
+            self.reply(250, &format!("{} octets received", size))
+        }
+    }
+
+    fn exceeds_max_size(&self, size: usize) -> bool {
+        matches!(self.config.max_size, Some(max_size) if size as u64 > max_size)
+    }
+
+    /// Hand a complete message to the handler, and reply with the outcome.
+    fn deliver(&mut self, data: &[u8]) -> io::Result<()> {
+        let transaction = std::mem::take(&mut self.transaction);
+        let envelope = transaction.envelope.unwrap_or_default();
+        let mut results = vec![];
+        for recipient in envelope.to.iter() {
+            let delivery = Delivery {
+                envelope: &envelope,
+                recipient,
+                data,
+            };
+            results.push(self.handler.deliver(&delivery));
+        }
+        match self.config.protocol {
+            Protocol::Lmtp => {
+                for result in results {
+                    match result {
+                        Ok(()) => self.reply(250, "OK")?,
+                        Err(reply) => self.send_reply(&reply)?,
+                    }
+                }
+                Ok(())
+            }
+            // SMTP can only report success or failure for the whole message,
+            // so succeed if any recipient accepted it, and bounce the others.
+            Protocol::Smtp => {
+                if !results.iter().any(Result::is_ok) {
+                    let first_error = results.into_iter().find_map(Result::err);
+                    return match first_error {
+                        Some(reply) => self.send_reply(&reply),
+                        None => self.reply(554, "Transaction failed"),
+                    };
+                }
+                let failures: Vec<_> = envelope
+                    .to
+                    .iter()
+                    .zip(results)
+                    .filter_map(|(recipient, result)| Some((recipient, result.err()?)))
+                    .collect();
+                if !failures.is_empty() {
+                    self.bounce(&envelope, data, &failures);
+                }
+                self.reply(250, "OK")
+            }
+        }
+    }
+
+    /// Hand the handler a notification about the recipients in `failures`.
+    fn bounce(&mut self, envelope: &SmtpEnvelope, data: &[u8], failures: &[(&Recipient, Reply)]) {
+        let sender = match &envelope.from {
+            ReversePath::Address(sender) => sender,
+            ReversePath::Null => return,
+        };
+        // The handler accepted the message for someone even if it doesn't
+        // parse; then the notification just can't include it.
+        let original = parse_message(data)
+            .or_else(|_| parse_message(b"\r\n"))
+            .expect("an empty message parses");
+        let now: DateTime<FixedOffset> = Local::now().into();
+        let mut builder = DsnBuilder::new(&self.config.hostname, &original, envelope);
+        for (recipient, reply) in failures {
+            let diagnostic = format!("{} {}", reply.code, reply.lines.join(" "));
+            builder = builder.failure(&recipient.address, status_code(reply), &diagnostic);
+        }
+        if let Some(dsn) = builder.arrival_date(now).build(now) {
+            self.handler.bounce(sender, dsn);
+        }
+    }
+
+    fn reply(&mut self, code: u16, text: &str) -> io::Result<()> {
+        self.send_reply(&Reply::new(code, text))
+    }
+
+    /// Queue a reply. Replies are only flushed once the client's pipelined
+    /// commands have all been handled (RFC 2920 section 3.2).
+    fn send_reply(&mut self, reply: &Reply) -> io::Result<()> {
+        reply.write(&mut self.replies);
+        if !self.conn.has_buffered_input() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.replies.is_empty() {
+            self.conn.write_all(&self.replies)?;
+            self.replies.clear();
+        }
+        self.conn.flush()
+    }
+}
+
+/// The enhanced status code at the start of `reply`'s text, or else a
+/// generic one of the same class, e.g. `4.0.0` for a 452 reply.
+fn status_code(reply: &Reply) -> StatusCode {
+    let class = (reply.code / 100) as u8;
+    reply
+        .lines
+        .first()
+        .and_then(|text| text.parse::<StatusCode>().ok())
+        .filter(|code| code.class == class)
+        .unwrap_or_else(|| StatusCode::new(class, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use super::{Delivery, Handler, Protocol, ServerConfig, SmtpServer};
+    use crate::report::dsn::DeliveryReport;
+    use crate::smtp::envelope::{Recipient, SmtpAddress};
+    use crate::smtp::Reply;
+    use crate::{Message, SmtpEnvelope};
+
+    /// A client that sends a canned script, recording the server's replies.
+    struct FakeClient {
+        commands: Cursor<Vec<u8>>,
+        received: Vec<u8>,
+    }
+
+    impl Read for FakeClient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.commands.read(buf)
+        }
+    }
+
+    impl Write for FakeClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Inbox(
+        Vec<(String, String, Vec<u8>)>,
+        Vec<(String, Message<'static>)>,
+    );
+
+    impl Handler for Inbox {
+        fn supports_dsn(&self) -> bool {
+            true
+        }
+
+        fn rcpt(&mut self, _: &SmtpEnvelope, recipient: &Recipient) -> Result<(), Reply> {
+            if recipient.address.local_part == "nobody" {
+                return Err(Reply::new(550, "No such user"));
+            }
+            Ok(())
+        }
+
+        fn deliver(&mut self, delivery: &Delivery) -> Result<(), Reply> {
+            let message = delivery
+                .message()
+                .map_err(|_| Reply::new(554, "Bad message"))?;
+            assert!(message.header_field("Subject").is_some());
+            let to = delivery.recipient.address.to_string();
+            if to.starts_with("full@") {
+                return Err(Reply::new(452, "Mailbox full"));
+            }
+            let from = delivery.envelope.from.to_string();
+            self.0.push((from, to, delivery.data.to_vec()));
+            Ok(())
+        }
+
+        fn bounce(&mut self, to: &SmtpAddress, dsn: Message<'static>) {
+            self.1.push((to.to_string(), dsn));
+        }
+    }
+
+    fn run(protocol: Protocol, script: &str) -> (String, Inbox) {
+        let client = FakeClient {
+            commands: Cursor::new(script.as_bytes().to_vec()),
+            received: vec![],
+        };
+        let config = ServerConfig::new("mx.example.com", protocol);
+        let mut server = SmtpServer::new(client, config, Inbox::default());
+        server.run().unwrap();
+        let received = String::from_utf8(server.conn.stream.received.clone()).unwrap();
+        (received, server.into_handler())
+    }
+
+    #[test]
+    fn test_smtp_session() {
+        let (replies, inbox) = run(
+            Protocol::Smtp,
+            "EHLO client.example.com\r\n\
+             MAIL FROM:<a@example.com>\r\n\
+             RCPT TO:<nobody@example.com>\r\n\
+             RCPT TO:<b@example.com> NOTIFY=NEVER,SUCCESS\r\n\
+             RCPT TO:<b@example.com> NOTIFY=NEVER\r\n\
+             RCPT TO:<full@example.com>\r\n\
+             DATA\r\n\
+             Subject: one\r\n\
+             \r\n\
+             ..dotted\r\n\
+             .\r\n\
+             MAIL FROM:<> SIZE=22\r\n\
+             RCPT TO:<c@example.com>\r\n\
+             BDAT 14\r\n\
+             Subject: two\r\n\
+             BDAT 8 LAST\r\n\
+             \r\n\
+             Body\r\n\
+             QUIT\r\n",
+        );
+        assert_eq!(
+            replies,
+            "220 mx.example.com ESMTP\r\n\
+             250-mx.example.com\r\n\
+             250-PIPELINING\r\n\
+             250-8BITMIME\r\n\
+             250-SMTPUTF8\r\n\
+             250-CHUNKING\r\n\
+             250 DSN\r\n\
+             250 OK\r\n\
+             550 No such user\r\n\
+             501 Invalid parameter NOTIFY=NEVER,SUCCESS\r\n\
+             250 OK\r\n\
+             250 OK\r\n\
+             354 End data with <CR><LF>.<CR><LF>\r\n\
+             250 OK\r\n\
+             250 OK\r\n\
+             250 OK\r\n\
+             250 14 octets received\r\n\
+             250 OK\r\n\
+             221 mx.example.com closing connection\r\n"
+        );
+        assert_eq!(
+            inbox.0,
+            vec![
+                (
+                    "<a@example.com>".to_owned(),
+                    "b@example.com".to_owned(),
+                    b"Subject: one\r\n\r\n.dotted\r\n".to_vec()
+                ),
+                (
+                    "<>".to_owned(),
+                    "c@example.com".to_owned(),
+                    b"Subject: two\r\n\r\nBody\r\n".to_vec()
+                ),
+            ]
+        );
+
+        let (to, dsn) = &inbox.1[0];
+        assert_eq!(to, "a@example.com");
+        let report = DeliveryReport::find(dsn).unwrap();
+        let failed: Vec<_> = report.failed().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].final_recipient.value, "full@example.com");
+        assert_eq!(failed[0].status.to_string(), "4.0.0");
+    }
+
+    #[test]
+    fn test_lmtp_per_recipient_replies() {
+        let (replies, inbox) = run(
+            Protocol::Lmtp,
+            "HELO client.example.com\r\n\
+             LHLO client.example.com\r\n\
+             MAIL FROM:<a@example.com>\r\n\
+             RCPT TO:<b@example.com>\r\n\
+             RCPT TO:<full@example.com>\r\n\
+             DATA\r\n\
+             Subject: hi\r\n\
+             \r\n\
+             .\r\n",
+        );
+        let replies: Vec<_> = replies.lines().collect();
+        assert_eq!(replies[1], "500 Command not recognized");
+        assert_eq!(
+            &replies[replies.len() - 3..],
+            &[
+                "354 End data with <CR><LF>.<CR><LF>",
+                "250 OK",
+                "452 Mailbox full"
+            ]
+        );
+        assert_eq!(inbox.0.len(), 1);
+    }
+
+    #[test]
+    fn test_data_ends_only_at_crlf_dot_crlf() {
+        let (replies, inbox) = run(
+            Protocol::Smtp,
+            "EHLO client.example.com\r\n\
+             MAIL FROM:<a@example.com>\r\n\
+             RCPT TO:<b@example.com>\r\n\
+             DATA\r\n\
+             Subject: one\r\n\
+             \r\n\
+             Hi\n.\n\
+             MAIL FROM:<spoofed@example.com>\r\n\
+             RCPT TO:<b@example.com>\r\n\
+             DATA\r\n\
+             Subject: smuggled\r\n\
+             \r\n\
+             Hi\n.\r\n\
+             MAIL FROM:<spoofed@example.com>\r\n\
+             RCPT TO:<b@example.com>\r\n\
+             DATA\r\n\
+             Subject: smuggled\r\n\
+             \r\n\
+             .\r\n\
+             QUIT\r\n",
+        );
+        let replies: Vec<_> = replies.lines().collect();
+        assert_eq!(
+            &replies[replies.len() - 3..],
+            &[
+                "354 End data with <CR><LF>.<CR><LF>",
+                "554 Bare CR or LF in message data",
+                "221 mx.example.com closing connection"
+            ]
+        );
+        assert!(inbox.0.is_empty());
+    }
+}
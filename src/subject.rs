@@ -0,0 +1,273 @@
+//! The base subject of a message (RFC 5256 section 2.1): its subject
+//! without `Re:` and `Fwd:` prefixes, mailing list tags and the like, for
+//! sorting and threading messages by subject.
+//!
+//! Besides the English prefixes of the RFC, common localized ones such as
+//! `AW:` and `回复:` are removed too.
+
+use charset::Charset;
+
+use crate::Message;
+
+/// Prefixes that mark a reply or forward when followed by a colon, in
+/// lowercase.
+const REFWD_PREFIXES: &[&str] = &[
+    "re", "fwd", "fw", // English, as in RFC 5256
+    "aw", "wg", // German
+    "antw", "doorst", // Dutch
+    "sv", "vs", // Scandinavian
+    "réf", "tr",  // French
+    "rif", // Italian
+    "rv", "enc", // Spanish and Portuguese
+    "odp", // Polish
+    "ynt", // Turkish
+    "回复", "回覆", "答复", "转发", "轉寄", // Chinese
+    "返信", "転送", // Japanese
+];
+
+/// The result of `base_subject`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BaseSubject {
+    pub text: String,
+    /// Whether anything was removed that marks the message as a reply or
+    /// forward, which threading by subject takes into account.
+    pub is_reply_or_forward: bool,
+}
+
+impl BaseSubject {
+    /// The form in which base subjects are compared, since the comparison
+    /// is case-insensitive.
+    pub fn key(&self) -> String {
+        self.text.to_lowercase()
+    }
+}
+
+/// Strip `prefix` (in lowercase) from the start of `s`, ignoring case.
+fn strip_prefix_ci<'s>(s: &'s str, prefix: &str) -> Option<&'s str> {
+    match s.get(..prefix.len()) {
+        Some(start) if start.to_lowercase() == prefix => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Strip a `subj-blob`, e.g. `[list-name] `.
+fn strip_blob(s: &str) -> Option<&str> {
+    let rest = s.strip_prefix('[')?;
+    let end = rest.find(['[', ']'])?;
+    if !rest[end..].starts_with(']') {
+        return None;
+    }
+    Some(rest[end + 1..].trim_start())
+}
+
+/// Strip a `subj-refwd`, e.g. `Re: ` or `Fwd[2]: `.
+fn strip_refwd(s: &str) -> Option<&str> {
+    REFWD_PREFIXES.iter().find_map(|prefix| {
+        let rest = strip_prefix_ci(s, prefix)?.trim_start();
+        let rest = strip_blob(rest).unwrap_or(rest);
+        // CJK subjects often have a full-width colon.
+        let rest = rest.strip_prefix(':').or_else(|| rest.strip_prefix('：'))?;
+        Some(rest.trim_start())
+    })
+}
+
+/// Strip a `subj-leader`: any number of blobs followed by a `subj-refwd`.
+fn strip_leader(mut s: &str) -> Option<&str> {
+    loop {
+        if let Some(rest) = strip_refwd(s) {
+            return Some(rest);
+        }
+        s = strip_blob(s)?;
+    }
+}
+
+/// Decode the "Q" encoding, which is like quoted-printable except that
+/// `_` stands for a space, and trailing spaces are significant.
+fn q_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'_' => data.push(b' '),
+            b'=' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                data.push((high * 16 + low) as u8);
+            }
+            b => data.push(b),
+        }
+    }
+    Some(data)
+}
+
+/// Decode the text of an encoded word, e.g. `iso-8859-1?q?caf=E9`.
+fn decode_encoded_word(word: &str) -> Option<String> {
+    let mut pieces = word.splitn(3, '?');
+    let (charset, encoding, text) = (pieces.next()?, pieces.next()?, pieces.next()?);
+    // The charset may have an RFC 2231 language suffix, e.g. `utf-8*en`.
+    let charset = charset.split('*').next()?;
+    let data = match encoding {
+        "B" | "b" => base64::decode(text).ok()?,
+        "Q" | "q" => q_decode(text)?,
+        _ => return None,
+    };
+    let charset = Charset::for_label(charset.as_bytes())?;
+    Some(charset.decode(&data).0.into_owned())
+}
+
+/// Find the first encoded word in `s`, returning where it starts and ends
+/// and its decoded text.
+fn find_encoded_word(s: &str) -> Option<(usize, usize, String)> {
+    let mut from = 0;
+    while let Some(i) = s[from..].find("=?") {
+        let start = from + i;
+        let word = &s[start + 2..];
+        // The end is the `?=` after the charset and encoding.
+        let text_start = word.match_indices('?').nth(1).map(|(j, _)| j + 1);
+        let end = text_start.and_then(|j| Some(j + word[j..].find("?=")?));
+        if let Some(end) = end {
+            let word = &word[..end];
+            if !word.contains(char::is_whitespace) {
+                if let Some(decoded) = decode_encoded_word(word) {
+                    return Some((start, start + 2 + end + 2, decoded));
+                }
+            }
+        }
+        from = start + 2;
+    }
+    None
+}
+
+/// Decode the encoded words (RFC 2047) in an unstructured field value.
+/// Words that can't be decoded are left alone.
+pub fn decode_encoded_words(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_encoded_word = false;
+    while let Some((start, end, text)) = find_encoded_word(rest) {
+        // Whitespace between adjacent encoded words isn't part of the text.
+        let between = &rest[..start];
+        if !(after_encoded_word && between.trim().is_empty()) {
+            decoded.push_str(between);
+        }
+        decoded.push_str(&text);
+        rest = &rest[end..];
+        after_encoded_word = true;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The base subject of `subject`, an unfolded `Subject` field value.
+pub fn base_subject(subject: &str) -> BaseSubject {
+    let mut is_reply_or_forward = false;
+    // Step 1: decode encoded words and collapse whitespace.
+    let mut text = decode_encoded_words(subject)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    loop {
+        // Step 2: remove `(fwd)` trailers.
+        let mut s = text.as_str();
+        loop {
+            s = s.trim_end();
+            match s.len().checked_sub(5).and_then(|i| s.get(i..)) {
+                Some(end) if end.eq_ignore_ascii_case("(fwd)") => {
+                    s = &s[..s.len() - 5];
+                    is_reply_or_forward = true;
+                }
+                _ => break,
+            }
+        }
+        // Steps 3 to 5: remove leaders, and blobs that aren't all there is.
+        loop {
+            s = s.trim_start();
+            if let Some(rest) = strip_leader(s) {
+                s = rest;
+                is_reply_or_forward = true;
+                continue;
+            }
+            match strip_blob(s) {
+                Some(rest) if !rest.is_empty() => s = rest,
+                _ => break,
+            }
+        }
+        // Step 6: unwrap `[fwd: ...]` and start again.
+        match strip_prefix_ci(s, "[fwd:").and_then(|rest| rest.strip_suffix(']')) {
+            Some(inner) => {
+                text = inner.to_owned();
+                is_reply_or_forward = true;
+            }
+            None => {
+                text = s.to_owned();
+                break;
+            }
+        }
+    }
+    BaseSubject {
+        text,
+        is_reply_or_forward,
+    }
+}
+
+impl<'a> Message<'a> {
+    /// The base subject of the `Subject` field, which is empty if
+    /// there is none.
+    pub fn base_subject(&self) -> BaseSubject {
+        self.header_field("Subject")
+            .map(|hf| base_subject(&String::from_utf8_lossy(&hf.unfolded_value().0)))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base_subject, decode_encoded_words};
+    use crate::parse::email::parse_message;
+
+    #[test]
+    fn test_base_subject() {
+        let cases = [
+            ("Meeting", "Meeting", false),
+            ("Re: Meeting", "Meeting", true),
+            ("RE:  re[2]:\tFWD: Meeting (fwd) ", "Meeting", true),
+            ("[bmail-dev] Re: [bmail-dev] Meeting", "Meeting", true),
+            ("[Fwd: Re: Meeting]", "Meeting", true),
+            ("[bmail-dev] Meeting", "Meeting", false),
+            ("[bmail-dev]", "[bmail-dev]", false),
+            ("Reply needed", "Reply needed", false),
+            ("Re:", "", true),
+            ("AW: SV: Antw: Meeting", "Meeting", true),
+            ("回复：回复: 会议", "会议", true),
+            ("Swap: Meeting", "Swap: Meeting", false),
+            ("=?utf-8?q?Re=3A_Caf=C3=A9?= (fwd)", "Café", true),
+        ];
+        for (subject, text, is_reply_or_forward) in cases.iter().copied() {
+            let base = base_subject(subject);
+            assert_eq!(base.text, text, "{:?}", subject);
+            assert_eq!(
+                base.is_reply_or_forward, is_reply_or_forward,
+                "{:?}",
+                subject
+            );
+        }
+    }
+
+    #[test]
+    fn test_encoded_words() {
+        assert_eq!(
+            decode_encoded_words(
+                "=?ISO-8859-1?Q?Caf=E9_?= =?utf-8?B?w6AgbGE=?=\t=?utf-8?q?_carte?="
+            ),
+            "Café à la carte"
+        );
+        assert_eq!(
+            decode_encoded_words("Price =?utf-8*en?Q?=E2=82=AC5?= (was =?bogus?q?x?=)"),
+            "Price €5 (was =?bogus?q?x?=)"
+        );
+
+        let message =
+            parse_message(b"Subject: =?utf-8?B?UmU6IEjDpGxsbw==?=\r\n\r\nHi.\r\n").unwrap();
+        assert_eq!(message.base_subject().text, "Hällo");
+    }
+}
@@ -0,0 +1,394 @@
+//! Grouping messages into conversations, as for the IMAP `THREAD` command
+//! (RFC 5256): `references`, Jamie Zawinski's algorithm using `Message-ID`,
+//! `References` and `In-Reply-To` with the base subject as a fallback, and
+//! `ordered_subject`, which only looks at the base subject.
+//!
+//! Messages are identified by their index in the slice of summaries given
+//! to each function.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::headers::HeaderFieldInner;
+use crate::subject::BaseSubject;
+use crate::Message;
+
+/// What threading needs to know about a message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub message_id: Option<String>,
+    /// The ids of the messages this one follows, oldest first: the
+    /// `References` field, or else the first id in `In-Reply-To`.
+    pub references: Vec<String>,
+    pub subject: BaseSubject,
+    /// The sent date. Where it's missing, an IMAP server should use the
+    /// internal date instead; otherwise the message sorts first.
+    pub date: Option<DateTime<FixedOffset>>,
+}
+
+/// The ids in `value`, which should be a list of `<id>`s.
+fn message_ids(value: &[u8]) -> Vec<String> {
+    let value = String::from_utf8_lossy(value);
+    let mut ids = vec![];
+    let mut rest = &*value;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let id: String = rest[..end].split_whitespace().collect();
+        if !id.is_empty() {
+            ids.push(id);
+        }
+        rest = &rest[end + 1..];
+    }
+    ids
+}
+
+impl Summary {
+    pub fn new(message: &Message) -> Self {
+        let ids = |name| {
+            message
+                .header_field(name)
+                .map(|hf| message_ids(&hf.unfolded_value().0))
+                .unwrap_or_default()
+        };
+        let mut references = ids("References");
+        if references.is_empty() {
+            references.extend(ids("In-Reply-To").into_iter().take(1));
+        }
+        let date = message.header().iter().find_map(|hf| match hf.inner() {
+            HeaderFieldInner::OrigDate(date) => Some(*date),
+            _ => None,
+        });
+        Summary {
+            message_id: ids("Message-ID").into_iter().next(),
+            references,
+            subject: message.base_subject(),
+            date,
+        }
+    }
+}
+
+/// A node in a tree of messages. The roots of the trees are the threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Thread {
+    /// The index of the message, or `None` for a placeholder standing in
+    /// for a missing message with more than one reply, or for a group of
+    /// threads with the same subject.
+    pub message: Option<usize>,
+    pub children: Vec<Thread>,
+}
+
+impl Thread {
+    fn new(message: usize) -> Self {
+        Thread {
+            message: Some(message),
+            children: vec![],
+        }
+    }
+
+    /// The message whose date and subject stand for this node: its own,
+    /// or for a placeholder, its first child's.
+    fn representative(&self) -> Option<usize> {
+        self.message
+            .or_else(|| self.children.first()?.representative())
+    }
+}
+
+/// The order of messages: by sent date, then by index.
+fn sort_key(
+    summaries: &[Summary],
+    message: Option<usize>,
+) -> (Option<DateTime<FixedOffset>>, usize) {
+    match message {
+        Some(i) => (summaries[i].date, i),
+        None => (None, 0),
+    }
+}
+
+/// Sort all the siblings in `threads` by date, placeholders by their
+/// (already sorted) first child.
+fn sort_threads(summaries: &[Summary], threads: &mut [Thread]) {
+    for thread in threads.iter_mut() {
+        sort_threads(summaries, &mut thread.children);
+    }
+    threads.sort_by_key(|thread| sort_key(summaries, thread.representative()));
+}
+
+#[derive(Default)]
+struct Container {
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// The containers of the messages and the ids they refer to.
+#[derive(Default)]
+struct Containers(Vec<Container>);
+
+impl Containers {
+    fn add(&mut self, message: Option<usize>) -> usize {
+        self.0.push(Container {
+            message,
+            ..Container::default()
+        });
+        self.0.len() - 1
+    }
+
+    /// Whether `descendant` is `ancestor` or below it.
+    fn is_below(&self, descendant: usize, ancestor: usize) -> bool {
+        let mut cur = Some(descendant);
+        while let Some(c) = cur {
+            if c == ancestor {
+                return true;
+            }
+            cur = self.0[c].parent;
+        }
+        false
+    }
+
+    fn unlink(&mut self, child: usize) {
+        if let Some(parent) = self.0[child].parent.take() {
+            self.0[parent].children.retain(|c| *c != child);
+        }
+    }
+
+    fn link(&mut self, parent: usize, child: usize) {
+        self.0[child].parent = Some(parent);
+        self.0[parent].children.push(child);
+    }
+
+    /// Turn `children` into trees, leaving out placeholders with no
+    /// children, and replacing those with one child (or that aren't at the
+    /// top level) by their children.
+    fn prune(&self, children: &[usize], top_level: bool) -> Vec<Thread> {
+        let mut threads = vec![];
+        for c in children.iter().copied() {
+            let mut grandchildren = self.prune(&self.0[c].children, false);
+            match self.0[c].message {
+                Some(message) => threads.push(Thread {
+                    message: Some(message),
+                    children: grandchildren,
+                }),
+                None if top_level && grandchildren.len() > 1 => threads.push(Thread {
+                    message: None,
+                    children: grandchildren,
+                }),
+                None => threads.append(&mut grandchildren),
+            }
+        }
+        threads
+    }
+}
+
+/// Thread messages by their references and then by subject, as for
+/// `THREAD=REFERENCES`.
+pub fn references(summaries: &[Summary]) -> Vec<Thread> {
+    // Step 1: link the messages by their ids.
+    let mut containers = Containers::default();
+    let mut by_id: HashMap<&str, usize> = HashMap::new();
+    for (i, summary) in summaries.iter().enumerate() {
+        let container = match summary.message_id.as_deref() {
+            Some(id) => match by_id.get(id).copied() {
+                Some(c) if containers.0[c].message.is_none() => {
+                    containers.0[c].message = Some(i);
+                    c
+                }
+                // A duplicate id; treat it as unique.
+                Some(_) => containers.add(Some(i)),
+                None => {
+                    let c = containers.add(Some(i));
+                    by_id.insert(id, c);
+                    c
+                }
+            },
+            None => containers.add(Some(i)),
+        };
+
+        let mut refs = vec![];
+        for id in summary.references.iter() {
+            let c = match by_id.get(id.as_str()) {
+                Some(c) => *c,
+                None => {
+                    let c = containers.add(None);
+                    by_id.insert(id, c);
+                    c
+                }
+            };
+            refs.push(c);
+        }
+        for pair in refs.windows(2) {
+            let (parent, child) = (pair[0], pair[1]);
+            if containers.0[child].parent.is_none()
+                && !containers.is_below(parent, child)
+                && !containers.is_below(child, parent)
+            {
+                containers.link(parent, child);
+            }
+        }
+        // The references may have been truncated, so the last one is
+        // the parent, whatever an earlier message said.
+        containers.unlink(container);
+        if let Some(parent) = refs.last().copied() {
+            if !containers.is_below(parent, container) {
+                containers.link(parent, container);
+            }
+        }
+    }
+
+    // Steps 2 to 4: gather the roots and prune placeholders.
+    let roots: Vec<usize> = (0..containers.0.len())
+        .filter(|c| containers.0[*c].parent.is_none())
+        .collect();
+    let mut roots = containers.prune(&roots, true);
+
+    // Step 5: sort the roots, which decides which is kept when merging.
+    sort_threads(summaries, &mut roots);
+
+    // Step 6: merge threads with the same base subject.
+    let subject = |thread: &Thread| &summaries[thread.representative().unwrap()].subject;
+    let mut by_subject: HashMap<String, usize> = HashMap::new();
+    for (i, thread) in roots.iter().enumerate() {
+        if subject(thread).text.is_empty() {
+            continue;
+        }
+        let key = subject(thread).key();
+        match by_subject.get(&key).map(|j| &roots[*j]) {
+            None => {
+                by_subject.insert(key, i);
+            }
+            Some(existing)
+                if existing.message.is_some()
+                    && (thread.message.is_none()
+                        || (subject(existing).is_reply_or_forward
+                            && !subject(thread).is_reply_or_forward)) =>
+            {
+                by_subject.insert(key, i);
+            }
+            Some(_) => {}
+        }
+    }
+    let mut slots: Vec<Option<Thread>> = roots.into_iter().map(Some).collect();
+    for i in 0..slots.len() {
+        let thread = slots[i].as_ref().unwrap();
+        if subject(thread).text.is_empty() {
+            continue;
+        }
+        let j = by_subject[&subject(thread).key()];
+        if i == j {
+            continue;
+        }
+        let thread = slots[i].take().unwrap();
+        let existing = slots[j].as_mut().unwrap();
+        if existing.message.is_none() && thread.message.is_none() {
+            existing.children.extend(thread.children);
+        } else if existing.message.is_none()
+            || (subject(&thread).is_reply_or_forward && !subject(existing).is_reply_or_forward)
+        {
+            existing.children.push(thread);
+        } else {
+            let existing = slots[j].take().unwrap();
+            slots[j] = Some(Thread {
+                message: None,
+                children: vec![existing, thread],
+            });
+        }
+    }
+
+    // Step 7: sort everything.
+    let mut threads: Vec<Thread> = slots.into_iter().flatten().collect();
+    sort_threads(summaries, &mut threads);
+    threads
+}
+
+/// Thread messages only by base subject, as for `THREAD=ORDEREDSUBJECT`:
+/// the first message with each subject is the parent of all the others.
+pub fn ordered_subject(summaries: &[Summary]) -> Vec<Thread> {
+    let mut order: Vec<(String, usize)> = summaries
+        .iter()
+        .enumerate()
+        .map(|(i, summary)| (summary.subject.key(), i))
+        .collect();
+    order.sort_by(|(a, i), (b, j)| {
+        a.cmp(b)
+            .then_with(|| sort_key(summaries, Some(*i)).cmp(&sort_key(summaries, Some(*j))))
+    });
+    let mut threads: Vec<Thread> = vec![];
+    let mut last_key = None;
+    for (key, i) in order {
+        match threads.last_mut() {
+            Some(thread) if last_key.as_ref() == Some(&key) => thread.children.push(Thread::new(i)),
+            _ => threads.push(Thread::new(i)),
+        }
+        last_key = Some(key);
+    }
+    threads.sort_by_key(|thread| sort_key(summaries, thread.message));
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ordered_subject, references, Summary, Thread};
+    use crate::imap;
+    use crate::parse::email::parse_message;
+
+    fn summaries(messages: &[(&str, &str, &str)]) -> Vec<Summary> {
+        messages
+            .iter()
+            .enumerate()
+            .map(|(i, (id, refs, subject))| {
+                let input = format!(
+                    "Message-ID: <{}>\r\n\
+                     References: {}\r\n\
+                     Subject: {}\r\n\
+                     Date: Mon, 1 Mar 2021 10:{:02}:00 +0000\r\n\
+                     \r\n",
+                    id, refs, subject, i
+                );
+                Summary::new(&parse_message(input.as_bytes()).unwrap())
+            })
+            .collect()
+    }
+
+    fn render(threads: &[Thread]) -> String {
+        let mut out = vec![];
+        imap::thread(threads, |i| i as u32 + 1, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_references() {
+        let summaries = summaries(&[
+            ("1@x", "", "Lunch"),
+            ("2@x", "<1@x>", "Re: Lunch"),
+            // A reply to a message we don't have.
+            ("3@x", "<1@x> <missing@x>", "Re: Lunch"),
+            ("4@x", "<1@x> <2@x>", "Re: Lunch"),
+            // Two replies to another missing message.
+            ("5@x", "<gone@x>", "Re: Taxes"),
+            ("6@x", "<gone@x>", "Re: Taxes"),
+            // No references, but the same subject as a thread.
+            ("7@x", "", "Fwd: Lunch"),
+            ("8@x", "", "Weather"),
+            ("9@x", "", "Weather"),
+        ]);
+        let threads = references(&summaries);
+        assert_eq!(render(&threads), "(1 (2 4)(3)(7))((5)(6))((8)(9))");
+        assert_eq!(threads[1].message, None);
+    }
+
+    #[test]
+    fn test_ordered_subject() {
+        let summaries = summaries(&[
+            ("1@x", "", "Weather"),
+            ("2@x", "", "Lunch"),
+            ("3@x", "<2@x>", "Re: lunch"),
+            ("4@x", "", "[list] Weather"),
+            ("5@x", "<3@x>", "Re: Lunch"),
+        ]);
+        assert_eq!(render(&ordered_subject(&summaries)), "(1 4)(2 (3)(5))");
+    }
+}
@@ -0,0 +1,271 @@
+//! Serialization of messages back to bytes.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use encoding_rs::Encoding;
+
+use crate::headers::mime::ContentType;
+use crate::headers::HeaderFieldInner;
+use crate::{Body, ByteStr, HeaderField, Message};
+
+impl<'a> Message<'a> {
+    /// Append the message, in the form in which it would be sent, to `out`.
+    ///
+    /// The header is written as-is, except for the Content-Type field. The
+    /// body is re-encoded according to the header's Content-Type charset and
+    /// Content-Transfer-Encoding, so the result is equivalent to, but not
+    /// necessarily identical to, the input the message was parsed from.
+    ///
+    /// Text that can't be encoded in its charset is written as UTF-8 and
+    /// relabelled, and a multipart body without a boundary gets one, with a
+    /// Content-Type field added if there is none.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let cte = self.header().iter().find_map(|hf| match hf.inner() {
+            HeaderFieldInner::ContentTransferEncoding(cte) => Some(*cte),
+            _ => None,
+        });
+        let mut body = vec![];
+        // The Content-Type to write instead of the one in the header.
+        let mut content_type = None;
+        match self.body() {
+            Body::SimpleText(text) => {
+                let label = self
+                    .content_type()
+                    .and_then(|ct| ct.parameters.get("charset"));
+                let encoded = match label {
+                    Some(label) => encode(text, label).unwrap_or_else(|| {
+                        content_type = Some(self.content_type_with("charset", "utf-8"));
+                        text.as_bytes().into()
+                    }),
+                    // [RFC] we decoded the text as utf8 in the first place.
+                    None => text.as_bytes().into(),
+                };
+                write_encoded(&encoded, cte, &mut body);
+            }
+            Body::SimpleBinary(data) => write_encoded(data, cte, &mut body),
+            Body::Multipart {
+                preamble,
+                parts,
+                epilogue,
+            } => {
+                let parts: Vec<_> = parts.iter().map(Message::to_bytes).collect();
+                let boundary = match self
+                    .content_type()
+                    .and_then(|ct| ct.parameters.get("boundary"))
+                {
+                    Some(boundary) => boundary.clone(),
+                    None => {
+                        let boundary = make_boundary(&parts);
+                        content_type = Some(self.content_type_with("boundary", &boundary));
+                        boundary
+                    }
+                };
+                body.extend_from_slice(preamble);
+                if !preamble.is_empty() && !preamble.ends_with(b"\r\n") {
+                    body.extend_from_slice(b"\r\n");
+                }
+                for part in parts {
+                    body.extend_from_slice(b"--");
+                    body.extend_from_slice(boundary.as_bytes());
+                    body.extend_from_slice(b"\r\n");
+                    body.extend_from_slice(&part);
+                    body.extend_from_slice(b"\r\n");
+                }
+                body.extend_from_slice(b"--");
+                body.extend_from_slice(boundary.as_bytes());
+                body.extend_from_slice(b"--\r\n");
+                body.extend_from_slice(epilogue);
+            }
+        }
+
+        // A type that came from parsing always renders; failing that, the
+        // original field is kept.
+        let content_type = content_type.and_then(|ct| {
+            HeaderField::from_inner("Content-Type", HeaderFieldInner::ContentType(ct)).ok()
+        });
+        for (i, hf) in self.header().iter().enumerate() {
+            match &content_type {
+                Some(ct) if Some(i) == self.content_type => ct.write(out),
+                _ => hf.write(out),
+            }
+        }
+        if let (Some(ct), None) = (&content_type, self.content_type) {
+            ct.write(out);
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&body);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.size());
+        self.write(&mut out);
+        out
+    }
+
+    /// The Content-Type with the parameter `name` set to `value`. A missing
+    /// one, which only matters for a multipart body, is `multipart/mixed`.
+    fn content_type_with(&self, name: &str, value: &str) -> ContentType<'static> {
+        let mut ct = match self.content_type() {
+            Some(ct) => ct.clone().into_owned(),
+            None => ContentType {
+                r#type: Cow::Owned(ByteStr::from_slice(b"multipart").to_owned()),
+                subtype: Cow::Owned(ByteStr::from_slice(b"mixed").to_owned()),
+                parameters: HashMap::new(),
+            },
+        };
+        ct.parameters.insert(name.to_owned(), value.to_owned());
+        ct
+    }
+}
+
+// The names of US-ASCII in the IANA character set registry, lowercased.
+const ASCII_LABELS: &[&str] = &[
+    "us-ascii",
+    "ascii",
+    "ansi_x3.4-1968",
+    "ansi_x3.4-1986",
+    "iso-ir-6",
+    "iso_646.irv:1991",
+    "iso646-us",
+    "us",
+    "ibm367",
+    "cp367",
+    "csascii",
+];
+
+/// Encode `text` in the charset called `label`. Returns `None` if that can't
+/// be done faithfully: the charset is unknown to encoding_rs, or it can only
+/// decode it (e.g. UTF-16, which it encodes as UTF-8), or some character
+/// isn't in the charset.
+fn encode<'t>(text: &'t str, label: &str) -> Option<Cow<'t, [u8]>> {
+    // encoding_rs treats these as windows-1252, which would write non-ASCII
+    // text in bytes that the label says can't be there.
+    if ASCII_LABELS.contains(&label.trim().to_ascii_lowercase().as_str()) {
+        return if text.is_ascii() {
+            Some(text.as_bytes().into())
+        } else {
+            None
+        };
+    }
+    let encoding = Encoding::for_label(label.as_bytes())?;
+    if encoding.output_encoding() != encoding {
+        return None;
+    }
+    let (encoded, _, unmappable) = encoding.encode(text);
+    if unmappable {
+        return None;
+    }
+    Some(encoded)
+}
+
+/// A boundary that doesn't occur in any of `parts`.
+fn make_boundary(parts: &[Vec<u8>]) -> String {
+    (0..)
+        .map(|n| format!("bmail-boundary-{}", n))
+        .find(|boundary| {
+            !parts.iter().any(|part| {
+                part.windows(boundary.len())
+                    .any(|w| w == boundary.as_bytes())
+            })
+        })
+        .unwrap()
+}
+
+fn write_encoded(
+    data: &[u8],
+    cte: Option<crate::headers::mime::ContentTransferEncoding>,
+    out: &mut Vec<u8>,
+) {
+    match cte {
+        Some(cte) => out.extend_from_slice(&cte.encode(data)),
+        None => out.extend_from_slice(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::headers::HeaderFieldInner;
+    use crate::parse::email::parse_message;
+    use crate::{Body, Message};
+
+    #[test]
+    fn test_write_round_trip() {
+        let input = "Subject: hi\r\n\
+                     Content-Type: multipart/mixed; boundary=xyz\r\n\
+                     \r\n\
+                     preamble\r\n\
+                     --xyz\r\n\
+                     Content-Type: text/plain; charset=iso-8859-1\r\n\
+                     Content-Transfer-Encoding: quoted-printable\r\n\
+                     \r\n\
+                     caf=E9\r\n\
+                     --xyz\r\n\
+                     Content-Transfer-Encoding: base64\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     \r\n\
+                     AAECAw==\r\n\
+                     --xyz--\r\n";
+        let m = parse_message(input.as_bytes()).unwrap();
+        let written = m.to_bytes();
+        assert_eq!(String::from_utf8_lossy(&written), input);
+
+        let reparsed = parse_message(&written).unwrap();
+        match reparsed.body() {
+            Body::Multipart { parts, .. } => match parts[0].body() {
+                Body::SimpleText(text) => assert_eq!(text, "caf\u{e9}"),
+                _ => panic!("expected text body"),
+            },
+            _ => panic!("expected multipart body"),
+        }
+    }
+
+    #[test]
+    fn test_relabel_and_boundary() {
+        for label in ["utf-16", "utf-7", "iso-8859-1"].iter() {
+            let input = format!("Content-Type: text/plain; charset={}\r\n\r\n", label);
+            let mut m = parse_message(input.as_bytes()).unwrap();
+            *m.body_mut() = Body::SimpleText("\u{2603}".to_owned());
+            let written = m.to_bytes();
+            assert_eq!(
+                String::from_utf8_lossy(&written),
+                "Content-Type: text/plain; charset=utf-8\r\n\r\n\u{2603}"
+            );
+        }
+
+        for label in ["us-ascii", "US-ASCII", "ANSI_X3.4-1968"].iter() {
+            let input = format!("Content-Type: text/plain; charset={}\r\n\r\n", label);
+            let mut m = parse_message(input.as_bytes()).unwrap();
+            *m.body_mut() = Body::SimpleText("caf\u{e9}".to_owned());
+            let written = m.to_bytes();
+            assert_eq!(
+                String::from_utf8_lossy(&written),
+                "Content-Type: text/plain; charset=utf-8\r\n\r\ncaf\u{e9}"
+            );
+
+            *m.body_mut() = Body::SimpleText("cafe".to_owned());
+            let written = m.to_bytes();
+            assert_eq!(String::from_utf8_lossy(&written), format!("{}cafe", input));
+        }
+
+        let part = |text: &str| Message::from_parts(vec![], Body::SimpleText(text.to_owned()));
+        let body = Body::Multipart {
+            preamble: Cow::Borrowed(b""),
+            parts: vec![part("bmail-boundary-0"), part("two")],
+            epilogue: Cow::Borrowed(b""),
+        };
+        let written = Message::from_parts(vec![], body).to_bytes();
+        let reparsed = parse_message(&written).unwrap();
+        let ct = match reparsed.header()[0].inner() {
+            HeaderFieldInner::ContentType(ct) => ct,
+            _ => panic!("expected Content-Type"),
+        };
+        assert_eq!(ct.parameters["boundary"], "bmail-boundary-1");
+        match reparsed.body() {
+            Body::Multipart { parts, .. } => assert_eq!(parts.len(), 2),
+            _ => panic!("expected multipart body"),
+        }
+    }
+}